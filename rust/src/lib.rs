@@ -3,7 +3,7 @@ use std::collections::HashMap;
 #[cfg(feature = "native")]
 use std::io::Cursor;
 #[cfg(feature = "native")]
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 #[cfg(feature = "native")]
 use std::sync::Mutex;
 
@@ -12,6 +12,9 @@ use image::{DynamicImage, ImageFormat};
 #[cfg(feature = "native")]
 use lazy_static::lazy_static;
 
+#[cfg(any(feature = "native", feature = "wasm-standalone"))]
+mod ops;
+
 #[cfg(feature = "native")]
 mod native {
     use super::*;
@@ -20,24 +23,68 @@ mod native {
 
     lazy_static! {
         static ref IMAGES: Mutex<HashMap<u32, DynamicImage>> = Mutex::new(HashMap::new());
+        static ref DEFAULT_QUALITY: Mutex<HashMap<ImageFormat, u8>> = Mutex::new(HashMap::new());
+        // id -> (created, last access) in unix millis. Always locked after IMAGES.
+        static ref ACCESS: Mutex<HashMap<u32, (u64, u64)>> = Mutex::new(HashMap::new());
+        // id -> active selection and the size it was set on. Always locked after IMAGES.
+        static ref SELECTIONS: Mutex<HashMap<u32, ops::Selection>> = Mutex::new(HashMap::new());
+        // id -> undo snapshots, oldest first. Always locked after IMAGES.
+        static ref HISTORY: Mutex<HashMap<u32, Vec<DynamicImage>>> = Mutex::new(HashMap::new());
+        // Animation handles share NEXT_ID with images but live apart from them.
+        static ref ANIMATIONS: Mutex<HashMap<u32, ops::GifAnimation>> = Mutex::new(HashMap::new());
+        // id -> color space tag; untagged images are sRGB. Always locked after IMAGES.
+        static ref COLORSPACES: Mutex<HashMap<u32, ops::ColorSpace>> = Mutex::new(HashMap::new());
+        // id -> EXIF orientation captured at decode; cleared by AutoOrient.
+        // Always locked after IMAGES.
+        static ref ORIENTATIONS: Mutex<HashMap<u32, image::metadata::Orientation>> = Mutex::new(HashMap::new());
     }
 
     static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+    static PIXELS_PROCESSED: AtomicU64 = AtomicU64::new(0);
+    static HISTORY_DEPTH: AtomicUsize = AtomicUsize::new(10);
+    // Allocation cap in bytes for adaptive decodes; 0 keeps the image crate default.
+    static DECODE_LIMIT: AtomicU64 = AtomicU64::new(0);
+    // Bumped by nativeCancelCurrent; see ops::CancelToken.
+    static CANCEL_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+    // Taken on entry by every cancellable op, before it waits on any lock.
+    fn cancel_token() -> ops::CancelToken<'static> {
+        ops::CancelToken::new(&CANCEL_EPOCH)
+    }
+
+    fn now_millis() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    fn touch(id: u32) {
+        if let Ok(mut access) = ACCESS.lock() {
+            if let Some(entry) = access.get_mut(&id) {
+                entry.1 = now_millis();
+            }
+        }
+    }
 
     fn get_image<'a>(
         map: &'a HashMap<u32, DynamicImage>,
         id: u32,
     ) -> Result<&'a DynamicImage, String> {
-        map.get(&id)
-            .ok_or_else(|| format!("invalid image id {}", id))
+        let img = map.get(&id)
+            .ok_or_else(|| format!("invalid image id {}", id))?;
+        touch(id);
+        Ok(img)
     }
 
     fn get_image_mut<'a>(
         map: &'a mut HashMap<u32, DynamicImage>,
         id: u32,
     ) -> Result<&'a mut DynamicImage, String> {
-        map.get_mut(&id)
-            .ok_or_else(|| format!("invalid image id {}", id))
+        let img = map.get_mut(&id)
+            .ok_or_else(|| format!("invalid image id {}", id))?;
+        touch(id);
+        Ok(img)
     }
 
     fn insert_image(img: DynamicImage) -> Result<u32, String> {
@@ -46,55 +93,101 @@ mod native {
             .lock()
             .map_err(|_| "image lock poisoned".to_string())?;
         map.insert(id, img);
+        let now = now_millis();
+        ACCESS
+            .lock()
+            .map_err(|_| "access lock poisoned".to_string())?
+            .insert(id, (now, now));
         Ok(id)
     }
 
+    // Meters work for rate limiting: every resampling op adds its source area.
+    fn record_pixels(img: &DynamicImage) {
+        let area = img.width() as u64 * img.height() as u64;
+        PIXELS_PROCESSED.fetch_add(area, Ordering::Relaxed);
+    }
+
     fn to_u32(v: i64, name: &str) -> Result<u32, String> {
         u32::try_from(v).map_err(|_| format!("{name} out of range: {v}"))
     }
 
+    // Width/height argument that must also be non-zero; the image crate
+    // panics or returns empty buffers on zero-sized targets.
+    fn to_dim(v: i64, name: &str) -> Result<u32, String> {
+        match to_u32(v, name)? {
+            0 => Err("dimension must be non-zero".to_string()),
+            d => Ok(d),
+        }
+    }
+
+    fn to_u8(v: i64, name: &str) -> Result<u8, String> {
+        u8::try_from(v).map_err(|_| format!("{name} out of range: {v}"))
+    }
+
+    fn to_rgba(r: i64, g: i64, b: i64, a: i64) -> Result<image::Rgba<u8>, String> {
+        Ok(image::Rgba([to_u8(r, "r")?, to_u8(g, "g")?, to_u8(b, "b")?, to_u8(a, "a")?]))
+    }
+
     fn open_impl(path: &str) -> Result<u32, String> {
         let img = image::open(path).map_err(|e| e.to_string())?;
-        insert_image(img)
+        insert_image(ops::normalize_hdr(img, ImageFormat::from_path(path).ok()))
     }
 
     fn open_from_bytes_impl(data: &[u8]) -> Result<u32, String> {
         let img = image::load_from_memory(data).map_err(|e| e.to_string())?;
-        insert_image(img)
+        let id = insert_image(ops::normalize_hdr(img, image::guess_format(data).ok()))?;
+        if let Some(orientation) = ops::read_orientation(data) {
+            ORIENTATIONS
+                .lock()
+                .map_err(|_| "orientation lock poisoned".to_string())?
+                .insert(id, orientation);
+        }
+        Ok(id)
+    }
+
+    fn format_from_ext(ext: &str) -> Result<ImageFormat, String> {
+        match ext.to_lowercase().trim_start_matches('.') {
+            "png"        => Ok(ImageFormat::Png),
+            "jpg"|"jpeg" => Ok(ImageFormat::Jpeg),
+            "gif"        => Ok(ImageFormat::Gif),
+            "bmp"        => Ok(ImageFormat::Bmp),
+            "webp"       => Ok(ImageFormat::WebP),
+            other => Err(format!("unsupported image format: {}", other)),
+        }
     }
 
     fn save_to_bytes_impl(id: u64, ext: &str) -> Result<Vec<u8>, String> {
         let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
-        let fmt = match ext.to_lowercase().trim_start_matches('.') {
-            "png"        => ImageFormat::Png,
-            "jpg"|"jpeg" => ImageFormat::Jpeg,
-            "gif"        => ImageFormat::Gif,
-            "bmp"        => ImageFormat::Bmp,
-            "webp"       => ImageFormat::WebP,
-            other => return Err(format!("unsupported image format: {}", other)),
-        };
+        let fmt = format_from_ext(ext)?;
+        let quality = DEFAULT_QUALITY
+            .lock()
+            .map_err(|_| "quality lock poisoned".to_string())?
+            .get(&fmt)
+            .copied();
         let map = IMAGES.lock().map_err(|_| "image lock poisoned".to_string())?;
         let img = get_image(&map, id)?;
-        let mut out = Cursor::new(Vec::new());
-        img.write_to(&mut out, fmt).map_err(|e| e.to_string())?;
-        Ok(out.into_inner())
+        match quality {
+            Some(quality) => ops::encode_with_quality(img, fmt, quality),
+            None => ops::encode_default(img, fmt),
+        }
     }
 
     fn new_rgba_impl(width: i64, height: i64) -> Result<u32, String> {
-        let width = to_u32(width, "width")?;
-        let height = to_u32(height, "height")?;
+        let width = to_dim(width, "width")?;
+        let height = to_dim(height, "height")?;
         let img = DynamicImage::new_rgba8(width, height);
         insert_image(img)
     }
 
     fn resize_impl(id: u64, width: i64, height: i64) -> Result<(), String> {
         let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
-        let width = to_u32(width, "width")?;
-        let height = to_u32(height, "height")?;
+        let width = to_dim(width, "width")?;
+        let height = to_dim(height, "height")?;
         let mut map = IMAGES
             .lock()
             .map_err(|_| "image lock poisoned".to_string())?;
         let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
         let resized = current.resize_exact(width, height, image::imageops::FilterType::Lanczos3);
         *current = resized;
         Ok(())
@@ -102,17 +195,32 @@ mod native {
 
     fn thumbnail_impl(id: u64, width: i64, height: i64) -> Result<(), String> {
         let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
-        let width = to_u32(width, "width")?;
-        let height = to_u32(height, "height")?;
+        let width = to_dim(width, "width")?;
+        let height = to_dim(height, "height")?;
         let mut map = IMAGES
             .lock()
             .map_err(|_| "image lock poisoned".to_string())?;
         let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
         let thumb = current.thumbnail(width, height);
         *current = thumb;
         Ok(())
     }
 
+    fn thumbnail_exact_impl(id: u64, width: i64, height: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let width = to_dim(width, "width")?;
+        let height = to_dim(height, "height")?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let thumb = ops::thumbnail_exact(current, width, height)?;
+        *current = thumb;
+        Ok(())
+    }
+
     fn save_impl(id: u64, path: &str) -> Result<(), String> {
         let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
         let map = IMAGES
@@ -130,7 +238,7 @@ mod native {
             .map_err(|_| "image lock poisoned".to_string())?;
         let img = get_image(&map, id)?;
 
-        let mut out = Cursor::new(Vec::new());
+        let mut out = Cursor::new(Vec::with_capacity(ops::encode_capacity_hint(img, ImageFormat::Png)));
         img.write_to(&mut out, ImageFormat::Png)
             .map_err(|e| e.to_string())?;
         Ok(out.into_inner())
@@ -152,530 +260,7754 @@ mod native {
             .map_err(|_| "image lock poisoned".to_string())?;
         map.remove(&id)
             .ok_or_else(|| format!("invalid image id {}", id))?;
+        ACCESS
+            .lock()
+            .map_err(|_| "access lock poisoned".to_string())?
+            .remove(&id);
+        SELECTIONS
+            .lock()
+            .map_err(|_| "selection lock poisoned".to_string())?
+            .remove(&id);
+        HISTORY
+            .lock()
+            .map_err(|_| "history lock poisoned".to_string())?
+            .remove(&id);
+        COLORSPACES
+            .lock()
+            .map_err(|_| "colorspace lock poisoned".to_string())?
+            .remove(&id);
+        ORIENTATIONS
+            .lock()
+            .map_err(|_| "orientation lock poisoned".to_string())?
+            .remove(&id);
         Ok(())
     }
 
-    #[vo_fn("image", "nativeOpen")]
-    pub fn native_open(call: &mut ExternCallContext) -> ExternResult {
-        let path = call.arg_str(0);
-        match open_impl(path) {
-            Ok(id) => {
-                call.ret_u64(0, id as u64);
-                write_nil_error(call, 1);
-            }
-            Err(msg) => {
-                call.ret_u64(0, 0);
-                write_error_to(call, 1, &msg);
-            }
+    fn compare_aa_tolerant_impl(id_a: u64, id_b: u64, threshold: f64) -> Result<u64, String> {
+        let id_a = u32::try_from(id_a).map_err(|_| format!("id out of range: {id_a}"))?;
+        let id_b = u32::try_from(id_b).map_err(|_| format!("id out of range: {id_b}"))?;
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(format!("threshold must be within 0..=1: {threshold}"));
         }
-        ExternResult::Ok
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let a = get_image(&map, id_a)?.to_rgba8();
+        let b = get_image(&map, id_b)?.to_rgba8();
+        if a.dimensions() != b.dimensions() {
+            return Err("image dimensions differ".to_string());
+        }
+        Ok(ops::compare_aa_tolerant(&a, &b, threshold))
     }
 
-    #[vo_fn("image", "nativeOpenFromBytes")]
-    pub fn native_open_from_bytes(call: &mut ExternCallContext) -> ExternResult {
-        let data = call.arg_bytes(0);
-        match open_from_bytes_impl(data) {
-            Ok(id) => {
-                call.ret_u64(0, id as u64);
-                write_nil_error(call, 1);
-            }
-            Err(msg) => {
-                call.ret_u64(0, 0);
-                write_error_to(call, 1, &msg);
-            }
-        }
-        ExternResult::Ok
+    fn open_first_frame_impl(data: &[u8]) -> Result<u32, String> {
+        let img = ops::decode_first_frame(data)?;
+        insert_image(img)
     }
 
-    #[vo_fn("image", "nativeSaveToBytes")]
-    pub fn native_save_to_bytes(call: &mut ExternCallContext) -> ExternResult {
-        let id = call.arg_u64(0);
-        let ext = call.arg_str(1);
-        match save_to_bytes_impl(id, ext) {
-            Ok(b) => {
-                let r = call.alloc_bytes(&b);
-                call.ret_ref(0, r);
-                write_nil_error(call, 1);
-            }
-            Err(msg) => {
-                call.ret_nil(0);
-                write_error_to(call, 1, &msg);
-            }
-        }
-        ExternResult::Ok
+    fn get_pixels_processed_impl() -> u64 {
+        PIXELS_PROCESSED.load(Ordering::Relaxed)
     }
 
-    #[vo_fn("image", "nativeNewRGBA")]
-    pub fn native_new_rgba(call: &mut ExternCallContext) -> ExternResult {
-        let width = call.arg_i64(0);
-        let height = call.arg_i64(1);
-        match new_rgba_impl(width, height) {
-            Ok(id) => {
-                call.ret_u64(0, id as u64);
-                write_nil_error(call, 1);
-            }
-            Err(msg) => {
-                call.ret_u64(0, 0);
-                write_error_to(call, 1, &msg);
-            }
-        }
-        ExternResult::Ok
+    fn reset_pixels_processed_impl() {
+        PIXELS_PROCESSED.store(0, Ordering::Relaxed);
     }
 
-    #[vo_fn("image", "nativeResize")]
-    pub fn native_resize(call: &mut ExternCallContext) -> ExternResult {
-        let id = call.arg_u64(0);
-        let width = call.arg_i64(1);
-        let height = call.arg_i64(2);
-        match resize_impl(id, width, height) {
-            Ok(()) => write_nil_error(call, 0),
-            Err(msg) => write_error_to(call, 0, &msg),
-        }
-        ExternResult::Ok
+    fn jpeg_lossless_rotate_impl(data: &[u8], degrees: i64) -> Result<Vec<u8>, String> {
+        ops::jpeg_lossless_rotate(data, degrees)
     }
 
-    #[vo_fn("image", "nativeThumbnail")]
-    pub fn native_thumbnail(call: &mut ExternCallContext) -> ExternResult {
-        let id = call.arg_u64(0);
-        let width = call.arg_i64(1);
-        let height = call.arg_i64(2);
-        match thumbnail_impl(id, width, height) {
-            Ok(()) => write_nil_error(call, 0),
-            Err(msg) => write_error_to(call, 0, &msg),
-        }
-        ExternResult::Ok
+    fn set_default_quality_impl(ext: &str, quality: i64) -> Result<(), String> {
+        let fmt = format_from_ext(ext)?;
+        let quality = ops::check_quality(fmt, quality)?;
+        DEFAULT_QUALITY
+            .lock()
+            .map_err(|_| "quality lock poisoned".to_string())?
+            .insert(fmt, quality);
+        Ok(())
     }
 
-    #[vo_fn("image", "nativeSave")]
-    pub fn native_save(call: &mut ExternCallContext) -> ExternResult {
-        let id = call.arg_u64(0);
-        let path = call.arg_str(1);
-        match save_impl(id, path) {
-            Ok(()) => write_nil_error(call, 0),
-            Err(msg) => write_error_to(call, 0, &msg),
-        }
-        ExternResult::Ok
+    fn clear_default_quality_impl(ext: &str) -> Result<(), String> {
+        let fmt = format_from_ext(ext)?;
+        DEFAULT_QUALITY
+            .lock()
+            .map_err(|_| "quality lock poisoned".to_string())?
+            .remove(&fmt);
+        Ok(())
     }
 
-    #[vo_fn("image", "nativeEncodePNG")]
-    pub fn native_encode_png(call: &mut ExternCallContext) -> ExternResult {
-        let id = call.arg_u64(0);
-        match encode_png_impl(id) {
-            Ok(bytes) => {
-                let out_ref = call.alloc_bytes(&bytes);
-                call.ret_ref(0, out_ref);
-                write_nil_error(call, 1);
-            }
-            Err(msg) => {
-                call.ret_nil(0);
-                write_error_to(call, 1, &msg);
-            }
-        }
-        ExternResult::Ok
+    fn resize_pad_impl(
+        id: u64,
+        width: i64,
+        height: i64,
+        r: i64,
+        g: i64,
+        b: i64,
+        a: i64,
+    ) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let width = to_dim(width, "width")?;
+        let height = to_dim(height, "height")?;
+        let color = to_rgba(r, g, b, a)?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let padded = ops::resize_pad(current, width, height, color)?;
+        *current = padded;
+        Ok(())
     }
 
-    #[vo_fn("image", "nativeSize")]
-    pub fn native_size(call: &mut ExternCallContext) -> ExternResult {
-        let id = call.arg_u64(0);
-        match size_impl(id) {
-            Ok((width, height)) => {
-                call.ret_i64(0, width as i64);
-                call.ret_i64(1, height as i64);
-                write_nil_error(call, 2);
-            }
-            Err(msg) => {
-                call.ret_i64(0, 0);
-                call.ret_i64(1, 0);
-                write_error_to(call, 2, &msg);
-            }
-        }
-        ExternResult::Ok
+    fn batch_thumbnail_from_bytes_impl(items: &[u8], max_w: i64, max_h: i64) -> Result<Vec<u8>, String> {
+        let cancel = cancel_token();
+        let max_w = to_u32(max_w, "max_w")?;
+        let max_h = to_u32(max_h, "max_h")?;
+        let blobs = ops::unpack_blobs(items)?;
+        let thumbs = ops::batch_thumbnail(&blobs, max_w, max_h, &cancel)?;
+        Ok(ops::pack_thumbnails(&thumbs))
     }
 
-    #[vo_fn("image", "nativeClose")]
-    pub fn native_close(call: &mut ExternCallContext) -> ExternResult {
-        let id = call.arg_u64(0);
-        match close_impl(id) {
-            Ok(()) => write_nil_error(call, 0),
-            Err(msg) => write_error_to(call, 0, &msg),
+    fn to_f32_bytes_impl(id: u64, scale: f64) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        // 0 selects the natural 8-bit range.
+        let scale = if scale == 0.0 { 255.0 } else { scale };
+        if !scale.is_finite() || scale <= 0.0 {
+            return Err(format!("scale must be positive: {scale}"));
         }
-        ExternResult::Ok
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::to_f32_bytes(img, scale as f32))
     }
 
-    #[cfg(test)]
-    mod tests {
-        use super::*;
-        use std::fs;
-        use std::time::{SystemTime, UNIX_EPOCH};
+    fn standardize_impl(id: u64, means: &[f64], stds: &[f64]) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::standardize(img, means, stds)
+    }
 
-        fn temp_file(name: &str) -> std::path::PathBuf {
-            let nanos = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("clock should be after unix epoch")
-                .as_nanos();
-            std::env::temp_dir().join(format!("vo_image_{name}_{nanos}.png"))
-        }
+    fn trim_alpha_impl(id: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let trimmed = ops::trim_alpha(current)?;
+        *current = trimmed;
+        Ok(())
+    }
 
-        #[test]
-        fn image_lifecycle_and_transform_paths() {
-            let id = new_rgba_impl(64, 32).expect("new_rgba should succeed");
+    fn to_rgb565_bytes_impl(id: u64, big_endian: bool) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::to_rgb565_bytes(img, big_endian))
+    }
 
-            let (w0, h0) = size_impl(id as u64).expect("size should succeed");
-            assert_eq!(w0, 64, "initial width should match creation width");
-            assert_eq!(h0, 32, "initial height should match creation height");
+    fn to_mono_bitmap_impl(id: u64, threshold: i64, dither: bool) -> Result<(Vec<u8>, usize), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let threshold = to_u8(threshold, "threshold")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::to_mono_bitmap(img, threshold, dither))
+    }
 
-            resize_impl(id as u64, 20, 10).expect("resize should succeed");
-            let (w1, h1) = size_impl(id as u64).expect("size after resize should succeed");
-            assert_eq!(w1, 20, "width should be updated by resize");
-            assert_eq!(h1, 10, "height should be updated by resize");
+    fn fade_to_color_impl(id: u64, r: i64, g: i64, b: i64, a: i64, amount: f64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let color = to_rgba(r, g, b, a)?;
+        if !(0.0..=1.0).contains(&amount) {
+            return Err(format!("amount must be within 0..=1: {amount}"));
+        }
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let faded = ops::fade_to_color(current, color, amount);
+        *current = faded;
+        Ok(())
+    }
+
+    fn blurhash_impl(id: u64, x_components: i64, y_components: i64) -> Result<String, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let x_components = to_u32(x_components, "x_components")?;
+        let y_components = to_u32(y_components, "y_components")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::blurhash(img, x_components, y_components)
+    }
+
+    fn open_from_blurhash_impl(hash: &str, width: i64, height: i64, punch: f64) -> Result<u32, String> {
+        let width = to_u32(width, "width")?;
+        let height = to_u32(height, "height")?;
+        if !punch.is_finite() || punch <= 0.0 {
+            return Err(format!("punch must be positive: {punch}"));
+        }
+        let img = ops::from_blurhash(hash, width, height, punch)?;
+        insert_image(img)
+    }
+
+    fn resize_to_pixels_impl(id: u64, max_pixels: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let max_pixels = u64::try_from(max_pixels)
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("max_pixels must be positive: {max_pixels}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        if let Some((w, h)) = ops::pixel_budget_dimensions(current.width(), current.height(), max_pixels) {
+            record_pixels(current);
+            let resized = current.resize_exact(w, h, image::imageops::FilterType::Lanczos3);
+            *current = resized;
+        }
+        Ok(())
+    }
+
+    fn tint_impl(id: u64, r: i64, g: i64, b: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let (r, g, b) = (to_u8(r, "r")?, to_u8(g, "g")?, to_u8(b, "b")?);
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let tinted = ops::tint(current, r, g, b);
+        *current = tinted;
+        Ok(())
+    }
+
+    fn tone_map_impl(id: u64, exposure: f64, gamma: f64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        if !exposure.is_finite() || exposure <= 0.0 {
+            return Err(format!("exposure must be positive: {exposure}"));
+        }
+        if !gamma.is_finite() || gamma <= 0.0 {
+            return Err(format!("gamma must be positive: {gamma}"));
+        }
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let mapped = ops::tone_map(current, exposure, gamma);
+        *current = mapped;
+        Ok(())
+    }
+
+    fn access_info_impl(id: u64) -> Result<(u64, u64), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let access = ACCESS
+            .lock()
+            .map_err(|_| "access lock poisoned".to_string())?;
+        access
+            .get(&id)
+            .copied()
+            .ok_or_else(|| format!("invalid image id {}", id))
+    }
+
+    fn encode_png16_impl(id: u64, upconvert: bool) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::encode_png16(img, upconvert)
+    }
+
+    fn unique_colors_impl(id: u64, limit: i64) -> Result<usize, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let limit = usize::try_from(limit).map_err(|_| format!("limit out of range: {limit}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::unique_colors(img, limit))
+    }
+
+    fn selection_set_impl(id: u64, x: i64, y: i64, width: i64, height: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let x = to_u32(x, "x")?;
+        let y = to_u32(y, "y")?;
+        let width = to_u32(width, "width")?;
+        let height = to_u32(height, "height")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        if x as u64 + width as u64 > img.width() as u64 || y as u64 + height as u64 > img.height() as u64 {
+            return Err("selection out of bounds".to_string());
+        }
+        SELECTIONS
+            .lock()
+            .map_err(|_| "selection lock poisoned".to_string())?
+            .insert(id, ops::Selection { region: (x, y, width, height), size: (img.width(), img.height()) });
+        Ok(())
+    }
+
+    fn selection_clear_impl(id: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        get_image(&map, id)?;
+        SELECTIONS
+            .lock()
+            .map_err(|_| "selection lock poisoned".to_string())?
+            .remove(&id);
+        Ok(())
+    }
+
+    fn snapshot_impl(id: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        let mut history = HISTORY
+            .lock()
+            .map_err(|_| "history lock poisoned".to_string())?;
+        let stack = history.entry(id).or_default();
+        stack.push(img.clone());
+        let depth = HISTORY_DEPTH.load(Ordering::Relaxed);
+        if stack.len() > depth {
+            stack.drain(..stack.len() - depth);
+        }
+        Ok(())
+    }
+
+    fn undo_impl(id: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let mut history = HISTORY
+            .lock()
+            .map_err(|_| "history lock poisoned".to_string())?;
+        let previous = history
+            .get_mut(&id)
+            .and_then(|stack| stack.pop())
+            .ok_or_else(|| "nothing to undo".to_string())?;
+        *current = previous;
+        Ok(())
+    }
+
+    fn set_history_depth_impl(depth: i64) -> Result<(), String> {
+        let depth = usize::try_from(depth).map_err(|_| format!("depth out of range: {depth}"))?;
+        HISTORY_DEPTH.store(depth, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn to_planar_rgba_bytes_impl(id: u64) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::to_planar_rgba(img))
+    }
+
+    fn new_from_planar_impl(width: i64, height: i64, data: &[u8]) -> Result<u32, String> {
+        let width = to_u32(width, "width")?;
+        let height = to_u32(height, "height")?;
+        let img = ops::from_planar_rgba(width, height, data)?;
+        insert_image(img)
+    }
+
+    fn despeckle_impl(id: u64, min_region_size: i64) -> Result<usize, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let min_size = usize::try_from(min_region_size)
+            .map_err(|_| format!("min_region_size out of range: {min_region_size}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let (cleaned, removed) = ops::despeckle(current, min_size);
+        *current = cleaned;
+        Ok(removed)
+    }
+
+    fn replace_color_masked_impl(
+        id: u64,
+        from: [i64; 4],
+        to: [i64; 4],
+        tolerance: f64,
+        metric: i64,
+        mask_id: u64,
+    ) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mask_id = u32::try_from(mask_id).map_err(|_| format!("id out of range: {mask_id}"))?;
+        let from = to_rgba(from[0], from[1], from[2], from[3])?;
+        let to = to_rgba(to[0], to[1], to[2], to[3])?;
+        let metric = ops::ColorMetric::from_code(metric)?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let mask = get_image(&map, mask_id)?.to_luma8();
+        let current = get_image_mut(&mut map, id)?;
+        let replaced = ops::replace_color_masked(current, &mask, from, to, tolerance, metric)?;
+        *current = replaced;
+        Ok(())
+    }
+
+    fn grayscale_weighted_impl(id: u64, wr: f64, wg: f64, wb: f64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let gray = ops::grayscale_weighted(current, [wr, wg, wb])?;
+        *current = gray;
+        Ok(())
+    }
+
+    fn encode_contact_sheet_pdf_impl(
+        ids: &[u32],
+        cols: i64,
+        thumb_w: i64,
+        thumb_h: i64,
+    ) -> Result<Vec<u8>, String> {
+        let cancel = cancel_token();
+        let cols = to_u32(cols, "cols")?;
+        let thumb_w = to_u32(thumb_w, "thumb_w")?;
+        let thumb_h = to_u32(thumb_h, "thumb_h")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let images = ids
+            .iter()
+            .map(|&id| get_image(&map, id))
+            .collect::<Result<Vec<_>, _>>()?;
+        for img in &images {
+            record_pixels(img);
+        }
+        ops::contact_sheet_pdf(&images, cols, thumb_w, thumb_h, &cancel)
+    }
+
+    fn alpha_coverage_impl(id: u64) -> Result<f64, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::alpha_coverage(img))
+    }
+
+    fn gif_decode_all_impl(data: &[u8]) -> Result<u32, String> {
+        let anim = ops::decode_gif_animation(data)?;
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        ANIMATIONS
+            .lock()
+            .map_err(|_| "animation lock poisoned".to_string())?
+            .insert(id, anim);
+        Ok(id)
+    }
+
+    fn gif_map_impl(handle: u64, op: &str) -> Result<(), String> {
+        let handle = u32::try_from(handle).map_err(|_| format!("id out of range: {handle}"))?;
+        let mut anims = ANIMATIONS
+            .lock()
+            .map_err(|_| "animation lock poisoned".to_string())?;
+        let anim = anims
+            .get_mut(&handle)
+            .ok_or_else(|| format!("invalid animation id {handle}"))?;
+        ops::map_gif_frames(anim, op)
+    }
+
+    fn gif_encode_handle_impl(handle: u64) -> Result<Vec<u8>, String> {
+        let handle = u32::try_from(handle).map_err(|_| format!("id out of range: {handle}"))?;
+        let anims = ANIMATIONS
+            .lock()
+            .map_err(|_| "animation lock poisoned".to_string())?;
+        let anim = anims
+            .get(&handle)
+            .ok_or_else(|| format!("invalid animation id {handle}"))?;
+        ops::encode_gif_animation(anim)
+    }
+
+    fn gif_close_impl(handle: u64) -> Result<(), String> {
+        let handle = u32::try_from(handle).map_err(|_| format!("id out of range: {handle}"))?;
+        ANIMATIONS
+            .lock()
+            .map_err(|_| "animation lock poisoned".to_string())?
+            .remove(&handle)
+            .map(|_| ())
+            .ok_or_else(|| format!("invalid animation id {handle}"))
+    }
+
+    fn encode_png_optimized_impl(id: u64) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::encode_png_optimized(img)
+    }
+
+    fn swap_impl(id_a: u64, id_b: u64) -> Result<(), String> {
+        let id_a = u32::try_from(id_a).map_err(|_| format!("id out of range: {id_a}"))?;
+        let id_b = u32::try_from(id_b).map_err(|_| format!("id out of range: {id_b}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        get_image(&map, id_a)?;
+        get_image(&map, id_b)?;
+        // Moves the two DynamicImages between slots; no pixel data is copied.
+        // State describing the content travels with it.
+        ops::swap_entries(&mut map, id_a, id_b);
+        ops::swap_entries(
+            &mut *SELECTIONS.lock().map_err(|_| "selection lock poisoned".to_string())?,
+            id_a,
+            id_b,
+        );
+        ops::swap_entries(&mut *HISTORY.lock().map_err(|_| "history lock poisoned".to_string())?, id_a, id_b);
+        ops::swap_entries(
+            &mut *COLORSPACES.lock().map_err(|_| "colorspace lock poisoned".to_string())?,
+            id_a,
+            id_b,
+        );
+        ops::swap_entries(
+            &mut *ORIENTATIONS.lock().map_err(|_| "orientation lock poisoned".to_string())?,
+            id_a,
+            id_b,
+        );
+        Ok(())
+    }
+
+    fn set_decode_limit_impl(bytes: i64) -> Result<(), String> {
+        let bytes = u64::try_from(bytes).map_err(|_| format!("limit out of range: {bytes}"))?;
+        DECODE_LIMIT.store(bytes, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn open_from_bytes_adaptive_impl(data: &[u8]) -> Result<(u32, u32), String> {
+        let (img, divisor) = ops::decode_adaptive(data, DECODE_LIMIT.load(Ordering::Relaxed))?;
+        let id = insert_image(img)?;
+        if let Some(orientation) = ops::read_orientation(data) {
+            ORIENTATIONS
+                .lock()
+                .map_err(|_| "orientation lock poisoned".to_string())?
+                .insert(id, orientation);
+        }
+        Ok((id, divisor))
+    }
+
+    fn clear_channel_impl(id: u64, channel: i64, value: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let channel = usize::try_from(channel).map_err(|_| format!("channel must be within 0..=3: {channel}"))?;
+        let value = to_u8(value, "value")?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let cleared = ops::clear_channel(current, channel, value)?;
+        *current = cleared;
+        Ok(())
+    }
+
+    fn crop_impl(id: u64, x: i64, y: i64, width: i64, height: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let x = to_u32(x, "x")?;
+        let y = to_u32(y, "y")?;
+        let width = to_dim(width, "width")?;
+        let height = to_dim(height, "height")?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        if x as u64 + width as u64 > current.width() as u64
+            || y as u64 + height as u64 > current.height() as u64
+        {
+            return Err("crop region out of bounds".to_string());
+        }
+        let cropped = current.crop_imm(x, y, width, height);
+        *current = cropped;
+        Ok(())
+    }
+
+    fn emboss_impl(id: u64, direction: i64) -> Result<(), String> {
+        let cancel = cancel_token();
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let direction = to_u32(direction, "direction")?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let embossed = ops::emboss(current, direction, &cancel)?;
+        *current = embossed;
+        Ok(())
+    }
+
+    fn rotate_impl(id: u64, degrees: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let rotated = match degrees {
+            90 => current.rotate90(),
+            180 => current.rotate180(),
+            270 => current.rotate270(),
+            other => return Err(format!("rotation must be 90, 180 or 270 degrees: {other}")),
+        };
+        *current = rotated;
+        Ok(())
+    }
+
+    fn to_ansi_preview_impl(id: u64, cols: i64) -> Result<String, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let cols = to_u32(cols, "cols")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        record_pixels(img);
+        ops::ansi_preview(img, cols)
+    }
+
+    fn flip_horizontal_impl(id: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let flipped = current.fliph();
+        *current = flipped;
+        Ok(())
+    }
+
+    fn flip_vertical_impl(id: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let flipped = current.flipv();
+        *current = flipped;
+        Ok(())
+    }
+
+    fn sharpen_impl(id: u64, strength: f64) -> Result<(), String> {
+        let cancel = cancel_token();
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let sharpened = ops::sharpen(current, strength, &cancel)?;
+        *current = sharpened;
+        Ok(())
+    }
+
+    fn rotate_angle_impl(id: u64, degrees: f64, bg_rgba: u64, interpolation: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        if bg_rgba > u32::MAX as u64 {
+            return Err(format!("packed color out of range: {bg_rgba:#x}"));
+        }
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let rotated = ops::rotate_angle(current, degrees, ops::unpack_rgba(bg_rgba), interpolation)?;
+        *current = rotated;
+        Ok(())
+    }
+
+    // Active selection for `id`, if any. Callers hold IMAGES. A selection set
+    // before `img` changed size no longer describes it and is dropped.
+    fn selection_of(id: u32, img: &DynamicImage) -> Result<Option<(u32, u32, u32, u32)>, String> {
+        let mut sel = SELECTIONS
+            .lock()
+            .map_err(|_| "selection lock poisoned".to_string())?;
+        match sel.get(&id) {
+            Some(s) if s.size == (img.width(), img.height()) => Ok(Some(s.region)),
+            Some(_) => {
+                sel.remove(&id);
+                Ok(None)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn grayscale_impl(id: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let gray = match selection_of(id, current)? {
+            Some(region) => ops::apply_in_region(current, region, |patch| Ok(patch.grayscale()))?,
+            None => current.grayscale(),
+        };
+        *current = gray;
+        Ok(())
+    }
+
+    fn checker_compare_impl(id_a: u64, id_b: u64, cell_size: i64) -> Result<u32, String> {
+        let id_a = u32::try_from(id_a).map_err(|_| format!("id out of range: {id_a}"))?;
+        let id_b = u32::try_from(id_b).map_err(|_| format!("id out of range: {id_b}"))?;
+        let cell_size = to_u32(cell_size, "cell_size")?;
+        let checker = {
+            let map = IMAGES
+                .lock()
+                .map_err(|_| "image lock poisoned".to_string())?;
+            let a = get_image(&map, id_a)?.to_rgba8();
+            let b = get_image(&map, id_b)?.to_rgba8();
+            ops::checker_compare(&a, &b, cell_size)?
+        };
+        insert_image(checker)
+    }
+
+    fn invert_impl(id: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        current.invert();
+        Ok(())
+    }
+
+    fn add_quiet_zone_impl(id: u64, modules: i64, module_size: i64, r: i64, g: i64, b: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let modules = to_u32(modules, "modules")?;
+        let module_size = to_u32(module_size, "module_size")?;
+        let border = modules
+            .checked_mul(module_size)
+            .ok_or_else(|| format!("quiet zone too large: {modules}x{module_size}"))?;
+        let color = to_rgba(r, g, b, 255)?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let padded = ops::pad_border(current, border, color)?;
+        *current = padded;
+        Ok(())
+    }
+
+    fn set_colorspace_impl(id: u64, space: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let space = ops::ColorSpace::from_code(space)?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        get_image(&map, id)?;
+        COLORSPACES
+            .lock()
+            .map_err(|_| "colorspace lock poisoned".to_string())?
+            .insert(id, space);
+        Ok(())
+    }
+
+    // Callers hold IMAGES.
+    fn colorspace_of(id: u32) -> Result<ops::ColorSpace, String> {
+        Ok(COLORSPACES
+            .lock()
+            .map_err(|_| "colorspace lock poisoned".to_string())?
+            .get(&id)
+            .copied()
+            .unwrap_or(ops::ColorSpace::Srgb))
+    }
+
+    fn get_colorspace_impl(id: u64) -> Result<i64, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        get_image(&map, id)?;
+        Ok(colorspace_of(id)?.code())
+    }
+
+    fn resize_linear_impl(id: u64, width: i64, height: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let width = to_dim(width, "width")?;
+        let height = to_dim(height, "height")?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let linearize = colorspace_of(id)?.needs_linearize();
+        record_pixels(current);
+        let resized = ops::resize_linear(current, width, height, linearize);
+        *current = resized;
+        Ok(())
+    }
+
+    fn blur_impl(id: u64, sigma: f64) -> Result<(), String> {
+        let cancel = cancel_token();
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let sigma = ops::blur_sigma(sigma)?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let blurred = match selection_of(id, current)? {
+            Some(region) => ops::apply_in_region(current, region, |patch| ops::blur(patch, sigma, &cancel))?,
+            None => ops::blur(current, sigma, &cancel)?,
+        };
+        *current = blurred;
+        Ok(())
+    }
+
+    fn new_from_nv12_impl(width: i64, height: i64, data: &[u8]) -> Result<u32, String> {
+        let width = to_u32(width, "width")?;
+        let height = to_u32(height, "height")?;
+        let img = ops::from_nv12(width, height, data)?;
+        insert_image(img)
+    }
+
+    fn unsharpen_impl(id: u64, sigma: f64, threshold: i64) -> Result<(), String> {
+        let cancel = cancel_token();
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let sharpened = ops::unsharpen(current, sigma, threshold, &cancel)?;
+        *current = sharpened;
+        Ok(())
+    }
+
+    fn dog_impl(id: u64, sigma1: f64, sigma2: f64) -> Result<(), String> {
+        let cancel = cancel_token();
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let band = ops::difference_of_gaussians(current, sigma1, sigma2, &cancel)?;
+        *current = band;
+        Ok(())
+    }
+
+    fn hue_rotate_impl(id: u64, degrees: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let degrees = i32::try_from(degrees).map_err(|_| format!("degrees out of range: {degrees}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let rotated = current.huerotate(degrees);
+        *current = rotated;
+        Ok(())
+    }
+
+    fn find_color_rect_impl(
+        id: u64,
+        r: i64,
+        g: i64,
+        b: i64,
+        tolerance: i64,
+    ) -> Result<Option<(u32, u32, u32, u32)>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let color = [to_u8(r, "r")?, to_u8(g, "g")?, to_u8(b, "b")?];
+        let tolerance = to_u8(tolerance, "tolerance")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::find_color_rect(img, color, tolerance))
+    }
+
+    fn resize_preserve_impl(id: u64, max_width: i64, max_height: i64) -> Result<(u32, u32), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let max_width = to_dim(max_width, "max_width")?;
+        let max_height = to_dim(max_height, "max_height")?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let resized = current.resize(max_width, max_height, image::imageops::FilterType::Lanczos3);
+        *current = resized;
+        Ok((current.width(), current.height()))
+    }
+
+    fn stencil_impl(base_id: u64, shape_id: u64, x: i64, y: i64, r: i64, g: i64, b: i64) -> Result<(), String> {
+        let base_id = u32::try_from(base_id).map_err(|_| format!("id out of range: {base_id}"))?;
+        let shape_id = u32::try_from(shape_id).map_err(|_| format!("id out of range: {shape_id}"))?;
+        let color = [to_u8(r, "r")?, to_u8(g, "g")?, to_u8(b, "b")?];
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let shape = get_image(&map, shape_id)?.to_rgba8();
+        let current = get_image_mut(&mut map, base_id)?;
+        let stenciled = ops::stencil(current, &shape, x, y, color);
+        *current = stenciled;
+        Ok(())
+    }
+
+    fn resize_with_filter_impl(id: u64, width: i64, height: i64, filter: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let width = to_dim(width, "width")?;
+        let height = to_dim(height, "height")?;
+        let filter = ops::filter_from_code(filter)?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let resized = current.resize_exact(width, height, filter);
+        *current = resized;
+        Ok(())
+    }
+
+    fn color_info_impl(id: u64) -> Result<(i64, i64, i64), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::color_info(img.color()))
+    }
+
+    fn height_to_normal_impl(id: u64, strength: f64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let normals = ops::height_to_normal(current, strength)?;
+        *current = normals;
+        Ok(())
+    }
+
+    fn count_blobs_impl(id: u64, threshold: i64, connectivity: i64) -> Result<usize, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let threshold = to_u8(threshold, "threshold")?;
+        let connectivity = to_u32(connectivity, "connectivity")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::count_blobs(img, threshold, connectivity)
+    }
+
+    fn save_to_bytes_jpeg_impl(id: u64, quality: i64) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let quality = ops::check_quality(ImageFormat::Jpeg, quality)?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::encode_jpeg(img, quality)
+    }
+
+    fn encode_png_with_level_impl(id: u64, level: i64) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let level = ops::check_quality(ImageFormat::Png, level)?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::encode_png_with_level(img, level)
+    }
+
+    fn histogram_region_impl(id: u64, x: i64, y: i64, w: i64, h: i64) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let region = (to_u32(x, "x")?, to_u32(y, "y")?, to_u32(w, "w")?, to_u32(h, "h")?);
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::histogram_region(img, region))
+    }
+
+    fn detect_background_impl(id: u64) -> Result<(image::Rgba<u8>, f64), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::detect_background(img))
+    }
+
+    fn overlay_impl(base_id: u64, top_id: u64, x: i64, y: i64) -> Result<(), String> {
+        let base_id = u32::try_from(base_id).map_err(|_| format!("id out of range: {base_id}"))?;
+        let top_id = u32::try_from(top_id).map_err(|_| format!("id out of range: {top_id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let top = get_image(&map, top_id)?.clone();
+        let current = get_image_mut(&mut map, base_id)?;
+        image::imageops::overlay(current, &top, x, y);
+        Ok(())
+    }
+
+    fn clone_impl(id: u64) -> Result<u32, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let copy = {
+            let map = IMAGES
+                .lock()
+                .map_err(|_| "image lock poisoned".to_string())?;
+            get_image(&map, id)?.clone()
+        };
+        insert_image(copy)
+    }
+
+    fn image_arithmetic_impl(base_id: u64, other_id: u64, op: i64) -> Result<(), String> {
+        let base_id = u32::try_from(base_id).map_err(|_| format!("id out of range: {base_id}"))?;
+        let other_id = u32::try_from(other_id).map_err(|_| format!("id out of range: {other_id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let other = get_image(&map, other_id)?.clone();
+        let current = get_image_mut(&mut map, base_id)?;
+        record_pixels(current);
+        let combined = ops::image_arithmetic(current, &other, op)?;
+        *current = combined;
+        Ok(())
+    }
+
+    fn fill_rect_impl(id: u64, x: i64, y: i64, width: i64, height: i64, rgba: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let region = (to_u32(x, "x")?, to_u32(y, "y")?, to_dim(width, "width")?, to_dim(height, "height")?);
+        if rgba > u32::MAX as u64 {
+            return Err(format!("packed color out of range: {rgba:#x}"));
+        }
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let filled = ops::fill_rect(current, region, ops::unpack_rgba(rgba), selection_of(id, current)?)?;
+        *current = filled;
+        Ok(())
+    }
+
+    fn selective_hue_rotate_impl(id: u64, target_hue: i64, range: i64, rotate_by: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let rotated = ops::selective_hue_rotate(current, target_hue as f64, range as f64, rotate_by as f64)?;
+        *current = rotated;
+        Ok(())
+    }
+
+    fn pixel_coords(img: &DynamicImage, x: i64, y: i64) -> Result<(u32, u32), String> {
+        match (u32::try_from(x), u32::try_from(y)) {
+            (Ok(x), Ok(y)) if x < img.width() && y < img.height() => Ok((x, y)),
+            _ => Err("pixel out of bounds".to_string()),
+        }
+    }
+
+    fn get_pixel_impl(id: u64, x: i64, y: i64) -> Result<u64, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        let (x, y) = pixel_coords(img, x, y)?;
+        Ok(ops::pack_rgba(image::GenericImageView::get_pixel(img, x, y)))
+    }
+
+    fn set_pixel_impl(id: u64, x: i64, y: i64, rgba: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        if rgba > u32::MAX as u64 {
+            return Err(format!("packed color out of range: {rgba:#x}"));
+        }
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let (x, y) = pixel_coords(current, x, y)?;
+        if !matches!(current, DynamicImage::ImageRgba8(_)) {
+            *current = DynamicImage::ImageRgba8(current.to_rgba8());
+        }
+        if let DynamicImage::ImageRgba8(rgba_img) = current {
+            rgba_img.put_pixel(x, y, ops::unpack_rgba(rgba));
+        }
+        Ok(())
+    }
+
+    fn favicon_set_impl(id: u64, sizes: &[u32]) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::favicon_set(img, sizes)
+    }
+
+    fn sample_impl(id: u64, x: f64, y: f64, interpolation: i64) -> Result<u64, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::sample(img, x, y, interpolation).map(ops::pack_rgba)
+    }
+
+    fn new_like_impl(id: u64, r: i64, g: i64, b: i64, a: i64) -> Result<u32, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let color = to_rgba(r, g, b, a)?;
+        let (width, height) = {
+            let map = IMAGES
+                .lock()
+                .map_err(|_| "image lock poisoned".to_string())?;
+            let img = get_image(&map, id)?;
+            (img.width(), img.height())
+        };
+        insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(width, height, color)))
+    }
+
+    fn thumbnail_fit_impl(id: u64, width: i64, height: i64, mode: i64) -> Result<(u32, u32), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let width = to_dim(width, "width")?;
+        let height = to_dim(height, "height")?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        let thumb = ops::thumbnail_fit(current, width, height, mode)?;
+        *current = thumb;
+        Ok((current.width(), current.height()))
+    }
+
+    fn cancel_current_impl() {
+        CANCEL_EPOCH.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn auto_orient_impl(id: u64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        let orientation = ORIENTATIONS
+            .lock()
+            .map_err(|_| "orientation lock poisoned".to_string())?
+            .remove(&id);
+        if let Some(orientation) = orientation {
+            current.apply_orientation(orientation);
+        }
+        Ok(())
+    }
+
+    fn guess_format_impl(data: &[u8]) -> Result<i64, String> {
+        ops::guess_format_code(data)
+    }
+
+    fn encode_jpeg_with_thumbnail_impl(id: u64, quality: i64, thumb_w: i64, thumb_h: i64) -> Result<Vec<u8>, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let quality = ops::check_quality(ImageFormat::Jpeg, quality)?;
+        let thumb_w = to_dim(thumb_w, "thumb_w")?;
+        let thumb_h = to_dim(thumb_h, "thumb_h")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        ops::encode_jpeg_with_thumbnail(img, quality, thumb_w, thumb_h)
+    }
+
+    fn estimate_skew_impl(id: u64) -> Result<f64, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let img = get_image(&map, id)?;
+        Ok(ops::estimate_skew(img))
+    }
+
+    fn dimensions_from_bytes_impl(data: &[u8]) -> Result<(u32, u32), String> {
+        ops::dimensions_from_bytes(data)
+    }
+
+    fn frame_info_impl(data: &[u8]) -> Result<(u32, u64), String> {
+        ops::frame_info(data)
+    }
+
+    fn reduce_impl(id: u64, op: i64) -> Result<u32, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let reduced = {
+            let map = IMAGES
+                .lock()
+                .map_err(|_| "image lock poisoned".to_string())?;
+            ops::reduce(get_image(&map, id)?, op)?
+        };
+        insert_image(reduced)
+    }
+
+    fn draw_grid_impl(id: u64, spacing: i64, r: i64, g: i64, b: i64, a: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let spacing = to_dim(spacing, "spacing")?;
+        let color = to_rgba(r, g, b, a)?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        *current = ops::draw_grid(current, spacing, color);
+        Ok(())
+    }
+
+    fn extract_frame_impl(data: &[u8], index: i64) -> Result<u32, String> {
+        let index = u64::try_from(index).map_err(|_| "frame index out of range".to_string())?;
+        insert_image(ops::extract_gif_frame(data, index)?)
+    }
+
+    fn encode_gif_impl(ids: &[u32], delay_ms: i64) -> Result<Vec<u8>, String> {
+        let delay_ms = to_u32(delay_ms, "delay_ms")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let images = ids
+            .iter()
+            .map(|&id| get_image(&map, id))
+            .collect::<Result<Vec<_>, _>>()?;
+        ops::encode_gif_frames(&images, delay_ms)
+    }
+
+    fn effective_bit_depth_impl(id: u64) -> Result<u32, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        Ok(ops::effective_bit_depth(get_image(&map, id)?))
+    }
+
+    fn average_color_impl(id: u64) -> Result<u64, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        Ok(ops::pack_rgba(ops::average_color(get_image(&map, id)?)))
+    }
+
+    fn morphology_impl(id: u64, radius: i64, dilate: bool) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let radius = to_u32(radius, "radius")?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        record_pixels(current);
+        *current = ops::morphology(current, radius, dilate);
+        Ok(())
+    }
+
+    fn erode_impl(id: u64, radius: i64) -> Result<(), String> {
+        morphology_impl(id, radius, false)
+    }
+
+    fn dilate_impl(id: u64, radius: i64) -> Result<(), String> {
+        morphology_impl(id, radius, true)
+    }
+
+    fn perceptual_hash_impl(id: u64) -> Result<u64, String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        Ok(ops::average_hash(get_image(&map, id)?))
+    }
+
+    // Hands spare map capacity back to the allocator, e.g. after closing a
+    // large batch. Side tables are keyed by image id, so they shrink too.
+    fn shrink_to_fit_impl() -> Result<(), String> {
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        map.shrink_to_fit();
+        ACCESS
+            .lock()
+            .map_err(|_| "access lock poisoned".to_string())?
+            .shrink_to_fit();
+        SELECTIONS
+            .lock()
+            .map_err(|_| "selection lock poisoned".to_string())?
+            .shrink_to_fit();
+        HISTORY
+            .lock()
+            .map_err(|_| "history lock poisoned".to_string())?
+            .shrink_to_fit();
+        COLORSPACES
+            .lock()
+            .map_err(|_| "colorspace lock poisoned".to_string())?
+            .shrink_to_fit();
+        ORIENTATIONS
+            .lock()
+            .map_err(|_| "orientation lock poisoned".to_string())?
+            .shrink_to_fit();
+        Ok(())
+    }
+
+    fn apply_colormap_impl(id: u64, colormap: &str) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        *current = ops::apply_colormap(current, colormap)?;
+        Ok(())
+    }
+
+    fn hash_distance_impl(hash_a: u64, hash_b: u64) -> u32 {
+        (hash_a ^ hash_b).count_ones()
+    }
+
+    fn compare_impl(id_a: u64, id_b: u64, tolerance: i64) -> Result<u64, String> {
+        let id_a = u32::try_from(id_a).map_err(|_| format!("id out of range: {id_a}"))?;
+        let id_b = u32::try_from(id_b).map_err(|_| format!("id out of range: {id_b}"))?;
+        let tolerance = to_u8(tolerance, "tolerance")?;
+        let map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let a = get_image(&map, id_a)?.to_rgba8();
+        let b = get_image(&map, id_b)?.to_rgba8();
+        if a.dimensions() != b.dimensions() {
+            return Err("image dimensions differ".to_string());
+        }
+        Ok(ops::count_differing(&a, &b, tolerance))
+    }
+
+    fn threshold_alpha_impl(id: u64, level: i64) -> Result<(), String> {
+        let id = u32::try_from(id).map_err(|_| format!("id out of range: {id}"))?;
+        let level = to_u8(level, "level")?;
+        let mut map = IMAGES
+            .lock()
+            .map_err(|_| "image lock poisoned".to_string())?;
+        let current = get_image_mut(&mut map, id)?;
+        *current = ops::threshold_alpha(current, level);
+        Ok(())
+    }
+
+    #[vo_fn("image", "nativeOpen")]
+    pub fn native_open(call: &mut ExternCallContext) -> ExternResult {
+        let path = call.arg_str(0);
+        match open_impl(path) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeOpenFromBytes")]
+    pub fn native_open_from_bytes(call: &mut ExternCallContext) -> ExternResult {
+        let data = call.arg_bytes(0);
+        match open_from_bytes_impl(data) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSaveToBytes")]
+    pub fn native_save_to_bytes(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let ext = call.arg_str(1);
+        match save_to_bytes_impl(id, ext) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeNewRGBA")]
+    pub fn native_new_rgba(call: &mut ExternCallContext) -> ExternResult {
+        let width = call.arg_i64(0);
+        let height = call.arg_i64(1);
+        match new_rgba_impl(width, height) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeResize")]
+    pub fn native_resize(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let width = call.arg_i64(1);
+        let height = call.arg_i64(2);
+        match resize_impl(id, width, height) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeThumbnail")]
+    pub fn native_thumbnail(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let width = call.arg_i64(1);
+        let height = call.arg_i64(2);
+        match thumbnail_impl(id, width, height) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeThumbnailExact")]
+    pub fn native_thumbnail_exact(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let width = call.arg_i64(1);
+        let height = call.arg_i64(2);
+        match thumbnail_exact_impl(id, width, height) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSave")]
+    pub fn native_save(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let path = call.arg_str(1);
+        match save_impl(id, path) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEncodePNG")]
+    pub fn native_encode_png(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match encode_png_impl(id) {
+            Ok(bytes) => {
+                let out_ref = call.alloc_bytes(&bytes);
+                call.ret_ref(0, out_ref);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSize")]
+    pub fn native_size(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match size_impl(id) {
+            Ok((width, height)) => {
+                call.ret_i64(0, width as i64);
+                call.ret_i64(1, height as i64);
+                write_nil_error(call, 2);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                call.ret_i64(1, 0);
+                write_error_to(call, 2, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeClose")]
+    pub fn native_close(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match close_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeCompareAATolerant")]
+    pub fn native_compare_aa_tolerant(call: &mut ExternCallContext) -> ExternResult {
+        let id_a = call.arg_u64(0);
+        let id_b = call.arg_u64(1);
+        let threshold = call.arg_f64(2);
+        match compare_aa_tolerant_impl(id_a, id_b, threshold) {
+            Ok(count) => {
+                call.ret_i64(0, count as i64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeOpenFirstFrame")]
+    pub fn native_open_first_frame(call: &mut ExternCallContext) -> ExternResult {
+        let data = call.arg_bytes(0);
+        match open_first_frame_impl(data) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGetPixelsProcessed")]
+    pub fn native_get_pixels_processed(call: &mut ExternCallContext) -> ExternResult {
+        call.ret_u64(0, get_pixels_processed_impl());
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeResetPixelsProcessed")]
+    pub fn native_reset_pixels_processed(_call: &mut ExternCallContext) -> ExternResult {
+        reset_pixels_processed_impl();
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeJPEGLosslessRotate")]
+    pub fn native_jpeg_lossless_rotate(call: &mut ExternCallContext) -> ExternResult {
+        let data = call.arg_bytes(0);
+        let degrees = call.arg_i64(1);
+        match jpeg_lossless_rotate_impl(data, degrees) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSetDefaultQuality")]
+    pub fn native_set_default_quality(call: &mut ExternCallContext) -> ExternResult {
+        let ext = call.arg_str(0);
+        let quality = call.arg_i64(1);
+        match set_default_quality_impl(ext, quality) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeClearDefaultQuality")]
+    pub fn native_clear_default_quality(call: &mut ExternCallContext) -> ExternResult {
+        let ext = call.arg_str(0);
+        match clear_default_quality_impl(ext) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeResizePad")]
+    pub fn native_resize_pad(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let width = call.arg_i64(1);
+        let height = call.arg_i64(2);
+        let (r, g, b, a) = (call.arg_i64(3), call.arg_i64(4), call.arg_i64(5), call.arg_i64(6));
+        match resize_pad_impl(id, width, height, r, g, b, a) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeBatchThumbnailFromBytes")]
+    pub fn native_batch_thumbnail_from_bytes(call: &mut ExternCallContext) -> ExternResult {
+        let items = call.arg_bytes(0);
+        let max_w = call.arg_i64(1);
+        let max_h = call.arg_i64(2);
+        match batch_thumbnail_from_bytes_impl(items, max_w, max_h) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeToF32Bytes")]
+    pub fn native_to_f32_bytes(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let scale = call.arg_f64(1);
+        match to_f32_bytes_impl(id, scale) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeStandardize")]
+    pub fn native_standardize(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let result = ops::unpack_f64s(call.arg_bytes(1)).and_then(|means| {
+            let stds = ops::unpack_f64s(call.arg_bytes(2))?;
+            standardize_impl(id, &means, &stds)
+        });
+        match result {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeTrimAlpha")]
+    pub fn native_trim_alpha(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match trim_alpha_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeToRGB565Bytes")]
+    pub fn native_to_rgb565_bytes(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let big_endian = call.arg_bool(1);
+        match to_rgb565_bytes_impl(id, big_endian) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeToMonoBitmap")]
+    pub fn native_to_mono_bitmap(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let threshold = call.arg_i64(1);
+        let dither = call.arg_bool(2);
+        match to_mono_bitmap_impl(id, threshold, dither) {
+            Ok((b, stride)) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                call.ret_i64(1, stride as i64);
+                write_nil_error(call, 2);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                call.ret_i64(1, 0);
+                write_error_to(call, 2, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeFadeToColor")]
+    pub fn native_fade_to_color(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let (r, g, b, a) = (call.arg_i64(1), call.arg_i64(2), call.arg_i64(3), call.arg_i64(4));
+        let amount = call.arg_f64(5);
+        match fade_to_color_impl(id, r, g, b, a, amount) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeBlurhash")]
+    pub fn native_blurhash(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let x_components = call.arg_i64(1);
+        let y_components = call.arg_i64(2);
+        match blurhash_impl(id, x_components, y_components) {
+            Ok(hash) => {
+                let r = call.alloc_bytes(hash.as_bytes());
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeOpenFromBlurhash")]
+    pub fn native_open_from_blurhash(call: &mut ExternCallContext) -> ExternResult {
+        let hash = call.arg_str(0);
+        let width = call.arg_i64(1);
+        let height = call.arg_i64(2);
+        let punch = call.arg_f64(3);
+        match open_from_blurhash_impl(hash, width, height, punch) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeResizeToPixels")]
+    pub fn native_resize_to_pixels(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let max_pixels = call.arg_i64(1);
+        match resize_to_pixels_impl(id, max_pixels) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeTint")]
+    pub fn native_tint(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let (r, g, b) = (call.arg_i64(1), call.arg_i64(2), call.arg_i64(3));
+        match tint_impl(id, r, g, b) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeToneMap")]
+    pub fn native_tone_map(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let exposure = call.arg_f64(1);
+        let gamma = call.arg_f64(2);
+        match tone_map_impl(id, exposure, gamma) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeAccessInfo")]
+    pub fn native_access_info(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match access_info_impl(id) {
+            Ok((created, last)) => {
+                call.ret_i64(0, created as i64);
+                call.ret_i64(1, last as i64);
+                write_nil_error(call, 2);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                call.ret_i64(1, 0);
+                write_error_to(call, 2, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEncodePNG16")]
+    pub fn native_encode_png16(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let upconvert = call.arg_bool(1);
+        match encode_png16_impl(id, upconvert) {
+            Ok(bytes) => {
+                let out_ref = call.alloc_bytes(&bytes);
+                call.ret_ref(0, out_ref);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeUniqueColors")]
+    pub fn native_unique_colors(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let limit = call.arg_i64(1);
+        match unique_colors_impl(id, limit) {
+            Ok(count) => {
+                call.ret_i64(0, count as i64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSelectionSet")]
+    pub fn native_selection_set(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let x = call.arg_i64(1);
+        let y = call.arg_i64(2);
+        let width = call.arg_i64(3);
+        let height = call.arg_i64(4);
+        match selection_set_impl(id, x, y, width, height) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSelectionClear")]
+    pub fn native_selection_clear(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match selection_clear_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSnapshot")]
+    pub fn native_snapshot(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match snapshot_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeUndo")]
+    pub fn native_undo(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match undo_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSetHistoryDepth")]
+    pub fn native_set_history_depth(call: &mut ExternCallContext) -> ExternResult {
+        let depth = call.arg_i64(0);
+        match set_history_depth_impl(depth) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeToPlanarRGBABytes")]
+    pub fn native_to_planar_rgba_bytes(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match to_planar_rgba_bytes_impl(id) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeNewFromPlanarRGBA")]
+    pub fn native_new_from_planar_rgba(call: &mut ExternCallContext) -> ExternResult {
+        let width = call.arg_i64(0);
+        let height = call.arg_i64(1);
+        let data = call.arg_bytes(2);
+        match new_from_planar_impl(width, height, data) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeDespeckle")]
+    pub fn native_despeckle(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let min_region_size = call.arg_i64(1);
+        match despeckle_impl(id, min_region_size) {
+            Ok(removed) => {
+                call.ret_i64(0, removed as i64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeReplaceColorMasked")]
+    pub fn native_replace_color_masked(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let from = [call.arg_i64(1), call.arg_i64(2), call.arg_i64(3), call.arg_i64(4)];
+        let to = [call.arg_i64(5), call.arg_i64(6), call.arg_i64(7), call.arg_i64(8)];
+        let tolerance = call.arg_f64(9);
+        let metric = call.arg_i64(10);
+        let mask_id = call.arg_u64(11);
+        match replace_color_masked_impl(id, from, to, tolerance, metric, mask_id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGrayscaleWeighted")]
+    pub fn native_grayscale_weighted(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let wr = call.arg_f64(1);
+        let wg = call.arg_f64(2);
+        let wb = call.arg_f64(3);
+        match grayscale_weighted_impl(id, wr, wg, wb) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEncodeContactSheetPDF")]
+    pub fn native_encode_contact_sheet_pdf(call: &mut ExternCallContext) -> ExternResult {
+        let cols = call.arg_i64(1);
+        let thumb_w = call.arg_i64(2);
+        let thumb_h = call.arg_i64(3);
+        let result = ops::unpack_ids(call.arg_bytes(0))
+            .and_then(|ids| encode_contact_sheet_pdf_impl(&ids, cols, thumb_w, thumb_h));
+        match result {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeAlphaCoverage")]
+    pub fn native_alpha_coverage(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match alpha_coverage_impl(id) {
+            Ok(coverage) => {
+                call.ret_u64(0, coverage.to_bits());
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGIFDecodeAll")]
+    pub fn native_gif_decode_all(call: &mut ExternCallContext) -> ExternResult {
+        let data = call.arg_bytes(0);
+        match gif_decode_all_impl(data) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGIFMap")]
+    pub fn native_gif_map(call: &mut ExternCallContext) -> ExternResult {
+        let handle = call.arg_u64(0);
+        let op = call.arg_str(1);
+        match gif_map_impl(handle, op) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGIFEncode")]
+    pub fn native_gif_encode(call: &mut ExternCallContext) -> ExternResult {
+        let handle = call.arg_u64(0);
+        match gif_encode_handle_impl(handle) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGIFClose")]
+    pub fn native_gif_close(call: &mut ExternCallContext) -> ExternResult {
+        let handle = call.arg_u64(0);
+        match gif_close_impl(handle) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEncodePNGOptimized")]
+    pub fn native_encode_png_optimized(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match encode_png_optimized_impl(id) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSwap")]
+    pub fn native_swap(call: &mut ExternCallContext) -> ExternResult {
+        let id_a = call.arg_u64(0);
+        let id_b = call.arg_u64(1);
+        match swap_impl(id_a, id_b) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSetDecodeLimit")]
+    pub fn native_set_decode_limit(call: &mut ExternCallContext) -> ExternResult {
+        let bytes = call.arg_i64(0);
+        match set_decode_limit_impl(bytes) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeOpenFromBytesAdaptive")]
+    pub fn native_open_from_bytes_adaptive(call: &mut ExternCallContext) -> ExternResult {
+        let data = call.arg_bytes(0);
+        match open_from_bytes_adaptive_impl(data) {
+            Ok((id, divisor)) => {
+                call.ret_u64(0, id as u64);
+                call.ret_i64(1, divisor as i64);
+                write_nil_error(call, 2);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                call.ret_i64(1, 0);
+                write_error_to(call, 2, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeClearChannel")]
+    pub fn native_clear_channel(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let channel = call.arg_i64(1);
+        let value = call.arg_i64(2);
+        match clear_channel_impl(id, channel, value) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeCrop")]
+    pub fn native_crop(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let x = call.arg_i64(1);
+        let y = call.arg_i64(2);
+        let width = call.arg_i64(3);
+        let height = call.arg_i64(4);
+        match crop_impl(id, x, y, width, height) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEmboss")]
+    pub fn native_emboss(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let direction = call.arg_i64(1);
+        match emboss_impl(id, direction) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeRotate")]
+    pub fn native_rotate(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let degrees = call.arg_i64(1);
+        match rotate_impl(id, degrees) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeToANSIPreview")]
+    pub fn native_to_ansi_preview(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let cols = call.arg_i64(1);
+        match to_ansi_preview_impl(id, cols) {
+            Ok(preview) => {
+                let r = call.alloc_bytes(preview.as_bytes());
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeFlipHorizontal")]
+    pub fn native_flip_horizontal(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match flip_horizontal_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeFlipVertical")]
+    pub fn native_flip_vertical(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match flip_vertical_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSharpen")]
+    pub fn native_sharpen(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let strength = call.arg_f64(1);
+        match sharpen_impl(id, strength) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeRotateAngle")]
+    pub fn native_rotate_angle(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let degrees = call.arg_f64(1);
+        let bg_rgba = call.arg_u64(2);
+        let interpolation = call.arg_i64(3);
+        match rotate_angle_impl(id, degrees, bg_rgba, interpolation) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGrayscale")]
+    pub fn native_grayscale(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match grayscale_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeCheckerCompare")]
+    pub fn native_checker_compare(call: &mut ExternCallContext) -> ExternResult {
+        let id_a = call.arg_u64(0);
+        let id_b = call.arg_u64(1);
+        let cell_size = call.arg_i64(2);
+        match checker_compare_impl(id_a, id_b, cell_size) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeInvert")]
+    pub fn native_invert(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match invert_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeAddQuietZone")]
+    pub fn native_add_quiet_zone(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let modules = call.arg_i64(1);
+        let module_size = call.arg_i64(2);
+        let r = call.arg_i64(3);
+        let g = call.arg_i64(4);
+        let b = call.arg_i64(5);
+        match add_quiet_zone_impl(id, modules, module_size, r, g, b) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSetColorspace")]
+    pub fn native_set_colorspace(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let space = call.arg_i64(1);
+        match set_colorspace_impl(id, space) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGetColorspace")]
+    pub fn native_get_colorspace(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match get_colorspace_impl(id) {
+            Ok(space) => {
+                call.ret_i64(0, space);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeResizeLinear")]
+    pub fn native_resize_linear(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let width = call.arg_i64(1);
+        let height = call.arg_i64(2);
+        match resize_linear_impl(id, width, height) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeBlur")]
+    pub fn native_blur(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let sigma = call.arg_f64(1);
+        match blur_impl(id, sigma) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeNewFromNV12")]
+    pub fn native_new_from_nv12(call: &mut ExternCallContext) -> ExternResult {
+        let width = call.arg_i64(0);
+        let height = call.arg_i64(1);
+        let data = call.arg_bytes(2);
+        match new_from_nv12_impl(width, height, data) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeUnsharpen")]
+    pub fn native_unsharpen(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let sigma = call.arg_f64(1);
+        let threshold = call.arg_i64(2);
+        match unsharpen_impl(id, sigma, threshold) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeDoG")]
+    pub fn native_dog(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let sigma1 = call.arg_f64(1);
+        let sigma2 = call.arg_f64(2);
+        match dog_impl(id, sigma1, sigma2) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeHueRotate")]
+    pub fn native_hue_rotate(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let degrees = call.arg_i64(1);
+        match hue_rotate_impl(id, degrees) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeFindColorRect")]
+    pub fn native_find_color_rect(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let r = call.arg_i64(1);
+        let g = call.arg_i64(2);
+        let b = call.arg_i64(3);
+        let tolerance = call.arg_i64(4);
+        match find_color_rect_impl(id, r, g, b, tolerance) {
+            Ok(found) => {
+                let (x, y, w, h) = found.unwrap_or_default();
+                call.ret_i64(0, x as i64);
+                call.ret_i64(1, y as i64);
+                call.ret_i64(2, w as i64);
+                call.ret_i64(3, h as i64);
+                call.ret_i64(4, found.is_some() as i64);
+                write_nil_error(call, 5);
+            }
+            Err(msg) => {
+                for slot in 0..5 {
+                    call.ret_i64(slot, 0);
+                }
+                write_error_to(call, 5, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeResizePreserve")]
+    pub fn native_resize_preserve(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let max_width = call.arg_i64(1);
+        let max_height = call.arg_i64(2);
+        match resize_preserve_impl(id, max_width, max_height) {
+            Ok((w, h)) => {
+                call.ret_i64(0, w as i64);
+                call.ret_i64(1, h as i64);
+                write_nil_error(call, 2);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                call.ret_i64(1, 0);
+                write_error_to(call, 2, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeStencil")]
+    pub fn native_stencil(call: &mut ExternCallContext) -> ExternResult {
+        let base_id = call.arg_u64(0);
+        let shape_id = call.arg_u64(1);
+        let x = call.arg_i64(2);
+        let y = call.arg_i64(3);
+        let r = call.arg_i64(4);
+        let g = call.arg_i64(5);
+        let b = call.arg_i64(6);
+        match stencil_impl(base_id, shape_id, x, y, r, g, b) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeResizeWithFilter")]
+    pub fn native_resize_with_filter(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let width = call.arg_i64(1);
+        let height = call.arg_i64(2);
+        let filter = call.arg_i64(3);
+        match resize_with_filter_impl(id, width, height, filter) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeColorInfo")]
+    pub fn native_color_info(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match color_info_impl(id) {
+            Ok((channels, bits, alpha)) => {
+                call.ret_i64(0, channels);
+                call.ret_i64(1, bits);
+                call.ret_i64(2, alpha);
+                write_nil_error(call, 3);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                call.ret_i64(1, 0);
+                call.ret_i64(2, 0);
+                write_error_to(call, 3, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeHeightToNormal")]
+    pub fn native_height_to_normal(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let strength = call.arg_f64(1);
+        match height_to_normal_impl(id, strength) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeCountBlobs")]
+    pub fn native_count_blobs(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let threshold = call.arg_i64(1);
+        let connectivity = call.arg_i64(2);
+        match count_blobs_impl(id, threshold, connectivity) {
+            Ok(count) => {
+                call.ret_i64(0, count as i64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSaveToBytesJpeg")]
+    pub fn native_save_to_bytes_jpeg(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let quality = call.arg_i64(1);
+        match save_to_bytes_jpeg_impl(id, quality) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEncodePNGWithLevel")]
+    pub fn native_encode_png_with_level(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let level = call.arg_i64(1);
+        match encode_png_with_level_impl(id, level) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeHistogramRegion")]
+    pub fn native_histogram_region(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let x = call.arg_i64(1);
+        let y = call.arg_i64(2);
+        let w = call.arg_i64(3);
+        let h = call.arg_i64(4);
+        match histogram_region_impl(id, x, y, w, h) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeDetectBackground")]
+    pub fn native_detect_background(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match detect_background_impl(id) {
+            Ok((color, confidence)) => {
+                call.ret_u64(0, ops::pack_rgba(color));
+                call.ret_u64(1, confidence.to_bits());
+                write_nil_error(call, 2);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                call.ret_u64(1, 0);
+                write_error_to(call, 2, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeOverlay")]
+    pub fn native_overlay(call: &mut ExternCallContext) -> ExternResult {
+        let base_id = call.arg_u64(0);
+        let top_id = call.arg_u64(1);
+        let x = call.arg_i64(2);
+        let y = call.arg_i64(3);
+        match overlay_impl(base_id, top_id, x, y) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeClone")]
+    pub fn native_clone(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match clone_impl(id) {
+            Ok(copy) => {
+                call.ret_u64(0, copy as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeImageArithmetic")]
+    pub fn native_image_arithmetic(call: &mut ExternCallContext) -> ExternResult {
+        let base_id = call.arg_u64(0);
+        let other_id = call.arg_u64(1);
+        let op = call.arg_i64(2);
+        match image_arithmetic_impl(base_id, other_id, op) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeFillRect")]
+    pub fn native_fill_rect(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let x = call.arg_i64(1);
+        let y = call.arg_i64(2);
+        let width = call.arg_i64(3);
+        let height = call.arg_i64(4);
+        let rgba = call.arg_u64(5);
+        match fill_rect_impl(id, x, y, width, height, rgba) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSelectiveHueRotate")]
+    pub fn native_selective_hue_rotate(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let target_hue = call.arg_i64(1);
+        let range = call.arg_i64(2);
+        let rotate_by = call.arg_i64(3);
+        match selective_hue_rotate_impl(id, target_hue, range, rotate_by) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGetPixel")]
+    pub fn native_get_pixel(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let x = call.arg_i64(1);
+        let y = call.arg_i64(2);
+        match get_pixel_impl(id, x, y) {
+            Ok(rgba) => {
+                call.ret_u64(0, rgba);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSetPixel")]
+    pub fn native_set_pixel(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let x = call.arg_i64(1);
+        let y = call.arg_i64(2);
+        let rgba = call.arg_u64(3);
+        match set_pixel_impl(id, x, y, rgba) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeFaviconSet")]
+    pub fn native_favicon_set(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let result = ops::unpack_u32s(call.arg_bytes(1), "size").and_then(|sizes| favicon_set_impl(id, &sizes));
+        match result {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeSample")]
+    pub fn native_sample(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let x = call.arg_f64(1);
+        let y = call.arg_f64(2);
+        let interpolation = call.arg_i64(3);
+        match sample_impl(id, x, y, interpolation) {
+            Ok(rgba) => {
+                call.ret_u64(0, rgba);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeNewLike")]
+    pub fn native_new_like(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let r = call.arg_i64(1);
+        let g = call.arg_i64(2);
+        let b = call.arg_i64(3);
+        let a = call.arg_i64(4);
+        match new_like_impl(id, r, g, b, a) {
+            Ok(new_id) => {
+                call.ret_u64(0, new_id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeThumbnailFit")]
+    pub fn native_thumbnail_fit(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let width = call.arg_i64(1);
+        let height = call.arg_i64(2);
+        let mode = call.arg_i64(3);
+        match thumbnail_fit_impl(id, width, height, mode) {
+            Ok((w, h)) => {
+                call.ret_i64(0, w as i64);
+                call.ret_i64(1, h as i64);
+                write_nil_error(call, 2);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                call.ret_i64(1, 0);
+                write_error_to(call, 2, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeCancelCurrent")]
+    pub fn native_cancel_current(_call: &mut ExternCallContext) -> ExternResult {
+        cancel_current_impl();
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeAutoOrient")]
+    pub fn native_auto_orient(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match auto_orient_impl(id) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeGuessFormat")]
+    pub fn native_guess_format(call: &mut ExternCallContext) -> ExternResult {
+        let data = call.arg_bytes(0);
+        match guess_format_impl(data) {
+            Ok(code) => {
+                call.ret_i64(0, code);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEncodeJPEGWithThumbnail")]
+    pub fn native_encode_jpeg_with_thumbnail(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let quality = call.arg_i64(1);
+        let thumb_w = call.arg_i64(2);
+        let thumb_h = call.arg_i64(3);
+        match encode_jpeg_with_thumbnail_impl(id, quality, thumb_w, thumb_h) {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEstimateSkew")]
+    pub fn native_estimate_skew(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match estimate_skew_impl(id) {
+            Ok(angle) => {
+                call.ret_u64(0, angle.to_bits());
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeDimensionsFromBytes")]
+    pub fn native_dimensions_from_bytes(call: &mut ExternCallContext) -> ExternResult {
+        let data = call.arg_bytes(0);
+        match dimensions_from_bytes_impl(data) {
+            Ok((width, height)) => {
+                call.ret_i64(0, width as i64);
+                call.ret_i64(1, height as i64);
+                write_nil_error(call, 2);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                call.ret_i64(1, 0);
+                write_error_to(call, 2, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeFrameInfo")]
+    pub fn native_frame_info(call: &mut ExternCallContext) -> ExternResult {
+        let data = call.arg_bytes(0);
+        match frame_info_impl(data) {
+            Ok((count, total_ms)) => {
+                call.ret_i64(0, count as i64);
+                call.ret_i64(1, total_ms as i64);
+                write_nil_error(call, 2);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                call.ret_i64(1, 0);
+                write_error_to(call, 2, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeReduce")]
+    pub fn native_reduce(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let op = call.arg_i64(1);
+        match reduce_impl(id, op) {
+            Ok(new_id) => {
+                call.ret_u64(0, new_id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeDrawGrid")]
+    pub fn native_draw_grid(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let spacing = call.arg_i64(1);
+        let (r, g, b, a) = (call.arg_i64(2), call.arg_i64(3), call.arg_i64(4), call.arg_i64(5));
+        match draw_grid_impl(id, spacing, r, g, b, a) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeExtractFrame")]
+    pub fn native_extract_frame(call: &mut ExternCallContext) -> ExternResult {
+        let data = call.arg_bytes(0);
+        let index = call.arg_i64(1);
+        match extract_frame_impl(data, index) {
+            Ok(id) => {
+                call.ret_u64(0, id as u64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEncodeGIF")]
+    pub fn native_encode_gif(call: &mut ExternCallContext) -> ExternResult {
+        let delay_ms = call.arg_i64(1);
+        let result = ops::unpack_ids(call.arg_bytes(0)).and_then(|ids| encode_gif_impl(&ids, delay_ms));
+        match result {
+            Ok(b) => {
+                let r = call.alloc_bytes(&b);
+                call.ret_ref(0, r);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_nil(0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeEffectiveBitDepth")]
+    pub fn native_effective_bit_depth(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match effective_bit_depth_impl(id) {
+            Ok(bits) => {
+                call.ret_i64(0, bits as i64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeAverageColor")]
+    pub fn native_average_color(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match average_color_impl(id) {
+            Ok(rgba) => {
+                call.ret_u64(0, rgba);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeErode")]
+    pub fn native_erode(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let radius = call.arg_i64(1);
+        match erode_impl(id, radius) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeDilate")]
+    pub fn native_dilate(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let radius = call.arg_i64(1);
+        match dilate_impl(id, radius) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativePerceptualHash")]
+    pub fn native_perceptual_hash(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        match perceptual_hash_impl(id) {
+            Ok(hash) => {
+                call.ret_u64(0, hash);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_u64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeShrinkToFit")]
+    pub fn native_shrink_to_fit(call: &mut ExternCallContext) -> ExternResult {
+        match shrink_to_fit_impl() {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeApplyColormap")]
+    pub fn native_apply_colormap(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let colormap = call.arg_str(1);
+        match apply_colormap_impl(id, colormap) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeHashDistance")]
+    pub fn native_hash_distance(call: &mut ExternCallContext) -> ExternResult {
+        let hash_a = call.arg_u64(0);
+        let hash_b = call.arg_u64(1);
+        call.ret_i64(0, hash_distance_impl(hash_a, hash_b) as i64);
+        write_nil_error(call, 1);
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeCompare")]
+    pub fn native_compare(call: &mut ExternCallContext) -> ExternResult {
+        let id_a = call.arg_u64(0);
+        let id_b = call.arg_u64(1);
+        let tolerance = call.arg_i64(2);
+        match compare_impl(id_a, id_b, tolerance) {
+            Ok(count) => {
+                call.ret_i64(0, count as i64);
+                write_nil_error(call, 1);
+            }
+            Err(msg) => {
+                call.ret_i64(0, 0);
+                write_error_to(call, 1, &msg);
+            }
+        }
+        ExternResult::Ok
+    }
+
+    #[vo_fn("image", "nativeThresholdAlpha")]
+    pub fn native_threshold_alpha(call: &mut ExternCallContext) -> ExternResult {
+        let id = call.arg_u64(0);
+        let level = call.arg_i64(1);
+        match threshold_alpha_impl(id, level) {
+            Ok(()) => write_nil_error(call, 0),
+            Err(msg) => write_error_to(call, 0, &msg),
+        }
+        ExternResult::Ok
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::fs;
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        fn temp_file(name: &str) -> std::path::PathBuf {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("clock should be after unix epoch")
+                .as_nanos();
+            std::env::temp_dir().join(format!("vo_image_{name}_{nanos}.png"))
+        }
+
+        fn png_bytes(width: u32, height: u32, color: [u8; 4]) -> Vec<u8> {
+            let img = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(width, height, image::Rgba(color)));
+            let mut out = Cursor::new(Vec::new());
+            img.write_to(&mut out, ImageFormat::Png).expect("png encode should succeed");
+            out.into_inner()
+        }
+
+        fn pack_blobs(blobs: &[&[u8]]) -> Vec<u8> {
+            let mut out = (blobs.len() as u32).to_le_bytes().to_vec();
+            for blob in blobs {
+                out.extend_from_slice(&(blob.len() as u32).to_le_bytes());
+                out.extend_from_slice(blob);
+            }
+            out
+        }
+
+        // Cancellation reaches every op running at the time, so tests that run
+        // cancellable ops take this lock to keep a cancel from landing in
+        // another test.
+        static CANCEL_TESTS: Mutex<()> = Mutex::new(());
+
+        fn pixel_at(id: u32, x: u32, y: u32) -> [u8; 4] {
+            let map = IMAGES.lock().expect("image lock should not be poisoned");
+            let img = get_image(&map, id).expect("image should exist");
+            image::GenericImageView::get_pixel(img, x, y).0
+        }
+
+        fn gif_bytes(width: u32, height: u32, colors: &[[u8; 4]], delay_ms: u32) -> Vec<u8> {
+            use image::codecs::gif::GifEncoder;
+            use image::{Delay, Frame, Rgba, RgbaImage};
+
+            let mut out = Vec::new();
+            {
+                let mut encoder = GifEncoder::new(&mut out);
+                let frames = colors.iter().map(|c| {
+                    Frame::from_parts(
+                        RgbaImage::from_pixel(width, height, Rgba(*c)),
+                        0,
+                        0,
+                        Delay::from_numer_denom_ms(delay_ms, 1),
+                    )
+                });
+                encoder.encode_frames(frames).expect("gif encode should succeed");
+            }
+            out
+        }
+
+        #[test]
+        fn image_lifecycle_and_transform_paths() {
+            let id = new_rgba_impl(64, 32).expect("new_rgba should succeed");
+
+            let (w0, h0) = size_impl(id as u64).expect("size should succeed");
+            assert_eq!(w0, 64, "initial width should match creation width");
+            assert_eq!(h0, 32, "initial height should match creation height");
+
+            resize_impl(id as u64, 20, 10).expect("resize should succeed");
+            let (w1, h1) = size_impl(id as u64).expect("size after resize should succeed");
+            assert_eq!(w1, 20, "width should be updated by resize");
+            assert_eq!(h1, 10, "height should be updated by resize");
 
             thumbnail_impl(id as u64, 8, 8).expect("thumbnail should succeed");
             let (w2, h2) = size_impl(id as u64).expect("size after thumbnail should succeed");
             assert!(w2 <= 8, "thumbnail width should be bounded by requested max");
             assert!(h2 <= 8, "thumbnail height should be bounded by requested max");
 
-            let encoded = encode_png_impl(id as u64).expect("encode_png should succeed");
-            assert!(!encoded.is_empty(), "encoded png bytes should not be empty");
+            let encoded = encode_png_impl(id as u64).expect("encode_png should succeed");
+            assert!(!encoded.is_empty(), "encoded png bytes should not be empty");
+
+            let out_path = temp_file("save");
+            save_impl(id as u64, &out_path.to_string_lossy()).expect("save should succeed");
+            assert!(out_path.exists(), "saved output file should exist");
+
+            let reopened = open_impl(&out_path.to_string_lossy()).expect("open should succeed");
+            let (rw, rh) = size_impl(reopened as u64).expect("reopened image size should succeed");
+            assert!(rw > 0 && rh > 0, "reopened image dimensions must be positive");
+
+            close_impl(reopened as u64).expect("close reopened image should succeed");
+            close_impl(id as u64).expect("close original image should succeed");
+            fs::remove_file(&out_path).expect("cleanup saved file should succeed");
+        }
+
+        #[test]
+        fn thumbnail_exact_fills_target_box() {
+            let id = new_rgba_impl(100, 50).expect("new_rgba should succeed");
+            thumbnail_exact_impl(id as u64, 40, 40).expect("thumbnail_exact should succeed");
+            let (w, h) = size_impl(id as u64).expect("size should succeed");
+            assert_eq!((w, h), (40, 40), "thumbnail_exact should produce the exact box");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn invalid_image_id_paths_fail() {
+            let invalid = 9_999_999u64;
+            assert!(size_impl(invalid).is_err(), "size should fail for invalid id");
+            assert!(
+                encode_png_impl(invalid).is_err(),
+                "encode_png should fail for invalid id"
+            );
+            assert!(close_impl(invalid).is_err(), "close should fail for invalid id");
+        }
+
+        #[test]
+        fn compare_aa_tolerant_ignores_edge_antialiasing() {
+            let mut sharp = image::RgbaImage::from_pixel(20, 20, image::Rgba([255, 255, 255, 255]));
+            for y in 0..20 {
+                for x in 0..10 {
+                    sharp.put_pixel(x, y, image::Rgba([0, 0, 0, 255]));
+                }
+            }
+            let mut smooth = sharp.clone();
+            for y in 0..20 {
+                smooth.put_pixel(10, y, image::Rgba([128, 128, 128, 255]));
+            }
+            let mut changed = sharp.clone();
+            changed.put_pixel(15, 5, image::Rgba([255, 0, 0, 255]));
+
+            let a = insert_image(DynamicImage::ImageRgba8(sharp)).expect("insert sharp");
+            let b = insert_image(DynamicImage::ImageRgba8(smooth)).expect("insert smooth");
+            let c = insert_image(DynamicImage::ImageRgba8(changed)).expect("insert changed");
+            let aa = compare_aa_tolerant_impl(a as u64, b as u64, 0.1).expect("compare should succeed");
+            assert_eq!(aa, 0, "anti-aliased edge should not count as a difference");
+            let real = compare_aa_tolerant_impl(a as u64, c as u64, 0.1).expect("compare should succeed");
+            assert_eq!(real, 1, "a genuinely changed pixel should be counted");
+            for id in [a, b, c] {
+                close_impl(id as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn open_first_frame_returns_frame_zero() {
+            let colors = [
+                [255, 0, 0, 255],
+                [0, 255, 0, 255],
+                [0, 0, 255, 255],
+                [255, 255, 0, 255],
+                [0, 255, 255, 255],
+            ];
+            let data = gif_bytes(12, 8, &colors, 100);
+            let id = open_first_frame_impl(&data).expect("open_first_frame should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (12, 8));
+            let map = IMAGES.lock().expect("lock should succeed");
+            let px = get_image(&map, id).expect("image should exist").to_rgba8().get_pixel(0, 0).0;
+            drop(map);
+            assert!(px[0] > 200 && px[1] < 50 && px[2] < 50, "first frame should be red, got {px:?}");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn resize_adds_source_area_to_pixel_counter() {
+            let id = new_rgba_impl(30, 20).expect("new_rgba should succeed");
+            let before = get_pixels_processed_impl();
+            resize_impl(id as u64, 10, 10).expect("resize should succeed");
+            let after = get_pixels_processed_impl();
+            // Other tests may resize concurrently, so only a lower bound is exact.
+            assert!(after - before >= 600, "counter should grow by the 30x20 source area");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[cfg(feature = "jpeg-transform")]
+        #[test]
+        fn jpeg_lossless_rotate_round_trips_pixels() {
+            use image::codecs::jpeg::JpegEncoder;
+
+            let src = image::RgbImage::from_fn(64, 64, |x, y| image::Rgb([(x * 4) as u8, (y * 4) as u8, 128]));
+            let mut original = Vec::new();
+            JpegEncoder::new_with_quality(&mut original, 90)
+                .encode_image(&src)
+                .expect("jpeg encode should succeed");
+
+            let once = jpeg_lossless_rotate_impl(&original, 180).expect("first rotate should succeed");
+            let twice = jpeg_lossless_rotate_impl(&once, 180).expect("second rotate should succeed");
+            let before = image::load_from_memory(&original).expect("decode original").to_rgb8();
+            let after = image::load_from_memory(&twice).expect("decode rotated").to_rgb8();
+            assert_eq!(before, after, "two lossless 180 rotations should restore the pixels");
+        }
+
+        #[test]
+        fn jpeg_lossless_rotate_rejects_odd_angles() {
+            assert!(jpeg_lossless_rotate_impl(&[], 45).is_err(), "45 degrees is not lossless");
+        }
+
+        #[test]
+        fn default_jpeg_save_flattens_rgba() {
+            let rgba = image::RgbaImage::from_pixel(16, 16, image::Rgba([200, 40, 90, 128]));
+            let id = insert_image(DynamicImage::ImageRgba8(rgba)).expect("insert should succeed");
+            let jpeg = save_to_bytes_impl(id as u64, "jpg").expect("RGBA saves as JPEG without a configured quality");
+            let decoded = image::load_from_memory(&jpeg).expect("output should decode");
+            assert_eq!(decoded.color(), image::ColorType::Rgb8, "alpha is dropped");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn default_jpeg_quality_applies_to_generic_save() {
+            let noisy = image::RgbImage::from_fn(64, 64, |x, y| {
+                let n = (x * 7919 + y * 104_729) % 251;
+                image::Rgb([n as u8, (x * 4) as u8, (y * 4) as u8])
+            });
+            let id = insert_image(DynamicImage::ImageRgb8(noisy)).expect("insert should succeed");
+
+            let library_default = save_to_bytes_impl(id as u64, "jpg").expect("default save should succeed");
+            set_default_quality_impl("jpg", 50).expect("set_default_quality should succeed");
+            let lowered = save_to_bytes_impl(id as u64, ".jpeg").expect("quality-50 save should succeed");
+            clear_default_quality_impl("jpg").expect("clearing the default should succeed");
+            let cleared = save_to_bytes_impl(id as u64, "jpg").expect("default save should succeed");
+
+            assert!(
+                lowered.len() < library_default.len(),
+                "quality 50 ({} bytes) should be smaller than the library default ({} bytes)",
+                lowered.len(),
+                library_default.len()
+            );
+            assert!(set_default_quality_impl("jpg", 0).is_err(), "jpeg quality 0 is out of range");
+            assert_eq!(cleared, library_default, "clearing restores the library default");
+            assert!(set_default_quality_impl("gif", 50).is_err(), "gif has no quality knob");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn resize_pad_letterboxes_into_exact_box() {
+            let src = image::RgbaImage::from_pixel(100, 50, image::Rgba([255, 0, 0, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(src)).expect("insert should succeed");
+            resize_pad_impl(id as u64, 100, 100, 0, 0, 255, 255).expect("resize_pad should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (100, 100));
+            assert_eq!(pixel_at(id, 50, 10), [0, 0, 255, 255], "top band should be pad color");
+            assert_eq!(pixel_at(id, 50, 90), [0, 0, 255, 255], "bottom band should be pad color");
+            assert_eq!(pixel_at(id, 50, 50), [255, 0, 0, 255], "center should be the source");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn batch_thumbnail_processes_items_independently() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let wide = png_bytes(80, 40, [255, 0, 0, 255]);
+            let tall = png_bytes(20, 60, [0, 255, 0, 255]);
+            let packed = pack_blobs(&[&wide, b"not an image", &tall]);
+            let out = batch_thumbnail_from_bytes_impl(&packed, 16, 16).expect("batch should succeed");
+
+            let u32_at = |pos: usize| u32::from_le_bytes(out[pos..pos + 4].try_into().unwrap());
+            assert_eq!(u32_at(0), 3, "one result per input");
+            let mut pos = 4;
+            let mut results = Vec::new();
+            for _ in 0..3 {
+                let ok = out[pos] == 1;
+                let (w, h, len) = (u32_at(pos + 1), u32_at(pos + 5), u32_at(pos + 9) as usize);
+                results.push((ok, w, h, len));
+                pos += 13 + len;
+            }
+            assert_eq!(pos, out.len(), "packed output should be fully consumed");
+            assert_eq!(results[0], (true, 16, 8, 16 * 8 * 4));
+            assert_eq!(results[1], (false, 0, 0, 0), "garbage input should be flagged");
+            assert!(results[2].0 && results[2].2 == 16 && results[2].1 <= 6);
+            assert!(batch_thumbnail_from_bytes_impl(&[1, 0, 0, 0], 16, 16).is_err(), "truncated list");
+        }
+
+        #[test]
+        fn to_f32_bytes_normalizes_samples() {
+            let src = image::RgbaImage::from_pixel(3, 2, image::Rgba([255, 0, 51, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(src)).expect("insert should succeed");
+            let out = to_f32_bytes_impl(id as u64, 0.0).expect("to_f32_bytes should succeed");
+            assert_eq!(out.len(), 3 * 2 * 4 * 4, "four f32 per pixel");
+            let f = |i: usize| f32::from_le_bytes(out[i * 4..i * 4 + 4].try_into().unwrap());
+            assert_eq!(f(0), 1.0, "255 should map to 1.0");
+            assert_eq!(f(1), 0.0, "0 should map to 0.0");
+            assert!((f(2) - 0.2).abs() < 1e-6, "51 should map to 0.2");
+            assert!(to_f32_bytes_impl(id as u64, -1.0).is_err(), "negative scale is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn standardize_maps_extremes_to_unit_range() {
+            let mut src = image::RgbImage::new(2, 1);
+            src.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+            src.put_pixel(1, 0, image::Rgb([0, 0, 0]));
+            let id = insert_image(DynamicImage::ImageRgb8(src)).expect("insert should succeed");
+            let half = [0.5; 3];
+            let out = standardize_impl(id as u64, &half, &half).expect("standardize should succeed");
+            assert_eq!(out.len(), 2 * 3 * 4, "three f32 per pixel for three-entry params");
+            let f = |i: usize| f32::from_le_bytes(out[i * 4..i * 4 + 4].try_into().unwrap());
+            assert_eq!(f(0), 1.0, "255 should map to 1.0");
+            assert_eq!(f(3), -1.0, "0 should map to -1.0");
+            assert!(standardize_impl(id as u64, &half, &[0.5, 0.0, 0.5]).is_err(), "zero std is rejected");
+            assert!(standardize_impl(id as u64, &[0.5; 2], &[0.5; 2]).is_err(), "two channels is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn trim_alpha_bounds_opaque_content() {
+            let mut sprite = image::RgbaImage::new(40, 30);
+            for y in 8..20 {
+                for x in 5..15 {
+                    sprite.put_pixel(x, y, image::Rgba([10, 20, 30, 255]));
+                }
+            }
+            sprite.put_pixel(25, 12, image::Rgba([0, 0, 0, 1]));
+            let id = insert_image(DynamicImage::ImageRgba8(sprite)).expect("insert should succeed");
+            trim_alpha_impl(id as u64).expect("trim_alpha should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (21, 12));
+            assert_eq!(pixel_at(id, 0, 0), [10, 20, 30, 255], "top-left should be opaque content");
+
+            let opaque = insert_image(DynamicImage::new_rgb8(4, 4)).expect("insert should succeed");
+            let err = trim_alpha_impl(opaque as u64).expect_err("rgb image should be rejected");
+            assert_eq!(err, "image has no alpha channel");
+            close_impl(id as u64).expect("close should succeed");
+            close_impl(opaque as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn rgb565_packs_pure_red() {
+            let src = image::RgbaImage::from_pixel(2, 3, image::Rgba([255, 0, 0, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(src)).expect("insert should succeed");
+            let be = to_rgb565_bytes_impl(id as u64, true).expect("rgb565 should succeed");
+            let le = to_rgb565_bytes_impl(id as u64, false).expect("rgb565 should succeed");
+            assert_eq!(be.len(), 2 * 3 * 2, "two bytes per pixel");
+            assert_eq!(&be[..2], &[0xF8, 0x00], "big-endian red should be 0xF800");
+            assert_eq!(&le[..2], &[0x00, 0xF8], "little-endian red should be 0xF800");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn mono_bitmap_packs_half_black_half_white() {
+            let src = image::GrayImage::from_fn(16, 2, |x, _| image::Luma([if x < 8 { 0 } else { 255 }]));
+            let id = insert_image(DynamicImage::ImageLuma8(src)).expect("insert should succeed");
+            for dither in [false, true] {
+                let (bits, stride) = to_mono_bitmap_impl(id as u64, 128, dither).expect("mono should succeed");
+                assert_eq!(stride, 2, "16 pixels pack into two bytes per row");
+                assert_eq!(bits, vec![0xFF, 0x00, 0xFF, 0x00], "dark half should set bits (dither={dither})");
+            }
+            assert!(to_mono_bitmap_impl(id as u64, 300, false).is_err(), "threshold above 255 is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn fade_to_color_blends_toward_target() {
+            let src = image::RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(src)).expect("insert should succeed");
+            fade_to_color_impl(id as u64, 0, 0, 255, 255, 0.0).expect("amount 0 should succeed");
+            assert_eq!(pixel_at(id, 1, 1), [255, 0, 0, 255], "amount 0 is identity");
+            fade_to_color_impl(id as u64, 0, 0, 255, 255, 0.5).expect("amount 0.5 should succeed");
+            let [r, g, b, a] = pixel_at(id, 1, 1);
+            assert!((127..=128).contains(&r) && g == 0 && (127..=128).contains(&b) && a == 255, "should be purple");
+            assert!(fade_to_color_impl(id as u64, 0, 0, 255, 255, 1.5).is_err(), "amount above 1 is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn blurhash_encodes_dominant_color() {
+            let src = image::RgbaImage::from_pixel(40, 30, image::Rgba([200, 60, 40, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(src)).expect("insert should succeed");
+            let hash = blurhash_impl(id as u64, 4, 3).expect("blurhash should succeed");
+            assert_eq!(hash.len(), 6 + 2 * (4 * 3 - 1), "hash length follows the component count");
+
+            let decoded = blurhash::decode(&hash, 8, 8, 1.0).expect("hash should decode");
+            let center = &decoded[(4 * 8 + 4) * 4..(4 * 8 + 4) * 4 + 3];
+            for (got, want) in center.iter().zip([200u8, 60, 40]) {
+                assert!(got.abs_diff(want) <= 4, "decoded {center:?} should be near the source color");
+            }
+            assert!(blurhash_impl(id as u64, 0, 3).is_err(), "zero components is rejected");
+            assert!(blurhash_impl(id as u64, 4, 10).is_err(), "ten components is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn open_from_blurhash_decodes_known_hash() {
+            // 1x1-component hash whose DC term is pure red.
+            let id = open_from_blurhash_impl("00TI:j", 32, 16, 1.0).expect("known hash should decode");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (32, 16));
+            let [r, g, b, a] = pixel_at(id, 16, 8);
+            assert!(r > 240 && g < 16 && b < 16 && a == 255, "dominant color should be red");
+            assert!(open_from_blurhash_impl("not a hash", 32, 16, 1.0).is_err(), "malformed hash is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn resize_to_pixels_fits_budget() {
+            let id = new_rgba_impl(1000, 1000).expect("new_rgba should succeed");
+            resize_to_pixels_impl(id as u64, 250_000).expect("resize_to_pixels should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (500, 500));
+            resize_to_pixels_impl(id as u64, 1_000_000).expect("under-budget call should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (500, 500), "under budget is untouched");
+
+            let wide = new_rgba_impl(300, 100).expect("new_rgba should succeed");
+            resize_to_pixels_impl(wide as u64, 1000).expect("resize_to_pixels should succeed");
+            let (w, h) = size_impl(wide as u64).expect("size should succeed");
+            assert!(w as u64 * h as u64 <= 1000 && w > h, "budget respected with aspect kept: {w}x{h}");
+            assert!(resize_to_pixels_impl(wide as u64, 0).is_err(), "zero budget is rejected");
+            close_impl(id as u64).expect("close should succeed");
+            close_impl(wide as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn tint_keeps_alpha_and_sets_rgb() {
+            let icon = image::RgbaImage::from_fn(8, 8, |x, _| image::Rgba([128, 128, 128, (x * 32) as u8]));
+            let id = insert_image(DynamicImage::ImageRgba8(icon)).expect("insert should succeed");
+            tint_impl(id as u64, 255, 0, 0).expect("tint should succeed");
+            for x in 0..8 {
+                assert_eq!(pixel_at(id, x, 3), [255, 0, 0, (x * 32) as u8], "alpha kept, rgb uniform");
+            }
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[cfg(feature = "hdr")]
+        #[test]
+        fn exr_decodes_as_float_and_tone_maps_to_8_bit() {
+            let hdr = image::Rgb32FImage::from_fn(8, 4, |x, _| image::Rgb([x as f32 * 2.0, 0.5, 0.0]));
+            let mut exr = Cursor::new(Vec::new());
+            DynamicImage::ImageRgb32F(hdr)
+                .write_to(&mut exr, ImageFormat::OpenExr)
+                .expect("exr encode should succeed");
+
+            let id = open_from_bytes_impl(exr.get_ref()).expect("exr should decode");
+            {
+                let map = IMAGES.lock().expect("lock should succeed");
+                assert_eq!(get_image(&map, id).expect("image").color(), image::ColorType::Rgb32F);
+            }
+            tone_map_impl(id as u64, 1.0, 2.2).expect("tone_map should succeed");
+            {
+                let map = IMAGES.lock().expect("lock should succeed");
+                assert_eq!(get_image(&map, id).expect("image").color(), image::ColorType::Rgb8);
+            }
+            assert_eq!(pixel_at(id, 0, 0)[0], 0, "zero radiance stays black");
+            assert!(pixel_at(id, 7, 0)[0] > pixel_at(id, 1, 0)[0], "brighter input maps brighter");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn tone_map_rejects_bad_parameters() {
+            let id = new_rgba_impl(2, 2).expect("new_rgba should succeed");
+            assert!(tone_map_impl(id as u64, 0.0, 2.2).is_err(), "zero exposure is rejected");
+            assert!(tone_map_impl(id as u64, 1.0, f64::NAN).is_err(), "NaN gamma is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn access_info_tracks_last_access() {
+            let id = new_rgba_impl(4, 4).expect("new_rgba should succeed");
+            let (created, last) = access_info_impl(id as u64).expect("access_info should succeed");
+            assert_eq!(created, last, "a fresh handle was last touched at creation");
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            size_impl(id as u64).expect("size should succeed");
+            let (created_again, touched) = access_info_impl(id as u64).expect("access_info should succeed");
+            assert_eq!(created_again, created, "creation time is fixed");
+            assert!(touched > created, "reading the size should advance last access");
+            close_impl(id as u64).expect("close should succeed");
+            assert!(access_info_impl(id as u64).is_err(), "closed handles have no access info");
+        }
+
+        #[test]
+        fn encode_png16_keeps_sixteen_bits() {
+            let mask = image::ImageBuffer::<image::Luma<u16>, Vec<u16>>::from_fn(8, 8, |x, y| {
+                image::Luma([(x * 8000 + y) as u16])
+            });
+            let id = insert_image(DynamicImage::ImageLuma16(mask)).expect("insert should succeed");
+            let png = encode_png16_impl(id as u64, false).expect("16-bit encode should succeed");
+            let decoded = image::load_from_memory(&png).expect("png should decode");
+            assert_eq!(decoded.color(), image::ColorType::L16, "output should decode as 16-bit luma");
+            assert_eq!(decoded.as_luma16().expect("luma16").get_pixel(7, 1).0[0], 56_001);
+
+            let rgba = new_rgba_impl(4, 4).expect("new_rgba should succeed");
+            assert!(encode_png16_impl(rgba as u64, false).is_err(), "8-bit source errors without upconvert");
+            let widened = encode_png16_impl(rgba as u64, true).expect("upconvert should succeed");
+            let decoded = image::load_from_memory(&widened).expect("png should decode");
+            assert_eq!(decoded.color(), image::ColorType::Rgba16, "upconverted output is 16-bit");
+            close_impl(id as u64).expect("close should succeed");
+            close_impl(rgba as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn unique_colors_counts_up_to_limit() {
+            let two = image::RgbaImage::from_fn(10, 10, |x, _| {
+                image::Rgba(if x < 5 { [0, 0, 0, 255] } else { [255, 255, 255, 255] })
+            });
+            let gradient = image::RgbaImage::from_fn(256, 1, |x, _| image::Rgba([x as u8, 0, 0, 255]));
+            let two = insert_image(DynamicImage::ImageRgba8(two)).expect("insert should succeed");
+            let gradient = insert_image(DynamicImage::ImageRgba8(gradient)).expect("insert should succeed");
+            assert_eq!(unique_colors_impl(two as u64, 256).expect("count should succeed"), 2);
+            assert_eq!(unique_colors_impl(gradient as u64, 100).expect("count should succeed"), 101, "sentinel");
+            assert_eq!(unique_colors_impl(gradient as u64, 256).expect("count should succeed"), 256);
+            close_impl(two as u64).expect("close should succeed");
+            close_impl(gradient as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn selection_set_validates_and_clears() {
+            let id = new_rgba_impl(20, 10).expect("new_rgba should succeed");
+            selection_set_impl(id as u64, 5, 2, 10, 8).expect("in-bounds selection should succeed");
+            assert_eq!(SELECTIONS.lock().unwrap().get(&id).map(|s| s.region), Some((5, 2, 10, 8)));
+            let err = selection_set_impl(id as u64, 15, 0, 10, 5).expect_err("overhanging selection");
+            assert_eq!(err, "selection out of bounds");
+            selection_clear_impl(id as u64).expect("clear should succeed");
+            assert_eq!(SELECTIONS.lock().unwrap().get(&id), None, "selection should be gone");
+            assert!(selection_set_impl(9_999_999, 0, 0, 1, 1).is_err(), "invalid id is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn resize_drops_stale_selection() {
+            let id = new_rgba_impl(20, 10).expect("new_rgba should succeed");
+            selection_set_impl(id as u64, 0, 0, 10, 10).expect("selection should succeed");
+            resize_impl(id as u64, 40, 20).expect("resize should succeed");
+            grayscale_impl(id as u64).expect("grayscale should succeed");
+            assert_eq!(SELECTIONS.lock().unwrap().get(&id), None, "selection is dropped after resize");
+            let map = IMAGES.lock().unwrap();
+            assert_eq!(map[&id].color(), image::ColorType::La8, "whole image was converted");
+            drop(map);
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn selection_op_keeps_color_type() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let gray = image::ImageBuffer::from_fn(8, 8, |x, _| image::Luma([(x * 8000) as u16]));
+            let id = insert_image(DynamicImage::ImageLuma16(gray)).expect("insert should succeed");
+            selection_set_impl(id as u64, 0, 0, 4, 8).expect("selection should succeed");
+            blur_impl(id as u64, 1.0).expect("blur should succeed");
+            let map = IMAGES.lock().unwrap();
+            assert_eq!(map[&id].color(), image::ColorType::L16, "16-bit gray survives a selection blur");
+            drop(map);
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn undo_restores_snapshot() {
+            let id = new_rgba_impl(64, 32).expect("new_rgba should succeed");
+            assert_eq!(undo_impl(id as u64).expect_err("empty history"), "nothing to undo");
+            snapshot_impl(id as u64).expect("snapshot should succeed");
+            resize_impl(id as u64, 10, 10).expect("resize should succeed");
+            undo_impl(id as u64).expect("undo should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (64, 32), "original size restored");
+            assert!(undo_impl(id as u64).is_err(), "history is consumed by undo");
+
+            for _ in 0..15 {
+                snapshot_impl(id as u64).expect("snapshot should succeed");
+            }
+            let depth = HISTORY.lock().unwrap().get(&id).map(Vec::len);
+            assert_eq!(depth, Some(10), "history is bounded by the default depth");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn encode_preallocates_output() {
+            let id = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_fn(256, 256, |x, y| {
+                image::Rgba([x as u8, y as u8, 128, 255])
+            })))
+            .expect("insert should succeed");
+            let hint = {
+                let map = IMAGES.lock().unwrap();
+                ops::encode_capacity_hint(map.get(&id).unwrap(), ImageFormat::Png)
+            };
+            assert_eq!(hint, 256 * 256 * 4);
+
+            let encoded = encode_png_impl(id as u64).expect("encode_png should succeed");
+            assert_eq!(encoded.capacity(), hint, "output never outgrew the preallocated buffer");
+            let mut plain = Cursor::new(Vec::new());
+            IMAGES.lock().unwrap().get(&id).unwrap().write_to(&mut plain, ImageFormat::Png).unwrap();
+            assert_eq!(encoded, plain.into_inner(), "preallocation doesn't change the bytes");
+
+            let saved = save_to_bytes_impl(id as u64, "bmp").expect("bmp save should succeed");
+            assert!(saved.len() > hint, "bmp adds headers over the raw size");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn planar_round_trip_is_pixel_identical() {
+            let src = image::RgbaImage::from_fn(3, 2, |x, y| image::Rgba([x as u8 * 40, y as u8 * 90, 7, 200 + x as u8]));
+            let id = insert_image(DynamicImage::ImageRgba8(src.clone())).expect("insert should succeed");
+            let planar = to_planar_rgba_bytes_impl(id as u64).expect("to_planar should succeed");
+            assert_eq!(planar.len(), 3 * 2 * 4);
+            assert_eq!(&planar[..6], &[0, 40, 80, 0, 40, 80], "red plane comes first");
+            assert_eq!(&planar[18..], &[200, 201, 202, 200, 201, 202], "alpha plane comes last");
+
+            let rebuilt = new_from_planar_impl(3, 2, &planar).expect("new_from_planar should succeed");
+            let map = IMAGES.lock().unwrap();
+            assert_eq!(map.get(&rebuilt).unwrap().to_rgba8(), src);
+            drop(map);
+            assert!(new_from_planar_impl(3, 2, &planar[1..]).is_err(), "length must match dimensions");
+            close_impl(id as u64).expect("close should succeed");
+            close_impl(rebuilt as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn despeckle_removes_small_regions_only() {
+            let mut src = image::RgbaImage::from_pixel(20, 20, image::Rgba([255, 255, 255, 255]));
+            let black = image::Rgba([0, 0, 0, 255]);
+            for (x, y) in [(2, 2), (17, 3), (4, 15)] {
+                src.put_pixel(x, y, black);
+            }
+            for y in 8..14 {
+                for x in 8..14 {
+                    src.put_pixel(x, y, black);
+                }
+            }
+            let id = insert_image(DynamicImage::ImageRgba8(src)).expect("insert should succeed");
+            assert_eq!(despeckle_impl(id as u64, 4).expect("despeckle should succeed"), 3);
+            assert_eq!(pixel_at(id, 2, 2), [255, 255, 255, 255], "speck filled with surrounding white");
+            assert_eq!(pixel_at(id, 17, 3), [255, 255, 255, 255]);
+            assert_eq!(pixel_at(id, 10, 10), [0, 0, 0, 255], "large region survives");
+            assert!(despeckle_impl(id as u64, -1).is_err());
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn replace_color_masked_respects_mask() {
+            let id = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(4, 2, image::Rgba([250, 0, 0, 255]))))
+                .expect("insert should succeed");
+            let mask = image::GrayImage::from_fn(4, 2, |x, _| image::Luma([if x < 2 { 255 } else { 0 }]));
+            let mask_id = insert_image(DynamicImage::ImageLuma8(mask)).expect("insert should succeed");
+            replace_color_masked_impl(id as u64, [255, 0, 0, 255], [0, 255, 0, 255], 10.0, 0, mask_id as u64)
+                .expect("replace should succeed");
+            assert_eq!(pixel_at(id, 0, 1), [0, 255, 0, 255], "white mask allows replacement");
+            assert_eq!(pixel_at(id, 3, 0), [250, 0, 0, 255], "black mask blocks replacement");
+
+            let small = new_rgba_impl(2, 2).expect("new_rgba should succeed");
+            assert_eq!(
+                replace_color_masked_impl(id as u64, [255, 0, 0, 255], [0, 255, 0, 255], 10.0, 0, small as u64)
+                    .expect_err("mask size must match"),
+                "mask dimensions differ"
+            );
+            for handle in [id, mask_id, small] {
+                close_impl(handle as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn grayscale_weighted_uses_given_weights() {
+            let id = insert_image(DynamicImage::ImageRgb8(image::RgbImage::from_fn(4, 1, |x, _| {
+                image::Rgb([x as u8 * 60, 200, 30])
+            })))
+            .expect("insert should succeed");
+            grayscale_weighted_impl(id as u64, 1.0, 0.0, 0.0).expect("grayscale should succeed");
+            let map = IMAGES.lock().unwrap();
+            let gray = map.get(&id).unwrap().as_luma8().expect("opaque source yields Luma8").clone();
+            drop(map);
+            assert_eq!(gray.into_raw(), vec![0, 60, 120, 180], "luma equals the red channel");
+
+            assert!(grayscale_weighted_impl(id as u64, -1.0, 1.0, 1.0).is_err());
+            assert_eq!(
+                grayscale_weighted_impl(id as u64, 0.0, 0.0, 0.0).expect_err("all-zero weights"),
+                "weights must not all be zero"
+            );
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn contact_sheet_pdf_embeds_each_thumbnail() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let ids: Vec<u32> = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]]
+                .iter()
+                .map(|&c| insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(120, 80, image::Rgba(c)))).unwrap())
+                .collect();
+            let pdf = encode_contact_sheet_pdf_impl(&ids, 2, 64, 64).expect("contact sheet should succeed");
+            assert!(pdf.starts_with(b"%PDF"), "output has a PDF header");
+            let marker = b"/Subtype /Image";
+            let embedded = pdf.windows(marker.len()).filter(|w| *w == marker).count();
+            assert_eq!(embedded, 3, "one image XObject per input");
+            assert!(pdf.ends_with(b"%%EOF\n"));
+
+            assert!(encode_contact_sheet_pdf_impl(&[], 2, 64, 64).is_err(), "empty list is rejected");
+            let huge = encode_contact_sheet_pdf_impl(&ids, 2, u32::MAX as i64 - 4, 64).expect_err("page overflows");
+            assert_eq!(huge, "contact sheet too large");
+            let tall = encode_contact_sheet_pdf_impl(&ids, 1, 64, u32::MAX as i64 / 2).expect_err("page overflows");
+            assert_eq!(tall, "contact sheet too large");
+            for id in ids {
+                close_impl(id as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn alpha_coverage_counts_visible_pixels() {
+            let half = image::RgbaImage::from_fn(10, 10, |x, _| image::Rgba([9, 9, 9, if x < 5 { 255 } else { 0 }]));
+            let id = insert_image(DynamicImage::ImageRgba8(half)).expect("insert should succeed");
+            let coverage = alpha_coverage_impl(id as u64).expect("alpha_coverage should succeed");
+            assert!((coverage - 0.5).abs() < 1e-9, "half-opaque image: {coverage}");
+
+            let clear = new_rgba_impl(4, 4).expect("new_rgba should succeed");
+            assert_eq!(alpha_coverage_impl(clear as u64).unwrap(), 0.0);
+            let opaque = insert_image(DynamicImage::new_rgb8(4, 4)).expect("insert should succeed");
+            assert_eq!(alpha_coverage_impl(opaque as u64).unwrap(), 1.0);
+            for handle in [id, clear, opaque] {
+                close_impl(handle as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn gif_round_trip_preserves_delays() {
+            use image::codecs::gif::GifDecoder;
+            use image::AnimationDecoder;
+
+            let delays = |data: &[u8]| -> Vec<(u32, u32)> {
+                GifDecoder::new(Cursor::new(data))
+                    .expect("gif should decode")
+                    .into_frames()
+                    .map(|f| f.expect("frame should decode").delay().numer_denom_ms())
+                    .collect()
+            };
+            let source = gif_bytes(4, 4, &[[255, 0, 0, 255], [0, 0, 255, 255]], 70);
+            let handle = gif_decode_all_impl(&source).expect("decode should succeed");
+            gif_map_impl(handle as u64, "grayscale").expect("grayscale should succeed");
+            assert!(gif_map_impl(handle as u64, "sepia").is_err());
+            let encoded = gif_encode_handle_impl(handle as u64).expect("encode should succeed");
+            assert_eq!(delays(&encoded), delays(&source), "per-frame delays are preserved");
+
+            let first = ops::decode_first_frame(&encoded).expect("first frame should decode").to_rgba8();
+            let [r, g, b, _] = first.get_pixel(0, 0).0;
+            assert!(r == g && g == b, "frames were grayscaled: {:?}", (r, g, b));
+            gif_close_impl(handle as u64).expect("close should succeed");
+            assert!(gif_encode_handle_impl(handle as u64).is_err(), "closed handle is gone");
+        }
+
+        #[test]
+        fn gif_round_trip_preserves_loop_count() {
+            use image::codecs::gif::{GifEncoder, Repeat};
+            use image::{Delay, Frame, Rgba, RgbaImage};
+
+            let mut source = Vec::new();
+            {
+                let mut encoder = GifEncoder::new(&mut source);
+                encoder.set_repeat(Repeat::Finite(3)).expect("repeat should be set");
+                let frames = [[255, 0, 0, 255], [0, 0, 255, 255]].map(|c| {
+                    Frame::from_parts(RgbaImage::from_pixel(4, 4, Rgba(c)), 0, 0, Delay::from_numer_denom_ms(50, 1))
+                });
+                encoder.encode_frames(frames).expect("gif encode should succeed");
+            }
+            assert!(matches!(ops::gif_repeat(&source), Some(Repeat::Finite(3))));
+            let handle = gif_decode_all_impl(&source).expect("decode should succeed");
+            let encoded = gif_encode_handle_impl(handle as u64).expect("encode should succeed");
+            assert!(matches!(ops::gif_repeat(&encoded), Some(Repeat::Finite(3))), "loop count survives");
+            gif_close_impl(handle as u64).expect("close should succeed");
+
+            // A comment after the image that happens to hold the same bytes is not a loop block.
+            let mut plain = gif_bytes(4, 4, &[[0, 255, 0, 255]], 50);
+            let trailer = plain.pop();
+            assert_eq!(trailer, Some(0x3B));
+            plain.extend_from_slice(&[0x21, 0xFE, 15]);
+            plain.extend_from_slice(b"NETSCAPE2.0\x03\x01\x05\x00");
+            plain.extend_from_slice(&[0x00, 0x3B]);
+            assert!(ops::gif_repeat(&plain).is_none(), "comment text is ignored");
+        }
+
+        #[test]
+        fn ciede2000_matches_perceptually_close_colors() {
+            let yellow = image::Rgba([255, 255, 0, 255]);
+            let pale = image::Rgba([255, 255, 80, 255]);
+            let id = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(2, 2, pale)))
+                .expect("insert should succeed");
+            let mask = insert_image(DynamicImage::ImageLuma8(image::GrayImage::from_pixel(2, 2, image::Luma([255]))))
+                .expect("insert should succeed");
+            let from = yellow.0.map(i64::from);
+            for metric in [0, 1] {
+                replace_color_masked_impl(id as u64, from, [0, 0, 0, 255], 5.0, metric, mask as u64)
+                    .expect("replace should succeed");
+                assert_eq!(pixel_at(id, 0, 0), pale.0, "metric {metric} sees the colors as distinct");
+            }
+            replace_color_masked_impl(id as u64, from, [0, 0, 0, 255], 5.0, 2, mask as u64)
+                .expect("replace should succeed");
+            assert_eq!(pixel_at(id, 0, 0), [0, 0, 0, 255], "CIEDE2000 treats them as a match");
+            assert!(replace_color_masked_impl(id as u64, from, [0, 0, 0, 255], 5.0, 3, mask as u64).is_err());
+
+            // Reference pair from Sharma et al.'s CIEDE2000 test data.
+            let de = ops::ciede2000((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485));
+            assert!((de - 2.0425).abs() < 1e-4, "got {de}");
+            close_impl(id as u64).expect("close should succeed");
+            close_impl(mask as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn optimized_png_is_smaller_and_lossless() {
+            let colors = [[255, 0, 0, 255], [0, 128, 255, 255], [20, 20, 20, 0], [250, 250, 0, 128], [9, 200, 9, 255]];
+            let src = image::RgbaImage::from_fn(97, 61, |x, y| image::Rgba(colors[((x / 7 + y / 5) % 5) as usize]));
+            let id = insert_image(DynamicImage::ImageRgba8(src.clone())).expect("insert should succeed");
+            let default = encode_png_impl(id as u64).expect("encode_png should succeed");
+            let optimized = encode_png_optimized_impl(id as u64).expect("optimized encode should succeed");
+            assert!(optimized.len() < default.len(), "{} >= {}", optimized.len(), default.len());
+            let decoded = image::load_from_memory(&optimized).expect("optimized png should decode");
+            assert_eq!(decoded.to_rgba8(), src, "palette encode is lossless");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn swap_exchanges_contents_keeping_ids() {
+            let a = new_rgba_impl(10, 10).expect("new_rgba should succeed");
+            let b = new_rgba_impl(20, 20).expect("new_rgba should succeed");
+            swap_impl(a as u64, b as u64).expect("swap should succeed");
+            assert_eq!(size_impl(a as u64).unwrap(), (20, 20));
+            assert_eq!(size_impl(b as u64).unwrap(), (10, 10));
+            swap_impl(a as u64, a as u64).expect("self-swap is a no-op");
+            assert_eq!(size_impl(a as u64).unwrap(), (20, 20));
+            assert!(swap_impl(a as u64, u32::MAX as u64).is_err(), "both ids must be valid");
+            assert_eq!(size_impl(a as u64).unwrap(), (20, 20), "failed swap leaves images in place");
+
+            // History and EXIF orientation follow the content they describe.
+            snapshot_impl(b as u64).expect("snapshot should succeed");
+            ORIENTATIONS.lock().unwrap().insert(b, image::metadata::Orientation::Rotate90);
+            swap_impl(a as u64, b as u64).expect("swap should succeed");
+            assert!(ORIENTATIONS.lock().unwrap().get(&b).is_none());
+            assert_eq!(ORIENTATIONS.lock().unwrap().get(&a), Some(&image::metadata::Orientation::Rotate90));
+            assert!(undo_impl(b as u64).is_err(), "b has no history of its own");
+            undo_impl(a as u64).expect("a carries the snapshot");
+            assert_eq!(size_impl(a as u64).unwrap(), (10, 10));
+            close_impl(a as u64).expect("close should succeed");
+            close_impl(b as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn adaptive_decode_downscales_under_tight_limit() {
+            let src = DynamicImage::ImageRgb8(image::RgbImage::from_fn(1024, 768, |x, y| {
+                image::Rgb([(x / 4) as u8, (y / 3) as u8, 90])
+            }));
+            let jpeg = ops::encode_jpeg(&src, 80).expect("jpeg encode should succeed");
+
+            let (full, divisor) = open_from_bytes_adaptive_impl(&jpeg).expect("default limit decodes");
+            assert_eq!(divisor, 1);
+            assert_eq!(size_impl(full as u64).unwrap(), (1024, 768));
+
+            // Full size needs 2.25 MiB; half size fits in 1 MiB.
+            set_decode_limit_impl(1 << 20).expect("set limit should succeed");
+            let result = open_from_bytes_adaptive_impl(&jpeg);
+            set_decode_limit_impl(0).expect("reset limit should succeed");
+            let (scaled, divisor) = result.expect("tight limit should decode at reduced scale");
+            assert_eq!(divisor, 2);
+            assert_eq!(size_impl(scaled as u64).unwrap(), (512, 384));
+            assert!(set_decode_limit_impl(-1).is_err());
+            close_impl(full as u64).expect("close should succeed");
+            close_impl(scaled as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn clear_alpha_makes_image_opaque() {
+            let id = new_rgba_impl(8, 8).expect("new_rgba should succeed");
+            assert_eq!(alpha_coverage_impl(id as u64).unwrap(), 0.0);
+            clear_channel_impl(id as u64, 3, 255).expect("clear_channel should succeed");
+            assert_eq!(alpha_coverage_impl(id as u64).unwrap(), 1.0);
+            assert_eq!(pixel_at(id, 3, 3), [0, 0, 0, 255]);
+            assert!(clear_channel_impl(id as u64, 4, 0).is_err());
+            assert!(clear_channel_impl(id as u64, 0, 256).is_err());
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn crop_extracts_region() {
+            let id = new_rgba_impl(64, 32).expect("new_rgba should succeed");
+            crop_impl(id as u64, 5, 5, 10, 10).expect("crop should succeed");
+            assert_eq!(size_impl(id as u64).unwrap(), (10, 10));
+            assert_eq!(
+                crop_impl(id as u64, 5, 0, 6, 10).expect_err("region past the right edge"),
+                "crop region out of bounds"
+            );
+            assert_eq!(size_impl(id as u64).unwrap(), (10, 10), "failed crop leaves the image alone");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn emboss_lights_the_edge_facing_the_light() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let stripe = image::GrayImage::from_fn(30, 10, |x, _| image::Luma([if (10..20).contains(&x) { 255 } else { 0 }]));
+            let id = insert_image(DynamicImage::ImageLuma8(stripe)).expect("insert should succeed");
+            emboss_impl(id as u64, 6).expect("emboss should succeed");
+            assert!(pixel_at(id, 10, 5)[0] > 200, "west-facing edge is lit");
+            assert!(pixel_at(id, 19, 5)[0] < 50, "east-facing edge is shaded");
+            assert_eq!(pixel_at(id, 3, 5)[0], 128, "flat background is mid-gray");
+            assert_eq!(pixel_at(id, 15, 5)[0], 128, "flat foreground is mid-gray");
+            assert!(emboss_impl(id as u64, 8).is_err());
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn rotate_quarter_turn_swaps_dimensions() {
+            let id = new_rgba_impl(64, 32).expect("new_rgba should succeed");
+            rotate_impl(id as u64, 90).expect("rotate should succeed");
+            assert_eq!(size_impl(id as u64).unwrap(), (32, 64));
+            rotate_impl(id as u64, 180).expect("rotate should succeed");
+            assert_eq!(size_impl(id as u64).unwrap(), (32, 64));
+            assert!(rotate_impl(id as u64, 45).is_err());
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn ansi_preview_uses_half_block_rows() {
+            let id = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(40, 20, image::Rgba([200, 10, 10, 255]))))
+                .expect("insert should succeed");
+            // 10 columns of a 2:1 image is 5 pixel rows, packed two to a line.
+            let preview = to_ansi_preview_impl(id as u64, 10).expect("preview should succeed");
+            assert!(preview.contains("\x1b[38;2;"), "output carries ANSI color escapes");
+            assert_eq!(preview.lines().count(), 3);
+            assert_eq!(preview.lines().next().unwrap().matches('\u{2580}').count(), 10);
+            assert!(to_ansi_preview_impl(id as u64, 0).is_err());
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn zero_dimensions_are_rejected() {
+            let msg = "dimension must be non-zero";
+            assert_eq!(new_rgba_impl(0, 0).expect_err("0x0 image"), msg);
+            assert_eq!(new_rgba_impl(10, 0).expect_err("zero height"), msg);
+            let id = new_rgba_impl(16, 8).expect("new_rgba should succeed");
+            assert_eq!(resize_impl(id as u64, 0, 8).expect_err("resize to zero width"), msg);
+            assert_eq!(crop_impl(id as u64, 0, 0, 4, 0).expect_err("crop to zero height"), msg);
+            assert_eq!(thumbnail_impl(id as u64, 0, 0).expect_err("zero thumbnail"), msg);
+            assert_eq!(thumbnail_exact_impl(id as u64, 0, 4).expect_err("zero exact thumbnail"), msg);
+            assert_eq!(resize_pad_impl(id as u64, 4, 0, 0, 0, 0, 0).expect_err("zero pad target"), msg);
+            assert_eq!(size_impl(id as u64).unwrap(), (16, 8), "rejected ops leave the image untouched");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn flipping_twice_restores_original() {
+            let src = image::RgbaImage::from_fn(7, 5, |x, y| image::Rgba([x as u8 * 30, y as u8 * 50, 0, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(src)).expect("insert should succeed");
+            let original = encode_png_impl(id as u64).expect("encode_png should succeed");
+
+            flip_horizontal_impl(id as u64).expect("fliph should succeed");
+            assert_eq!(pixel_at(id, 0, 0), [180, 0, 0, 255], "columns are mirrored");
+            flip_horizontal_impl(id as u64).expect("fliph should succeed");
+            assert_eq!(encode_png_impl(id as u64).unwrap(), original);
+
+            flip_vertical_impl(id as u64).expect("flipv should succeed");
+            assert_eq!(pixel_at(id, 0, 0), [0, 200, 0, 255], "rows are mirrored");
+            flip_vertical_impl(id as u64).expect("flipv should succeed");
+            assert_eq!(encode_png_impl(id as u64).unwrap(), original);
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn sharpen_raises_laplacian_variance() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            fn laplacian_variance(id: u32) -> f64 {
+                let map = IMAGES.lock().unwrap();
+                let luma = map.get(&id).unwrap().to_luma8();
+                let (w, h) = luma.dimensions();
+                let at = |x: u32, y: u32| luma.get_pixel(x, y).0[0] as f64;
+                let mut values = Vec::new();
+                for y in 1..h - 1 {
+                    for x in 1..w - 1 {
+                        values.push(at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y));
+                    }
+                }
+                let mean = values.iter().sum::<f64>() / values.len() as f64;
+                values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / values.len() as f64
+            }
+
+            let step = image::RgbaImage::from_fn(32, 32, |x, y| {
+                let v = if (x / 8 + y / 8) % 2 == 0 { 40 } else { 220 };
+                image::Rgba([v, v, v, 255])
+            });
+            let soft = DynamicImage::ImageRgba8(step).blur(1.5);
+            let id = insert_image(soft).expect("insert should succeed");
+            let before = laplacian_variance(id);
+            sharpen_impl(id as u64, 0.0).expect("sharpen should succeed");
+            assert_eq!(laplacian_variance(id), before, "strength 0 is identity");
+            sharpen_impl(id as u64, 1.0).expect("sharpen should succeed");
+            let after = laplacian_variance(id);
+            assert!(after > before, "sharpening should raise variance: {before} -> {after}");
+            assert!(sharpen_impl(id as u64, 1.5).is_err());
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn rotate_angle_grows_canvas_and_fills_corners() {
+            let id = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(40, 20, image::Rgba([255, 0, 0, 255]))))
+                .expect("insert should succeed");
+            rotate_angle_impl(id as u64, 45.0, 0x0000ffff, 1).expect("rotate should succeed");
+            let (w, h) = size_impl(id as u64).unwrap();
+            assert!(w > 40 && h > 20, "canvas grows to fit: {w}x{h}");
+            assert_eq!(pixel_at(id, 0, 0), [0, 0, 255, 255], "exposed corner takes the fill color");
+            assert_eq!(pixel_at(id, w / 2, h / 2), [255, 0, 0, 255], "center keeps the source");
+
+            let square = new_rgba_impl(30, 10).expect("new_rgba should succeed");
+            rotate_angle_impl(square as u64, 90.0, 0, 1).expect("rotate should succeed");
+            assert_eq!(size_impl(square as u64).unwrap(), (10, 30), "right angles don't pick up float slop");
+            assert!(rotate_angle_impl(square as u64, f64::NAN, 0, 1).is_err());
+            assert!(rotate_angle_impl(square as u64, 10.0, 0, 3).is_err(), "unknown interpolation is rejected");
+            close_impl(id as u64).expect("close should succeed");
+            close_impl(square as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn rotate_angle_nearest_keeps_pixel_art_palette() {
+            let palette = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255], [255, 255, 0, 255]];
+            let art = image::RgbaImage::from_fn(6, 4, |x, y| image::Rgba(palette[((x / 2 + y) % 4) as usize]));
+            let id = insert_image(DynamicImage::ImageRgba8(art)).expect("insert should succeed");
+            rotate_angle_impl(id as u64, 90.0, 0, 0).expect("rotate should succeed");
+            assert_eq!(size_impl(id as u64).unwrap(), (4, 6));
+            for y in 0..6 {
+                for x in 0..4 {
+                    let px = pixel_at(id, x, y);
+                    assert!(palette.contains(&px), "no new colors at ({x}, {y}): {px:?}");
+                }
+            }
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn grayscale_still_encodes() {
+            let id = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(12, 6, image::Rgba([200, 40, 40, 255]))))
+                .expect("insert should succeed");
+            grayscale_impl(id as u64).expect("grayscale should succeed");
+            assert_eq!(size_impl(id as u64).unwrap(), (12, 6));
+            let png = encode_png_impl(id as u64).expect("encode_png should succeed");
+            let decoded = image::load_from_memory(&png).expect("grayscale png should decode");
+            assert_eq!(decoded.color(), image::ColorType::La8);
+
+            // With a selection only the selected region loses its color.
+            let partial = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(12, 6, image::Rgba([200, 40, 40, 255]))))
+                .expect("insert should succeed");
+            selection_set_impl(partial as u64, 0, 0, 6, 6).expect("selection should succeed");
+            grayscale_impl(partial as u64).expect("grayscale should succeed");
+            let [r, g, b, _] = pixel_at(partial, 2, 2);
+            assert!(r == g && g == b, "selected pixel is gray");
+            assert_eq!(pixel_at(partial, 9, 2), [200, 40, 40, 255], "unselected pixel keeps its color");
+            close_impl(id as u64).expect("close should succeed");
+            close_impl(partial as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn checker_compare_alternates_sources() {
+            let red = [255, 0, 0, 255];
+            let blue = [0, 0, 255, 255];
+            let a = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(16, 16, image::Rgba(red)))).unwrap();
+            let b = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(16, 16, image::Rgba(blue)))).unwrap();
+            let checker = checker_compare_impl(a as u64, b as u64, 4).expect("checker_compare should succeed");
+            assert_eq!(pixel_at(checker, 0, 0), red);
+            assert_eq!(pixel_at(checker, 4, 0), blue);
+            assert_eq!(pixel_at(checker, 0, 4), blue);
+            assert_eq!(pixel_at(checker, 5, 5), red);
+            assert_eq!(pixel_at(checker, 15, 3), blue);
+
+            let small = new_rgba_impl(8, 8).expect("new_rgba should succeed");
+            assert!(checker_compare_impl(a as u64, small as u64, 4).is_err(), "dimensions must match");
+            for id in [a, b, checker, small] {
+                close_impl(id as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn invert_turns_white_black_keeping_alpha() {
+            let id = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(4, 4, image::Rgba([255, 255, 255, 200]))))
+                .expect("insert should succeed");
+            invert_impl(id as u64).expect("invert should succeed");
+            assert_eq!(pixel_at(id, 1, 2), [0, 0, 0, 200]);
+            assert!(invert_impl(u32::MAX as u64).is_err());
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn quiet_zone_pads_with_background() {
+            let id = insert_image(DynamicImage::ImageLuma8(image::GrayImage::new(21, 21))).expect("insert should succeed");
+            add_quiet_zone_impl(id as u64, 4, 3, 255, 255, 255).expect("quiet zone should succeed");
+            assert_eq!(size_impl(id as u64).unwrap(), (21 + 24, 21 + 24));
+            assert_eq!(pixel_at(id, 0, 0), [255, 255, 255, 255]);
+            assert_eq!(pixel_at(id, 11, 30), [255, 255, 255, 255], "left border");
+            assert_eq!(pixel_at(id, 12, 12), [0, 0, 0, 255], "code starts after the border");
+            assert!(add_quiet_zone_impl(id as u64, 4, 3, 256, 0, 0).is_err());
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn resize_linear_skips_conversion_for_linear_tag() {
+            let halves = || {
+                DynamicImage::ImageRgba8(image::RgbaImage::from_fn(2, 2, |x, _| {
+                    let v = if x == 0 { 0 } else { 255 };
+                    image::Rgba([v, v, v, 255])
+                }))
+            };
+            let srgb = insert_image(halves()).expect("insert should succeed");
+            let linear = insert_image(halves()).expect("insert should succeed");
+            assert_eq!(get_colorspace_impl(srgb as u64).unwrap(), 0, "untagged images are sRGB");
+            set_colorspace_impl(linear as u64, 1).expect("set_colorspace should succeed");
+            assert_eq!(get_colorspace_impl(linear as u64).unwrap(), 1);
+            assert!(set_colorspace_impl(linear as u64, 3).is_err());
+
+            resize_linear_impl(srgb as u64, 1, 1).expect("resize_linear should succeed");
+            resize_linear_impl(linear as u64, 1, 1).expect("resize_linear should succeed");
+            // Averaging black and white in linear light lands near sRGB 188; data
+            // already tagged linear is averaged as stored.
+            let converted = pixel_at(srgb, 0, 0)[0];
+            let direct = pixel_at(linear, 0, 0)[0];
+            assert!((180..=195).contains(&converted), "sRGB source linearized: {converted}");
+            assert!((120..=135).contains(&direct), "linear source left as is: {direct}");
+
+            close_impl(srgb as u64).expect("close should succeed");
+            close_impl(linear as u64).expect("close should succeed");
+            assert!(COLORSPACES.lock().unwrap().get(&linear).is_none(), "tag is dropped on close");
+        }
+
+        #[test]
+        fn blur_softens_checkerboard() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let checker = image::RgbaImage::from_fn(16, 16, |x, y| {
+                let v = if (x + y) % 2 == 0 { 0 } else { 255 };
+                image::Rgba([v, v, v, 255])
+            });
+            let id = insert_image(DynamicImage::ImageRgba8(checker)).expect("insert should succeed");
+            assert!(blur_impl(id as u64, 0.0).is_err(), "zero sigma is rejected");
+            assert!(blur_impl(id as u64, f64::NAN).is_err(), "NaN sigma is rejected");
+            assert!(blur_impl(id as u64, f64::INFINITY).is_err(), "infinite sigma is rejected");
+            assert!(blur_impl(id as u64, 1e300).is_err(), "sigma beyond f32 is rejected");
+            blur_impl(id as u64, 1.0).expect("blur should succeed");
+            let a = pixel_at(id, 8, 8)[0] as i32;
+            let b = pixel_at(id, 9, 8)[0] as i32;
+            assert!((a - b).abs() < 64, "neighbours should converge: {a} vs {b}");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn blur_honors_selection() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let checker = image::RgbaImage::from_fn(16, 16, |x, y| {
+                let v = if (x + y) % 2 == 0 { 0 } else { 255 };
+                image::Rgba([v, v, v, 255])
+            });
+            let id = insert_image(DynamicImage::ImageRgba8(checker)).expect("insert should succeed");
+            selection_set_impl(id as u64, 0, 0, 8, 16).expect("selection should succeed");
+            blur_impl(id as u64, 1.0).expect("blur should succeed");
+            let inside = (pixel_at(id, 4, 4)[0] as i32 - pixel_at(id, 5, 4)[0] as i32).abs();
+            assert!(inside < 64, "selected half is blurred: {inside}");
+            for x in 8..16 {
+                let expected = if (x + 4) % 2 == 0 { 0 } else { 255 };
+                assert_eq!(pixel_at(id, x, 4)[0], expected, "unselected pixel at x={x} is untouched");
+            }
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn new_from_nv12_converts_gray_frame() {
+            let (w, h) = (4usize, 2usize);
+            let mut frame = vec![128u8; w * h];
+            frame.extend(std::iter::repeat(128u8).take(w * h / 2));
+            let id = new_from_nv12_impl(w as i64, h as i64, &frame).expect("NV12 frame should convert");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (4, 2));
+            let px = pixel_at(id, 3, 1);
+            assert_eq!(px[0], px[1], "neutral chroma gives gray");
+            assert_eq!(px[1], px[2], "neutral chroma gives gray");
+            assert!((120..=140).contains(&px[0]), "mid luma maps to mid gray: {px:?}");
+            assert_eq!(px[3], 255);
+            assert!(new_from_nv12_impl(4, 2, &frame[1..]).is_err(), "short buffer is rejected");
+            assert!(new_from_nv12_impl(3, 2, &frame).is_err(), "odd width is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn unsharpen_increases_edge_contrast() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let step = image::RgbaImage::from_fn(32, 8, |x, _| {
+                let v = if x < 16 { 60 } else { 190 };
+                image::Rgba([v, v, v, 255])
+            });
+            let soft = DynamicImage::ImageRgba8(step).blur(1.5);
+            let id = insert_image(soft).expect("insert should succeed");
+            let before = pixel_at(id, 16, 4)[0] as i32 - pixel_at(id, 15, 4)[0] as i32;
+            assert!(unsharpen_impl(id as u64, 0.0, 0).is_err(), "zero sigma is rejected");
+            assert!(unsharpen_impl(id as u64, f64::INFINITY, 0).is_err(), "infinite sigma is rejected");
+            assert!(unsharpen_impl(id as u64, 2.0, -1).is_err(), "negative threshold is rejected");
+            unsharpen_impl(id as u64, 2.0, 0).expect("unsharpen should succeed");
+            let after = pixel_at(id, 16, 4)[0] as i32 - pixel_at(id, 15, 4)[0] as i32;
+            assert!(after > before, "edge step should grow: {before} -> {after}");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn dog_responds_only_at_edge() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let step = image::RgbaImage::from_fn(64, 8, |x, _| {
+                let v = if x < 32 { 40 } else { 200 };
+                image::Rgba([v, v, v, 255])
+            });
+            let id = insert_image(DynamicImage::ImageRgba8(step)).expect("insert should succeed");
+            assert!(dog_impl(id as u64, 2.0, 1.0).is_err(), "sigma2 must exceed sigma1");
+            assert!(dog_impl(id as u64, 0.0, 1.0).is_err(), "sigma1 must be positive");
+            assert!(dog_impl(id as u64, 1.0, f64::INFINITY).is_err(), "infinite sigma2 is rejected");
+            assert!(dog_impl(id as u64, 1.0, 1e300).is_err(), "sigma2 beyond f32 is rejected");
+            dog_impl(id as u64, 1.0, 3.0).expect("dog should succeed");
+            let flat = pixel_at(id, 4, 4)[0] as i32;
+            assert!((flat - 128).abs() <= 1, "flat area is mid-gray: {flat}");
+            let dark_side = pixel_at(id, 31, 4)[0] as i32;
+            let bright_side = pixel_at(id, 32, 4)[0] as i32;
+            assert!(dark_side < 118, "dark side of the edge dips: {dark_side}");
+            assert!(bright_side > 138, "bright side of the edge peaks: {bright_side}");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn hue_rotate_moves_red_toward_green() {
+            let red = image::RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 200]));
+            let id = insert_image(DynamicImage::ImageRgba8(red)).expect("insert should succeed");
+            hue_rotate_impl(id as u64, 120).expect("hue_rotate should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (4, 4));
+            let px = pixel_at(id, 1, 1);
+            assert!(px[1] > px[0] && px[1] > px[2], "green should dominate: {px:?}");
+            assert_eq!(px[3], 200, "alpha is preserved");
+            assert!(hue_rotate_impl(id as u64, i64::from(i32::MAX) + 1).is_err());
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn find_color_rect_locates_panel() {
+            let mut canvas = image::RgbaImage::from_pixel(40, 30, image::Rgba([255, 255, 255, 255]));
+            for y in 5..25 {
+                for x in 8..30 {
+                    canvas.put_pixel(x, y, image::Rgba([30, 90, 200, 255]));
+                }
+            }
+            // A smaller patch of the same color elsewhere must not win.
+            canvas.put_pixel(36, 2, image::Rgba([30, 90, 200, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(canvas)).expect("insert should succeed");
+            let found = find_color_rect_impl(id as u64, 32, 88, 198, 4).expect("find should succeed");
+            assert_eq!(found, Some((8, 5, 22, 20)));
+            let missing = find_color_rect_impl(id as u64, 0, 0, 0, 0).expect("find should succeed");
+            assert_eq!(missing, None, "absent color reports no match");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn resize_preserve_keeps_aspect_ratio() {
+            let id = new_rgba_impl(64, 32).expect("new_rgba should succeed");
+            let got = resize_preserve_impl(id as u64, 20, 20).expect("resize_preserve should succeed");
+            assert_eq!(got, (20, 10));
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (20, 10));
+            assert!(resize_preserve_impl(id as u64, 0, 20).is_err(), "zero box is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn stencil_paints_shape_in_solid_color() {
+            let base = image::RgbaImage::from_pixel(32, 32, image::Rgba([255, 255, 255, 255]));
+            let base_id = insert_image(DynamicImage::ImageRgba8(base)).expect("insert should succeed");
+            let circle = image::RgbaImage::from_fn(16, 16, |x, y| {
+                let (dx, dy) = (x as i32 - 8, y as i32 - 8);
+                let a = if dx * dx + dy * dy <= 36 { 255 } else { 0 };
+                image::Rgba([0, 200, 0, a])
+            });
+            let shape_id = insert_image(DynamicImage::ImageRgba8(circle)).expect("insert should succeed");
+            stencil_impl(base_id as u64, shape_id as u64, 8, 8, 255, 0, 0).expect("stencil should succeed");
+            assert_eq!(pixel_at(base_id, 16, 16), [255, 0, 0, 255], "circle center is red, not the shape color");
+            assert_eq!(pixel_at(base_id, 9, 9), [255, 255, 255, 255], "outside the circle stays white");
+            assert_eq!(pixel_at(base_id, 0, 0), [255, 255, 255, 255], "outside the shape stays white");
+            close_impl(base_id as u64).expect("close should succeed");
+            close_impl(shape_id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn save_to_bytes_keeps_gray_jpeg_single_channel() {
+            for gray in [
+                DynamicImage::ImageLuma8(image::GrayImage::from_pixel(24, 12, image::Luma([90]))),
+                DynamicImage::ImageLumaA8(image::GrayAlphaImage::from_pixel(24, 12, image::LumaA([90, 128]))),
+            ] {
+                let id = insert_image(gray).expect("insert should succeed");
+                let jpeg = save_to_bytes_impl(id as u64, "jpg").expect("gray jpeg encode should succeed");
+                let decoded = image::load_from_memory(&jpeg).expect("jpeg should decode");
+                assert_eq!(decoded.color(), image::ColorType::L8, "gray source stays single-component");
+                assert_eq!((decoded.width(), decoded.height()), (24, 12));
+                close_impl(id as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn resize_with_filter_changes_output() {
+            let stripes = || {
+                DynamicImage::ImageRgba8(image::RgbaImage::from_fn(30, 30, |x, y| {
+                    let v = ((x * 37 + y * 11) % 256) as u8;
+                    image::Rgba([v, 255 - v, v / 2, 255])
+                }))
+            };
+            let nearest = insert_image(stripes()).expect("insert should succeed");
+            let lanczos = insert_image(stripes()).expect("insert should succeed");
+            resize_with_filter_impl(nearest as u64, 7, 7, 0).expect("nearest resize should succeed");
+            resize_with_filter_impl(lanczos as u64, 7, 7, 4).expect("lanczos resize should succeed");
+            let bytes = |id: u32| IMAGES.lock().unwrap().get(&id).unwrap().to_rgba8().into_raw();
+            assert_ne!(bytes(nearest), bytes(lanczos), "filters should differ");
+            assert_eq!(
+                resize_with_filter_impl(nearest as u64, 7, 7, 5).expect_err("unknown filter"),
+                "unknown resize filter: 5"
+            );
+            close_impl(nearest as u64).expect("close should succeed");
+            close_impl(lanczos as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn color_info_reports_layout() {
+            let id = new_rgba_impl(4, 4).expect("new_rgba should succeed");
+            assert_eq!(color_info_impl(id as u64).expect("color_info should succeed"), (4, 8, 1));
+            close_impl(id as u64).expect("close should succeed");
+            assert_eq!(ops::color_info(image::ColorType::L8), (1, 8, 0));
+            assert_eq!(ops::color_info(image::ColorType::Rgb16), (3, 16, 0));
+            assert_eq!(ops::color_info(image::ColorType::Rgba32F), (4, 32, 1));
+        }
+
+        #[test]
+        fn height_to_normal_flat_is_neutral() {
+            let flat = image::RgbaImage::from_pixel(8, 8, image::Rgba([100, 100, 100, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(flat)).expect("insert should succeed");
+            assert!(height_to_normal_impl(id as u64, -1.0).is_err(), "negative strength is rejected");
+            height_to_normal_impl(id as u64, 4.0).expect("height_to_normal should succeed");
+            for (x, y) in [(0, 0), (4, 4), (7, 7)] {
+                assert_eq!(pixel_at(id, x, y), [128, 128, 255, 255], "flat height at ({x}, {y})");
+            }
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn count_blobs_counts_separate_squares() {
+            let mut canvas = image::RgbaImage::from_pixel(40, 12, image::Rgba([0, 0, 0, 255]));
+            for left in [2u32, 15, 28] {
+                for y in 3..9 {
+                    for x in left..left + 6 {
+                        canvas.put_pixel(x, y, image::Rgba([255, 255, 255, 255]));
+                    }
+                }
+            }
+            // Two diagonal-touching pixels: one blob with 8-connectivity, two with 4.
+            canvas.put_pixel(36, 0, image::Rgba([255, 255, 255, 255]));
+            canvas.put_pixel(37, 1, image::Rgba([255, 255, 255, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(canvas)).expect("insert should succeed");
+            assert_eq!(count_blobs_impl(id as u64, 128, 8).expect("count should succeed"), 4);
+            assert_eq!(count_blobs_impl(id as u64, 128, 4).expect("count should succeed"), 5);
+            assert!(count_blobs_impl(id as u64, 128, 6).is_err(), "only 4 and 8 are accepted");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn save_to_bytes_jpeg_quality_controls_size() {
+            let noisy = image::RgbaImage::from_fn(64, 64, |x, y| {
+                let v = ((x * 7919 + y * 104_729) % 251) as u8;
+                image::Rgba([v, v.wrapping_mul(3), v.wrapping_add(90), 255])
+            });
+            let id = insert_image(DynamicImage::ImageRgba8(noisy)).expect("insert should succeed");
+            let low = save_to_bytes_jpeg_impl(id as u64, 10).expect("quality 10 should encode");
+            let high = save_to_bytes_jpeg_impl(id as u64, 90).expect("quality 90 should encode");
+            assert!(low.len() < high.len(), "quality 10 ({}) should be smaller than 90 ({})", low.len(), high.len());
+            assert!(save_to_bytes_jpeg_impl(id as u64, 0).is_err(), "quality 0 is rejected");
+            assert!(save_to_bytes_jpeg_impl(id as u64, 101).is_err(), "quality 101 is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn encode_png_with_level_best_not_larger_than_fast() {
+            let bands = image::RgbaImage::from_fn(128, 128, |x, y| {
+                let v = ((x / 8 + y / 16) % 4 * 60) as u8;
+                image::Rgba([v, 255 - v, 40, 255])
+            });
+            let id = insert_image(DynamicImage::ImageRgba8(bands)).expect("insert should succeed");
+            let fast = encode_png_with_level_impl(id as u64, 0).expect("fast encode should succeed");
+            let best = encode_png_with_level_impl(id as u64, 2).expect("best encode should succeed");
+            assert!(best.len() <= fast.len(), "best ({}) should not exceed fast ({})", best.len(), fast.len());
+            assert!(encode_png_with_level_impl(id as u64, 3).is_err(), "level 3 is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn histogram_region_counts_only_region() {
+            let halves = image::RgbaImage::from_fn(10, 4, |x, _| {
+                if x < 5 { image::Rgba([200, 0, 0, 255]) } else { image::Rgba([0, 0, 90, 255]) }
+            });
+            let id = insert_image(DynamicImage::ImageRgba8(halves)).expect("insert should succeed");
+            let count = |hist: &[u8], channel: usize, value: usize| {
+                let at = (channel * 256 + value) * 4;
+                u32::from_le_bytes(hist[at..at + 4].try_into().unwrap())
+            };
+            let full = histogram_region_impl(id as u64, 0, 0, 10, 4).expect("full histogram should succeed");
+            assert_eq!(full.len(), 4 * 256 * 4);
+            assert_eq!((count(&full, 0, 200), count(&full, 2, 90)), (20, 20));
+            // Overhanging rectangle is clipped to the right half.
+            let right = histogram_region_impl(id as u64, 5, 0, 50, 50).expect("region histogram should succeed");
+            assert_eq!(count(&right, 0, 200), 0, "left color is excluded");
+            assert_eq!(count(&right, 2, 90), 20);
+            assert_eq!(count(&right, 3, 255), 20);
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn detect_background_finds_white_margin() {
+            let mut canvas = image::RgbaImage::from_pixel(20, 16, image::Rgba([255, 255, 255, 255]));
+            for y in 3..13 {
+                for x in 4..16 {
+                    canvas.put_pixel(x, y, image::Rgba([20, 60, 120, 255]));
+                }
+            }
+            canvas.put_pixel(0, 0, image::Rgba([0, 0, 0, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(canvas)).expect("insert should succeed");
+            let (color, confidence) = detect_background_impl(id as u64).expect("detect should succeed");
+            assert_eq!(color.0, [255, 255, 255, 255]);
+            assert!(confidence > 0.95 && confidence < 1.0, "one stray border pixel: {confidence}");
+            assert_eq!(ops::pack_rgba(color), 0xffff_ffff);
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn overlay_blends_top_onto_base() {
+            let base = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+                20,
+                20,
+                image::Rgba([255, 255, 255, 255]),
+            )))
+            .expect("insert should succeed");
+            let top = insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+                6,
+                6,
+                image::Rgba([0, 0, 255, 255]),
+            )))
+            .expect("insert should succeed");
+            overlay_impl(base as u64, top as u64, 5, 5).expect("overlay should succeed");
+            assert_eq!(pixel_at(base, 7, 7), [0, 0, 255, 255], "covered pixel takes the top color");
+            assert_eq!(pixel_at(base, 2, 2), [255, 255, 255, 255], "uncovered pixel is unchanged");
+            overlay_impl(base as u64, top as u64, 17, -3).expect("overhanging overlay is clipped");
+            assert_eq!(pixel_at(base, 19, 0), [0, 0, 255, 255]);
+            assert!(overlay_impl(base as u64, 9_999_999, 0, 0).is_err(), "missing top is reported");
+            close_impl(base as u64).expect("close should succeed");
+            close_impl(top as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn clone_is_independent_of_original() {
+            let id = new_rgba_impl(40, 30).expect("new_rgba should succeed");
+            let copy = clone_impl(id as u64).expect("clone should succeed");
+            assert_ne!(copy, id, "clone gets a fresh id");
+            resize_impl(copy as u64, 10, 10).expect("resize should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (40, 30), "original is untouched");
+            assert_eq!(size_impl(copy as u64).expect("size should succeed"), (10, 10));
+            assert!(clone_impl(9_999_999).is_err(), "invalid id is rejected");
+            close_impl(id as u64).expect("close should succeed");
+            close_impl(copy as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn image_arithmetic_add_saturates() {
+            let gradient = || {
+                DynamicImage::ImageRgba8(image::RgbaImage::from_fn(4, 1, |x, _| {
+                    let v = [40, 100, 200, 250][x as usize];
+                    image::Rgba([v, v, v, 255])
+                }))
+            };
+            let a = insert_image(gradient()).expect("insert should succeed");
+            let b = insert_image(gradient()).expect("insert should succeed");
+            image_arithmetic_impl(a as u64, b as u64, 0).expect("add should succeed");
+            let row: Vec<u8> = (0..4).map(|x| pixel_at(a, x, 0)[0]).collect();
+            assert_eq!(row, vec![80, 200, 255, 255], "dark doubles, bright clips");
+            assert_eq!(pixel_at(a, 0, 0)[3], 255, "alpha is kept");
+            assert!(image_arithmetic_impl(a as u64, b as u64, 4).is_err(), "unknown op is rejected");
+            let small = new_rgba_impl(2, 2).expect("new_rgba should succeed");
+            assert!(image_arithmetic_impl(a as u64, small as u64, 0).is_err(), "sizes must match");
+            for id in [a, b, small] {
+                close_impl(id as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn fill_rect_paints_region() {
+            let id = new_rgba_impl(30, 30).expect("new_rgba should succeed");
+            fill_rect_impl(id as u64, 5, 5, 10, 10, 0x11_22_33_ff).expect("fill should succeed");
+            assert_eq!(pixel_at(id, 9, 9), [0x11, 0x22, 0x33, 0xff]);
+            assert_eq!(pixel_at(id, 15, 15), [0, 0, 0, 0], "outside the rectangle is untouched");
+            assert_eq!(
+                fill_rect_impl(id as u64, 25, 0, 10, 10, 0).expect_err("overhanging fill"),
+                "fill region out of bounds"
+            );
+
+            selection_set_impl(id as u64, 0, 0, 10, 30).expect("selection should succeed");
+            fill_rect_impl(id as u64, 5, 20, 10, 5, 0xff_00_00_ff).expect("fill should succeed");
+            assert_eq!(pixel_at(id, 7, 22), [0xff, 0, 0, 0xff], "inside the selection is filled");
+            assert_eq!(pixel_at(id, 12, 22), [0, 0, 0, 0], "outside the selection is left alone");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn selective_hue_rotate_only_touches_target_hue() {
+            let halves = image::RgbaImage::from_fn(8, 4, |x, _| {
+                if x < 4 { image::Rgba([220, 30, 30, 255]) } else { image::Rgba([30, 30, 220, 255]) }
+            });
+            let id = insert_image(DynamicImage::ImageRgba8(halves)).expect("insert should succeed");
+            selective_hue_rotate_impl(id as u64, 0, 30, 120).expect("selective rotate should succeed");
+            let red = pixel_at(id, 1, 1);
+            assert!(red[1] > red[0] && red[1] > red[2], "reds should turn green: {red:?}");
+            assert_eq!(pixel_at(id, 6, 1), [30, 30, 220, 255], "blue is unchanged");
+            assert!(selective_hue_rotate_impl(id as u64, 0, 181, 10).is_err(), "range above 180 is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn set_pixel_round_trips_through_get_pixel() {
+            let gray = DynamicImage::ImageLuma8(image::GrayImage::from_pixel(6, 4, image::Luma([50])));
+            let id = insert_image(gray).expect("insert should succeed");
+            assert_eq!(get_pixel_impl(id as u64, 0, 0).expect("get should succeed"), 0x32_32_32_ff, "gray widens to RGBA");
+            set_pixel_impl(id as u64, 5, 3, 0x12_34_56_78).expect("set should succeed");
+            assert_eq!(get_pixel_impl(id as u64, 5, 3).expect("get should succeed"), 0x12_34_56_78);
+            assert_eq!(get_pixel_impl(id as u64, 6, 0).expect_err("x past the edge"), "pixel out of bounds");
+            assert_eq!(set_pixel_impl(id as u64, -1, 0, 0).expect_err("negative x"), "pixel out of bounds");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn favicon_set_returns_png_per_size() {
+            let id = new_rgba_impl(64, 48).expect("new_rgba should succeed");
+            let packed = favicon_set_impl(id as u64, &[16, 32]).expect("favicon set should succeed");
+            let read = |pos: usize| u32::from_le_bytes(packed[pos..pos + 4].try_into().unwrap()) as usize;
+            assert_eq!(read(0), 2);
+            let mut pos = 4;
+            for expected in [16, 32] {
+                let (size, n) = (read(pos), read(pos + 4));
+                let png = image::load_from_memory_with_format(&packed[pos + 8..pos + 8 + n], ImageFormat::Png)
+                    .expect("entry should be a PNG");
+                assert_eq!(size, expected);
+                assert_eq!((png.width(), png.height()), (expected as u32, expected as u32));
+                pos += 8 + n;
+            }
+            assert_eq!(pos, packed.len());
+            assert!(favicon_set_impl(id as u64, &[0]).is_err(), "zero size is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn sample_interpolates_between_columns() {
+            let columns = image::RgbaImage::from_fn(2, 2, |x, _| {
+                if x == 0 { image::Rgba([0, 0, 0, 255]) } else { image::Rgba([255, 255, 255, 255]) }
+            });
+            let id = insert_image(DynamicImage::ImageRgba8(columns)).expect("insert should succeed");
+            let gray = sample_impl(id as u64, 0.5, 0.0, 1).expect("bilinear sample should succeed");
+            assert_eq!(gray, 0x80_80_80_ff, "halfway between black and white");
+            assert_eq!(sample_impl(id as u64, -3.0, 9.0, 1).expect("clamped sample"), 0x00_00_00_ff);
+            assert_eq!(sample_impl(id as u64, 1.0, 0.0, 2).expect("bicubic at a center"), 0xff_ff_ff_ff);
+            assert!(sample_impl(id as u64, 0.5, 0.0, 3).is_err(), "unknown interpolation is rejected");
+            for interpolation in 0..=2 {
+                let far = sample_impl(id as u64, 1e300, -1e300, interpolation).expect("huge coordinates clamp");
+                assert_eq!(far, 0xff_ff_ff_ff, "far right reads the white edge");
+            }
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn new_like_matches_source_size() {
+            let src = new_rgba_impl(37, 21).expect("new_rgba should succeed");
+            let like = new_like_impl(src as u64, 10, 20, 30, 255).expect("new_like should succeed");
+            assert_eq!(size_impl(like as u64).expect("size should succeed"), (37, 21));
+            assert_eq!(pixel_at(like, 36, 20), [10, 20, 30, 255]);
+            assert!(new_like_impl(src as u64, 256, 0, 0, 0).is_err(), "channel out of range is rejected");
+            close_impl(src as u64).expect("close should succeed");
+            close_impl(like as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn thumbnail_fit_cover_is_exact() {
+            let cover = new_rgba_impl(64, 32).expect("new_rgba should succeed");
+            assert_eq!(thumbnail_fit_impl(cover as u64, 20, 20, 1).expect("cover should succeed"), (20, 20));
+            assert_eq!(size_impl(cover as u64).expect("size should succeed"), (20, 20));
+            let contain = new_rgba_impl(64, 32).expect("new_rgba should succeed");
+            assert_eq!(thumbnail_fit_impl(contain as u64, 20, 20, 0).expect("contain should succeed"), (20, 10));
+            assert!(thumbnail_fit_impl(contain as u64, 20, 20, 2).is_err(), "unknown mode is rejected");
+            close_impl(cover as u64).expect("close should succeed");
+            close_impl(contain as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn cancel_current_stops_batch_thumbnail() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let item = png_bytes(256, 256, [10, 20, 30, 255]);
+            let items: Vec<&[u8]> = std::iter::repeat_n(item.as_slice(), 4000).collect();
+            let packed = pack_blobs(&items);
+            let started = std::time::Instant::now();
+            let worker = std::thread::spawn(move || batch_thumbnail_from_bytes_impl(&packed, 16, 16));
+            while !worker.is_finished() {
+                cancel_current_impl();
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            let result = worker.join().expect("batch thread should not panic");
+            assert_eq!(result.expect_err("batch should be cancelled"), ops::CANCELLED);
+            assert!(started.elapsed() < std::time::Duration::from_secs(5), "cancellation should be prompt");
+        }
+
+        #[test]
+        fn cancel_current_stops_blur_between_bands() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            let id = insert_image(DynamicImage::new_rgba8(512, 4096)).expect("insert should succeed");
+            let worker = std::thread::spawn(move || blur_impl(id as u64, 3.0));
+            while !worker.is_finished() {
+                cancel_current_impl();
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+            let result = worker.join().expect("blur thread should not panic");
+            assert_eq!(result.expect_err("blur should be cancelled"), ops::CANCELLED);
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn stray_cancel_does_not_abort_next_op() {
+            let _serial = CANCEL_TESTS.lock().unwrap_or_else(|e| e.into_inner());
+            cancel_current_impl();
+            let item = png_bytes(8, 8, [10, 20, 30, 255]);
+            let packed = pack_blobs(&[item.as_slice()]);
+            batch_thumbnail_from_bytes_impl(&packed, 4, 4).expect("batch after a stray cancel should run");
+            let id = insert_image(DynamicImage::new_rgba8(16, 16)).expect("insert should succeed");
+            blur_impl(id as u64, 1.0).expect("blur after a stray cancel should run");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn banded_blur_matches_whole_image_blur() {
+            let src = DynamicImage::ImageRgb8(image::RgbImage::from_fn(40, 300, |x, y| {
+                image::Rgb([(x * 6) as u8, (y % 256) as u8, ((x * y) % 251) as u8])
+            }));
+            let epoch = std::sync::atomic::AtomicU64::new(0);
+            let banded = ops::blur(&src, 2.5, &ops::CancelToken::new(&epoch)).expect("blur should succeed");
+            assert_eq!(banded.color(), src.color());
+            assert_eq!(banded.to_rgb8(), src.blur(2.5).to_rgb8());
+        }
+
+        #[test]
+        fn banded_blur_with_margin_past_image_height_matches() {
+            assert_eq!(ops::blur_margin(2e9), u32::MAX, "margin saturates instead of wrapping");
+            let src = DynamicImage::ImageRgb8(image::RgbImage::from_fn(12, 90, |x, y| {
+                image::Rgb([(x * 20) as u8, (y * 2) as u8, ((x + y) % 7 * 30) as u8])
+            }));
+            let epoch = std::sync::atomic::AtomicU64::new(0);
+            let banded = ops::blur(&src, 500.0, &ops::CancelToken::new(&epoch)).expect("blur should succeed");
+            assert_eq!(banded.to_rgb8(), src.blur(500.0).to_rgb8());
+        }
+
+        #[test]
+        fn auto_orient_applies_exif_rotation() {
+            let src = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 4, image::Rgb([200, 100, 50])));
+            let plain = ops::encode_jpeg(&src, 90).expect("jpeg encode should succeed");
+            // Minimal little-endian TIFF block with one Orientation (0x0112) entry = 6.
+            let mut exif = b"Exif\0\0II*\0".to_vec();
+            exif.extend_from_slice(&8u32.to_le_bytes());
+            exif.extend_from_slice(&1u16.to_le_bytes());
+            exif.extend_from_slice(&[0x12, 0x01, 3, 0]);
+            exif.extend_from_slice(&1u32.to_le_bytes());
+            exif.extend_from_slice(&[6, 0, 0, 0]);
+            exif.extend_from_slice(&0u32.to_le_bytes());
+            let mut jpeg = plain[..2].to_vec();
+            jpeg.extend_from_slice(&[0xFF, 0xE1]);
+            jpeg.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+            jpeg.extend_from_slice(&exif);
+            jpeg.extend_from_slice(&plain[2..]);
+
+            let id = open_from_bytes_impl(&jpeg).expect("jpeg with exif should open");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (8, 4), "decode keeps stored layout");
+            auto_orient_impl(id as u64).expect("auto_orient should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (4, 8), "orientation 6 swaps axes");
+            auto_orient_impl(id as u64).expect("second auto_orient should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (4, 8), "orientation is cleared");
+            close_impl(id as u64).expect("close should succeed");
+
+            let (id, divisor) = open_from_bytes_adaptive_impl(&jpeg).expect("adaptive open should succeed");
+            assert_eq!(divisor, 1);
+            auto_orient_impl(id as u64).expect("auto_orient should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (4, 8), "adaptive open records orientation");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn guess_format_reads_magic_bytes() {
+            let png = png_bytes(4, 4, [1, 2, 3, 255]);
+            assert_eq!(guess_format_impl(&png[..16]).expect("png header should be recognized"), 0);
+            let jpeg = ops::encode_jpeg(&DynamicImage::new_rgb8(4, 4), 80).expect("jpeg encode should succeed");
+            assert_eq!(guess_format_impl(&jpeg).expect("jpeg should be recognized"), 1);
+            assert!(guess_format_impl(b"definitely not an image").is_err(), "garbage is rejected");
+        }
+
+        #[test]
+        fn encode_jpeg_with_thumbnail_embeds_ifd1_thumbnail() {
+            let src = image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([(x * 4) as u8, (y * 5) as u8, 90]));
+            let id = insert_image(DynamicImage::ImageRgb8(src)).expect("insert should succeed");
+            let jpeg = encode_jpeg_with_thumbnail_impl(id as u64, 85, 16, 16).expect("encode should succeed");
+            let full = image::load_from_memory(&jpeg).expect("output should decode");
+            assert_eq!((full.width(), full.height()), (64, 48));
+
+            // Walk APP1 -> TIFF -> IFD0 -> IFD1 and pull the thumbnail out.
+            assert_eq!(&jpeg[2..4], &[0xFF, 0xE1]);
+            assert_eq!(&jpeg[6..12], b"Exif\0\0");
+            let tiff = &jpeg[12..];
+            let u16_at = |p: usize| u16::from_le_bytes([tiff[p], tiff[p + 1]]) as usize;
+            let u32_at = |p: usize| u32::from_le_bytes(tiff[p..p + 4].try_into().unwrap()) as usize;
+            let ifd0 = u32_at(4);
+            let ifd1 = u32_at(ifd0 + 2 + 12 * u16_at(ifd0));
+            let (mut offset, mut length) = (0, 0);
+            for i in 0..u16_at(ifd1) {
+                let e = ifd1 + 2 + 12 * i;
+                match u16_at(e) {
+                    0x0201 => offset = u32_at(e + 8),
+                    0x0202 => length = u32_at(e + 8),
+                    _ => {}
+                }
+            }
+            let thumb = image::load_from_memory_with_format(&tiff[offset..offset + length], ImageFormat::Jpeg)
+                .expect("embedded thumbnail should decode");
+            assert_eq!((thumb.width(), thumb.height()), (16, 12));
+            assert!(encode_jpeg_with_thumbnail_impl(id as u64, 0, 16, 16).is_err(), "quality 0 is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn estimate_skew_recovers_rotation_of_line_pattern() {
+            let lines = image::RgbaImage::from_fn(200, 200, |_, y| {
+                if y % 12 < 2 { image::Rgba([0, 0, 0, 255]) } else { image::Rgba([255, 255, 255, 255]) }
+            });
+            let white = image::Rgba([255, 255, 255, 255]);
+            let skewed = ops::rotate_angle(&DynamicImage::ImageRgba8(lines), 5.0, white, 1).expect("rotate should succeed");
+            let id = insert_image(skewed).expect("insert should succeed");
+            let angle = estimate_skew_impl(id as u64).expect("estimate should succeed");
+            assert!((angle - 5.0).abs() < 0.5, "estimated {angle}");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn dimensions_from_bytes_matches_full_decode() {
+            let png = png_bytes(37, 21, [10, 20, 30, 255]);
+            let dims = dimensions_from_bytes_impl(&png).expect("header read should succeed");
+            let id = open_from_bytes_impl(&png).expect("open should succeed");
+            assert_eq!(dims, size_impl(id as u64).expect("size should succeed"));
+            close_impl(id as u64).expect("close should succeed");
+            assert!(dimensions_from_bytes_impl(b"not an image").is_err());
+        }
+
+        #[test]
+        fn frame_info_counts_frames_and_sums_delays() {
+            let gif = gif_bytes(4, 4, &[[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]], 100);
+            assert_eq!(frame_info_impl(&gif).expect("gif should be readable"), (3, 300));
+            let png = png_bytes(4, 4, [1, 2, 3, 255]);
+            assert_eq!(frame_info_impl(&png).expect("png should be readable"), (1, 0));
+        }
+
+        #[test]
+        fn reduce_mean_yields_average_color() {
+            let gradient = image::RgbaImage::from_fn(5, 3, |x, _| image::Rgba([(x * 50) as u8, (200 - x * 50) as u8, 7, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(gradient)).expect("insert should succeed");
+            let mean = reduce_impl(id as u64, 0).expect("mean should succeed");
+            assert_eq!(size_impl(mean as u64).expect("size should succeed"), (1, 1));
+            assert_eq!(pixel_at(mean, 0, 0), [100, 100, 7, 255]);
+            let max = reduce_impl(id as u64, 2).expect("max should succeed");
+            assert_eq!(pixel_at(max, 0, 0), [200, 200, 7, 255]);
+            assert!(reduce_impl(id as u64, 9).is_err(), "unknown op is rejected");
+            for id in [id, mean, max] {
+                close_impl(id as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn draw_grid_places_lines_at_spacing() {
+            let id = insert_image(DynamicImage::new_rgba8(30, 30)).expect("insert should succeed");
+            draw_grid_impl(id as u64, 10, 255, 0, 0, 255).expect("grid should succeed");
+            let red = [255, 0, 0, 255];
+            for at in [0, 10, 20] {
+                assert_eq!(pixel_at(id, at, 5), red, "vertical line at x={at}");
+                assert_eq!(pixel_at(id, 5, at), red, "horizontal line at y={at}");
+            }
+            assert_eq!(pixel_at(id, 5, 5), [0, 0, 0, 0]);
+            assert_eq!(pixel_at(id, 29, 29), [0, 0, 0, 0]);
+            assert!(draw_grid_impl(id as u64, 0, 255, 0, 0, 255).is_err(), "zero spacing is rejected");
+            assert!(draw_grid_impl(id as u64, -1, 255, 0, 0, 255).is_err(), "negative spacing is rejected");
+            assert!(draw_grid_impl(id as u64, 10, 256, 0, 0, 255).is_err(), "channel above 255 is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn extract_frame_inserts_chosen_gif_frame() {
+            let gif = gif_bytes(6, 4, &[[255, 0, 0, 255], [0, 0, 255, 255]], 50);
+            let first = extract_frame_impl(&gif, 0).expect("frame 0 should extract");
+            assert_eq!(size_impl(first as u64).expect("size should succeed"), (6, 4));
+            let err = extract_frame_impl(&gif, 2).expect_err("index past the end should fail");
+            assert_eq!(err, "frame index out of range");
+            close_impl(first as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn encode_gif_round_trips_frame_count() {
+            let ids: Vec<u32> = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]]
+                .into_iter()
+                .map(|c| insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(8, 8, image::Rgba(c)))))
+                .collect::<Result<_, _>>()
+                .expect("insert should succeed");
+            let gif = encode_gif_impl(&ids, 40).expect("encode should succeed");
+            assert_eq!(frame_info_impl(&gif).expect("gif should decode"), (3, 120));
+
+            let odd = insert_image(DynamicImage::new_rgba8(4, 8)).expect("insert should succeed");
+            assert!(encode_gif_impl(&[ids[0], odd], 40).is_err(), "mismatched sizes are rejected");
+            for id in ids.into_iter().chain([odd]) {
+                close_impl(id as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn effective_bit_depth_sees_8_bit_data_in_16_bit_image() {
+            let widened = image::ImageBuffer::<image::Rgb<u16>, _>::from_fn(16, 16, |x, y| {
+                image::Rgb([(x * 16) as u16 * 257, (y * 7) as u16 * 257, 200 * 257])
+            });
+            let id = insert_image(DynamicImage::ImageRgb16(widened)).expect("insert should succeed");
+            assert_eq!(effective_bit_depth_impl(id as u64).expect("scan should succeed"), 8);
+            close_impl(id as u64).expect("close should succeed");
+
+            let full = image::ImageBuffer::from_pixel(2, 1, image::Luma([1234u16]));
+            let id = insert_image(DynamicImage::ImageLuma16(full)).expect("insert should succeed");
+            assert_eq!(effective_bit_depth_impl(id as u64).expect("scan should succeed"), 16);
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn average_color_of_half_black_half_white_is_mid_gray() {
+            let split = image::RgbaImage::from_fn(10, 4, |x, _| {
+                if x < 5 { image::Rgba([0, 0, 0, 255]) } else { image::Rgba([255, 255, 255, 255]) }
+            });
+            let id = insert_image(DynamicImage::ImageRgba8(split)).expect("insert should succeed");
+            let [r, g, b, a] = (average_color_impl(id as u64).expect("average should succeed") as u32).to_be_bytes();
+            for v in [r, g, b] {
+                assert!(v.abs_diff(128) <= 1, "channel {v} should be mid-gray");
+            }
+            assert_eq!(a, 255);
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn dilate_then_erode_restores_single_pixel() {
+            let mut dot = image::RgbaImage::from_pixel(7, 7, image::Rgba([0, 0, 0, 255]));
+            dot.put_pixel(3, 3, image::Rgba([255, 255, 255, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(dot)).expect("insert should succeed");
+            dilate_impl(id as u64, 1).expect("dilate should succeed");
+            for y in 0..7 {
+                for x in 0..7 {
+                    let inside = (2..=4).contains(&x) && (2..=4).contains(&y);
+                    assert_eq!(pixel_at(id, x, y)[0] == 255, inside, "pixel ({x}, {y}) after dilate");
+                }
+            }
+            erode_impl(id as u64, 1).expect("erode should succeed");
+            for y in 0..7 {
+                for x in 0..7 {
+                    assert_eq!(pixel_at(id, x, y)[0] == 255, (x, y) == (3, 3), "pixel ({x}, {y}) after erode");
+                }
+            }
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn morphology_matches_brute_force_window() {
+            let src = image::RgbaImage::from_fn(13, 9, |x, y| {
+                image::Rgba([((x * 37 + y * 11) % 256) as u8, ((x * y * 7) % 256) as u8, (x * 19) as u8, 255])
+            });
+            for radius in [0u32, 1, 2, 5, 20, u32::MAX] {
+                for dilate in [false, true] {
+                    let got = ops::morphology(&DynamicImage::ImageRgba8(src.clone()), radius, dilate).to_rgba8();
+                    let r = radius.min(20);
+                    for (x, y, px) in got.enumerate_pixels() {
+                        let mut want = src.get_pixel(x, y).0;
+                        for wy in y.saturating_sub(r)..=(y + r).min(8) {
+                            for wx in x.saturating_sub(r)..=(x + r).min(12) {
+                                for (c, v) in want.iter_mut().enumerate() {
+                                    let s = src.get_pixel(wx, wy).0[c];
+                                    *v = if dilate { (*v).max(s) } else { (*v).min(s) };
+                                }
+                            }
+                        }
+                        assert_eq!(px.0, want, "radius {radius}, dilate {dilate}, pixel ({x}, {y})");
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn perceptual_hash_is_stable_under_brightening() {
+            let scene = |lift: u32| {
+                image::RgbImage::from_fn(64, 64, |x, y| {
+                    let v = ((x * 3 + y * 2) % 160 + lift) as u8;
+                    image::Rgb([v, v / 2, 200 - v])
+                })
+            };
+            let original = insert_image(DynamicImage::ImageRgb8(scene(0))).expect("insert should succeed");
+            let brighter = insert_image(DynamicImage::ImageRgb8(scene(12))).expect("insert should succeed");
+            let a = perceptual_hash_impl(original as u64).expect("hash should succeed");
+            let b = perceptual_hash_impl(brighter as u64).expect("hash should succeed");
+            assert_ne!(a, 0, "a textured image sets some bits");
+            assert!((a ^ b).count_ones() <= 6, "hashes {a:016x} and {b:016x} should be close");
+            for id in [original, brighter] {
+                close_impl(id as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn shrink_to_fit_keeps_map_usable() {
+            let ids: Vec<u32> = (0..64)
+                .map(|_| insert_image(DynamicImage::new_rgba8(2, 2)))
+                .collect::<Result<_, _>>()
+                .expect("insert should succeed");
+            for &id in &ids {
+                close_impl(id as u64).expect("close should succeed");
+            }
+            shrink_to_fit_impl().expect("shrink should succeed");
+            {
+                let map = IMAGES.lock().expect("image lock should not be poisoned");
+                assert!(map.capacity() >= map.len());
+            }
+            let id = insert_image(DynamicImage::new_rgba8(3, 5)).expect("insert after shrink should succeed");
+            assert_eq!(size_impl(id as u64).expect("size should succeed"), (3, 5));
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn apply_colormap_maps_ends_of_gradient() {
+            let gradient = image::GrayImage::from_fn(256, 1, |x, _| image::Luma([x as u8]));
+            let cases: [(&str, [u8; 3], [u8; 3]); 3] = [
+                ("viridis", [68, 1, 84], [253, 231, 37]),
+                ("jet", [0, 0, 128], [128, 0, 0]),
+                ("grayscale", [0, 0, 0], [255, 255, 255]),
+            ];
+            for (name, start, end) in cases {
+                let id = insert_image(DynamicImage::ImageLuma8(gradient.clone())).expect("insert should succeed");
+                apply_colormap_impl(id as u64, name).expect("colormap should apply");
+                assert_eq!(pixel_at(id, 0, 0)[..3], start, "{name} start");
+                assert_eq!(pixel_at(id, 255, 0)[..3], end, "{name} end");
+                close_impl(id as u64).expect("close should succeed");
+            }
+            let id = insert_image(DynamicImage::ImageLuma8(gradient)).expect("insert should succeed");
+            assert!(apply_colormap_impl(id as u64, "rainbow").is_err(), "unknown names are rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+
+        #[test]
+        fn hash_distance_counts_differing_bits() {
+            let a = 0xF0F0_1234_0000_FFFFu64;
+            let b = a ^ ((1 << 63) | (1 << 20) | 1);
+            assert_eq!(hash_distance_impl(a, b), 3);
+            assert_eq!(hash_distance_impl(a, a), 0);
+        }
+
+        #[test]
+        fn compare_counts_pixels_beyond_tolerance() {
+            let src = image::RgbaImage::from_fn(6, 5, |x, y| image::Rgba([(x * 40) as u8, (y * 50) as u8, 77, 255]));
+            let id = insert_image(DynamicImage::ImageRgba8(src)).expect("insert should succeed");
+            assert_eq!(compare_impl(id as u64, id as u64, 0).expect("compare should succeed"), 0);
+            let inverted = clone_impl(id as u64).expect("clone should succeed");
+            invert_impl(inverted as u64).expect("invert should succeed");
+            assert_eq!(compare_impl(id as u64, inverted as u64, 0).expect("compare should succeed"), 30);
+
+            let small = insert_image(DynamicImage::new_rgba8(2, 2)).expect("insert should succeed");
+            let err = compare_impl(id as u64, small as u64, 0).expect_err("sizes differ");
+            assert_eq!(err, "image dimensions differ");
+            for id in [id, inverted, small] {
+                close_impl(id as u64).expect("close should succeed");
+            }
+        }
+
+        #[test]
+        fn threshold_alpha_splits_soft_edge_at_level() {
+            let soft = image::RgbaImage::from_fn(256, 1, |x, _| image::Rgba([10, 20, 30, x as u8]));
+            let id = insert_image(DynamicImage::ImageRgba8(soft)).expect("insert should succeed");
+            threshold_alpha_impl(id as u64, 100).expect("threshold should succeed");
+            for x in 0..256 {
+                let expected = if x >= 100 { 255 } else { 0 };
+                assert_eq!(pixel_at(id, x, 0), [10, 20, 30, expected], "pixel {x}");
+            }
+            assert!(threshold_alpha_impl(id as u64, 256).is_err(), "level above 255 is rejected");
+            close_impl(id as u64).expect("close should succeed");
+        }
+    }
+}
+
+#[cfg(feature = "native")]
+vo_ext::export_extensions!();
+
+// ── Standalone C-ABI WASM exports ────────────────────────────────────────────
+//
+// Uses ext_bridge v2 tagged binary protocol:
+//   Input:  one entry per param slot — Value=[u64 LE 8B], Bytes=[u32 len][bytes]
+//   Output: self-describing tagged stream — see tag constants below.
+
+#[cfg(feature = "wasm-standalone")]
+mod standalone {
+    use std::collections::HashMap;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use image::{DynamicImage, ImageFormat};
+    use lazy_static::lazy_static;
+
+    use crate::ops;
+
+    // v2 tagged protocol output tags (mirrors ext_bridge.rs constants)
+    const TAG_NIL_ERROR: u8 = 0xE0;
+    const TAG_ERROR_STR: u8 = 0xE1;
+    const TAG_VALUE:     u8 = 0xE2;
+    const TAG_BYTES:     u8 = 0xE3;
+    const TAG_NIL_REF:   u8 = 0xE4;
+
+    lazy_static! {
+        static ref IMAGES: Mutex<HashMap<u32, DynamicImage>> = Mutex::new(HashMap::new());
+        static ref DEFAULT_QUALITY: Mutex<HashMap<ImageFormat, u8>> = Mutex::new(HashMap::new());
+        static ref SELECTIONS: Mutex<HashMap<u32, ops::Selection>> = Mutex::new(HashMap::new());
+        static ref HISTORY: Mutex<HashMap<u32, Vec<DynamicImage>>> = Mutex::new(HashMap::new());
+        static ref ANIMATIONS: Mutex<HashMap<u32, ops::GifAnimation>> = Mutex::new(HashMap::new());
+        static ref COLORSPACES: Mutex<HashMap<u32, ops::ColorSpace>> = Mutex::new(HashMap::new());
+        static ref ORIENTATIONS: Mutex<HashMap<u32, image::metadata::Orientation>> = Mutex::new(HashMap::new());
+    }
+    static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+    static PIXELS_PROCESSED: AtomicU64 = AtomicU64::new(0);
+    static HISTORY_DEPTH: AtomicUsize = AtomicUsize::new(10);
+    // Allocation cap in bytes for adaptive decodes; 0 keeps the image crate default.
+    static DECODE_LIMIT: AtomicU64 = AtomicU64::new(0);
+    static CANCEL_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+    fn cancel_token() -> ops::CancelToken<'static> {
+        ops::CancelToken::new(&CANCEL_EPOCH)
+    }
+
+    // ── Memory management ─────────────────────────────────────────────────────
+
+    #[no_mangle]
+    pub extern "C" fn vo_alloc(size: u32) -> *mut u8 {
+        let mut buf = Vec::<u8>::with_capacity(size as usize);
+        let ptr = buf.as_mut_ptr();
+        std::mem::forget(buf);
+        ptr
+    }
+
+    #[no_mangle]
+    pub extern "C" fn vo_dealloc(ptr: *mut u8, size: u32) {
+        unsafe { drop(Vec::from_raw_parts(ptr, 0, size as usize)) };
+    }
+
+    // ── Input / output helpers ────────────────────────────────────────────────
+
+    fn alloc_output(data: &[u8], out_len: *mut u32) -> *mut u8 {
+        unsafe { *out_len = data.len() as u32; }
+        let ptr = vo_alloc(data.len() as u32);
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()); }
+        ptr
+    }
+
+    struct Input<'a> { buf: &'a [u8], pos: usize }
+    impl<'a> Input<'a> {
+        unsafe fn new(ptr: *const u8, len: u32) -> Self {
+            Self { buf: std::slice::from_raw_parts(ptr, len as usize), pos: 0 }
+        }
+        fn read_u64(&mut self) -> u64 {
+            if self.pos + 8 > self.buf.len() { return 0; }
+            let v = u64::from_le_bytes(self.buf[self.pos..self.pos + 8].try_into().unwrap());
+            self.pos += 8;
+            v
+        }
+        fn read_bytes(&mut self) -> &[u8] {
+            if self.pos + 4 > self.buf.len() { return &[]; }
+            let len = u32::from_le_bytes(self.buf[self.pos..self.pos + 4].try_into().unwrap()) as usize;
+            self.pos += 4;
+            if self.pos + len > self.buf.len() { return &[]; }
+            let data = &self.buf[self.pos..self.pos + len];
+            self.pos += len;
+            data
+        }
+        fn read_str(&mut self) -> &str {
+            std::str::from_utf8(self.read_bytes()).unwrap_or("")
+        }
+        // Reads an int that must fit a channel value, with native's error text.
+        fn read_u8(&mut self, name: &str) -> Result<u8, String> {
+            let v = self.read_u64() as i64;
+            u8::try_from(v).map_err(|_| format!("{name} out of range: {v}"))
+        }
+        // Same as `read_u8` for ints that must fit a u32, like native `to_u32`.
+        fn read_u32(&mut self, name: &str) -> Result<u32, String> {
+            let v = self.read_u64() as i64;
+            u32::try_from(v).map_err(|_| format!("{name} out of range: {v}"))
+        }
+        // A non-zero u32 size, like native `to_dim`.
+        fn read_dim(&mut self, name: &str) -> Result<u32, String> {
+            match self.read_u32(name)? {
+                0 => Err("dimension must be non-zero".to_string()),
+                d => Ok(d),
+            }
+        }
+    }
+
+    fn write_u64_ok(v: u64, out_len: *mut u32) -> *mut u8 {
+        // [TAG_VALUE][u64 LE][TAG_NIL_ERROR]
+        let mut buf = Vec::with_capacity(11);
+        buf.push(TAG_VALUE);
+        buf.extend_from_slice(&v.to_le_bytes());
+        buf.push(TAG_NIL_ERROR);
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_u64_err(msg: &str, out_len: *mut u32) -> *mut u8 {
+        // [TAG_VALUE][u64 LE 0][TAG_ERROR_STR][u16 len][msg]
+        let mb = msg.as_bytes();
+        let mlen = mb.len().min(0xFFFF) as u16;
+        let mut buf = Vec::with_capacity(11 + mlen as usize);
+        buf.push(TAG_VALUE);
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.push(TAG_ERROR_STR);
+        buf.extend_from_slice(&mlen.to_le_bytes());
+        buf.extend_from_slice(&mb[..mlen as usize]);
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_nil_error(out_len: *mut u32) -> *mut u8 {
+        alloc_output(&[TAG_NIL_ERROR], out_len)
+    }
+
+    fn write_error(msg: &str, out_len: *mut u32) -> *mut u8 {
+        let mb = msg.as_bytes();
+        let mlen = mb.len().min(0xFFFF) as u16;
+        let mut buf = Vec::with_capacity(3 + mlen as usize);
+        buf.push(TAG_ERROR_STR);
+        buf.extend_from_slice(&mlen.to_le_bytes());
+        buf.extend_from_slice(&mb[..mlen as usize]);
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_bytes_ok(data: &[u8], out_len: *mut u32) -> *mut u8 {
+        // [TAG_BYTES][u32 len][bytes][TAG_NIL_ERROR]
+        let mut buf = Vec::with_capacity(5 + data.len() + 1);
+        buf.push(TAG_BYTES);
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(data);
+        buf.push(TAG_NIL_ERROR);
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_bytes_err(msg: &str, out_len: *mut u32) -> *mut u8 {
+        // [TAG_NIL_REF][TAG_ERROR_STR][u16 len][msg]
+        let mb = msg.as_bytes();
+        let mlen = mb.len().min(0xFFFF) as u16;
+        let mut buf = Vec::with_capacity(4 + mlen as usize);
+        buf.push(TAG_NIL_REF);
+        buf.push(TAG_ERROR_STR);
+        buf.extend_from_slice(&mlen.to_le_bytes());
+        buf.extend_from_slice(&mb[..mlen as usize]);
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_two_ints_ok(a: i64, b: i64, out_len: *mut u32) -> *mut u8 {
+        // [TAG_VALUE][u64 LE a][TAG_VALUE][u64 LE b][TAG_NIL_ERROR]
+        let mut buf = Vec::with_capacity(19);
+        buf.push(TAG_VALUE); buf.extend_from_slice(&(a as u64).to_le_bytes());
+        buf.push(TAG_VALUE); buf.extend_from_slice(&(b as u64).to_le_bytes());
+        buf.push(TAG_NIL_ERROR);
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_two_ints_err(msg: &str, out_len: *mut u32) -> *mut u8 {
+        // [TAG_VALUE][0][TAG_VALUE][0][TAG_ERROR_STR][u16 len][msg]
+        let mb = msg.as_bytes();
+        let mlen = mb.len().min(0xFFFF) as u16;
+        let mut buf = Vec::with_capacity(19 + mlen as usize);
+        buf.push(TAG_VALUE); buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.push(TAG_VALUE); buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.push(TAG_ERROR_STR);
+        buf.extend_from_slice(&mlen.to_le_bytes());
+        buf.extend_from_slice(&mb[..mlen as usize]);
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_u64(v: u64, out_len: *mut u32) -> *mut u8 {
+        // [TAG_VALUE][u64 LE]
+        let mut buf = Vec::with_capacity(9);
+        buf.push(TAG_VALUE);
+        buf.extend_from_slice(&v.to_le_bytes());
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_empty(out_len: *mut u32) -> *mut u8 {
+        alloc_output(&[], out_len)
+    }
+
+    fn read_rgba(input: &mut Input) -> Result<image::Rgba<u8>, String> {
+        let mut c = [0u8; 4];
+        for (v, name) in c.iter_mut().zip(["r", "g", "b", "a"]) {
+            *v = input.read_u8(name)?;
+        }
+        Ok(image::Rgba(c))
+    }
+
+    fn read_rgb(input: &mut Input) -> Result<[u8; 3], String> {
+        Ok([input.read_u8("r")?, input.read_u8("g")?, input.read_u8("b")?])
+    }
+
+    fn write_bytes_int_ok(data: &[u8], v: i64, out_len: *mut u32) -> *mut u8 {
+        // [TAG_BYTES][u32 len][bytes][TAG_VALUE][u64 LE v][TAG_NIL_ERROR]
+        let mut buf = Vec::with_capacity(5 + data.len() + 10);
+        buf.push(TAG_BYTES);
+        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buf.extend_from_slice(data);
+        buf.push(TAG_VALUE);
+        buf.extend_from_slice(&(v as u64).to_le_bytes());
+        buf.push(TAG_NIL_ERROR);
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_bytes_int_err(msg: &str, out_len: *mut u32) -> *mut u8 {
+        // [TAG_NIL_REF][TAG_VALUE][0][TAG_ERROR_STR][u16 len][msg]
+        let mb = msg.as_bytes();
+        let mlen = mb.len().min(0xFFFF) as u16;
+        let mut buf = Vec::with_capacity(13 + mlen as usize);
+        buf.push(TAG_NIL_REF);
+        buf.push(TAG_VALUE); buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.push(TAG_ERROR_STR);
+        buf.extend_from_slice(&mlen.to_le_bytes());
+        buf.extend_from_slice(&mb[..mlen as usize]);
+        alloc_output(&buf, out_len)
+    }
+
+    // Active selection for `id`, if any. Callers hold IMAGES. A selection set
+    // before `img` changed size no longer describes it and is dropped.
+    fn selection_of(id: u32, img: &DynamicImage) -> Option<(u32, u32, u32, u32)> {
+        let mut sel = SELECTIONS.lock().ok()?;
+        match sel.get(&id) {
+            Some(s) if s.size == (img.width(), img.height()) => Some(s.region),
+            Some(_) => {
+                sel.remove(&id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    // Callers hold IMAGES.
+    fn colorspace_of(id: u32) -> ops::ColorSpace {
+        COLORSPACES
+            .lock()
+            .ok()
+            .and_then(|spaces| spaces.get(&id).copied())
+            .unwrap_or(ops::ColorSpace::Srgb)
+    }
+
+    fn write_ints_ok(values: &[i64], out_len: *mut u32) -> *mut u8 {
+        // ([TAG_VALUE][u64 LE])* [TAG_NIL_ERROR]
+        let mut buf = Vec::with_capacity(values.len() * 9 + 1);
+        for v in values {
+            buf.push(TAG_VALUE);
+            buf.extend_from_slice(&(*v as u64).to_le_bytes());
+        }
+        buf.push(TAG_NIL_ERROR);
+        alloc_output(&buf, out_len)
+    }
+
+    fn write_ints_err(count: usize, msg: &str, out_len: *mut u32) -> *mut u8 {
+        // ([TAG_VALUE][0])* [TAG_ERROR_STR][u16 len][msg]
+        let mb = msg.as_bytes();
+        let mlen = mb.len().min(0xFFFF) as u16;
+        let mut buf = Vec::with_capacity(count * 9 + 3 + mlen as usize);
+        for _ in 0..count {
+            buf.push(TAG_VALUE);
+            buf.extend_from_slice(&0u64.to_le_bytes());
+        }
+        buf.push(TAG_ERROR_STR);
+        buf.extend_from_slice(&mlen.to_le_bytes());
+        buf.extend_from_slice(&mb[..mlen as usize]);
+        alloc_output(&buf, out_len)
+    }
+
+    // ── Image operations ──────────────────────────────────────────────────────
+
+    fn record_pixels(img: &DynamicImage) {
+        let area = img.width() as u64 * img.height() as u64;
+        PIXELS_PROCESSED.fetch_add(area, Ordering::Relaxed);
+    }
+
+    fn insert_image(img: DynamicImage) -> Result<u32, String> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        IMAGES.lock().map_err(|_| "image lock poisoned".to_string())?.insert(id, img);
+        Ok(id)
+    }
+
+    fn format_from_ext(ext: &str) -> Result<ImageFormat, String> {
+        match ext.to_lowercase().trim_start_matches('.') {
+            "png"  => Ok(ImageFormat::Png),
+            "jpg" | "jpeg" => Ok(ImageFormat::Jpeg),
+            "gif"  => Ok(ImageFormat::Gif),
+            "bmp"  => Ok(ImageFormat::Bmp),
+            "webp" => Ok(ImageFormat::WebP),
+            other  => Err(format!("unsupported image format: {}", other)),
+        }
+    }
+
+    // ── WASM exports ──────────────────────────────────────────────────────────
+
+    // Input: [u32 len][data bytes]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeOpenFromBytes(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let data = input.read_bytes();
+        match image::load_from_memory(data) {
+            Ok(img) => match insert_image(ops::normalize_hdr(img, image::guess_format(data).ok())) {
+                Ok(id) => {
+                    if let (Some(orientation), Ok(mut orientations)) = (ops::read_orientation(data), ORIENTATIONS.lock()) {
+                        orientations.insert(id, orientation);
+                    }
+                    write_u64_ok(id as u64, out_len)
+                }
+                Err(e) => write_u64_err(&e, out_len),
+            },
+            Err(e) => write_u64_err(&e.to_string(), out_len),
+        }
+    }
+
+    // Input: [u64 LE w][u64 LE h]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeNewRGBA(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let w = input.read_u64() as u32;
+        let h = input.read_u64() as u32;
+        if w == 0 || h == 0 {
+            return write_u64_err("dimension must be non-zero", out_len);
+        }
+        match insert_image(DynamicImage::new_rgba8(w, h)) {
+            Ok(id) => write_u64_ok(id as u64, out_len),
+            Err(e) => write_u64_err(&e, out_len),
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE w][u64 LE h]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeResize(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let w  = input.read_u64() as u32;
+        let h  = input.read_u64() as u32;
+        if w == 0 || h == 0 {
+            return write_error("dimension must be non-zero", out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    *img = img.resize_exact(w, h, image::imageops::FilterType::Lanczos3);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE w][u64 LE h]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeThumbnail(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let w  = input.read_u64() as u32;
+        let h  = input.read_u64() as u32;
+        if w == 0 || h == 0 {
+            return write_error("dimension must be non-zero", out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    *img = img.thumbnail(w, h);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE w][u64 LE h]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeThumbnailExact(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let w  = input.read_u64() as u32;
+        let h  = input.read_u64() as u32;
+        if w == 0 || h == 0 {
+            return write_error("dimension must be non-zero", out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::thumbnail_exact(img, w, h) {
+                    Ok(thumb) => {
+                        record_pixels(img);
+                        *img = thumb;
+                        write_nil_error(out_len)
+                    }
+                    Err(e) => write_error(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u32 LE len][ext bytes]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeSaveToBytes(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id  = input.read_u64() as u32;
+        let ext = input.read_str().to_string();
+        let fmt = match format_from_ext(&ext) {
+            Ok(f)  => f,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        let quality = match DEFAULT_QUALITY.lock() {
+            Ok(q)  => q.get(&fmt).copied(),
+            Err(_) => return write_bytes_err("quality lock poisoned", out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    let encoded = match quality {
+                        Some(q) => ops::encode_with_quality(img, fmt, q),
+                        None => ops::encode_default(img, fmt),
+                    };
+                    match encoded {
+                        Ok(b)  => write_bytes_ok(&b, out_len),
+                        Err(e) => write_bytes_err(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeEncodePNG(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    let mut out = Cursor::new(Vec::with_capacity(ops::encode_capacity_hint(img, ImageFormat::Png)));
+                    match img.write_to(&mut out, ImageFormat::Png) {
+                        Ok(())  => write_bytes_ok(&out.into_inner(), out_len),
+                        Err(e) => write_bytes_err(&e.to_string(), out_len),
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → (int, int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeSize(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_two_ints_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_two_ints_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_two_ints_ok(img.width() as i64, img.height() as i64, out_len),
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeClose(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.remove(&id) {
+                None    => write_error(&format!("invalid image id {}", id), out_len),
+                Some(_) => {
+                    if let Ok(mut sel) = SELECTIONS.lock() {
+                        sel.remove(&id);
+                    }
+                    if let Ok(mut history) = HISTORY.lock() {
+                        history.remove(&id);
+                    }
+                    if let Ok(mut spaces) = COLORSPACES.lock() {
+                        spaces.remove(&id);
+                    }
+                    if let Ok(mut orientations) = ORIENTATIONS.lock() {
+                        orientations.remove(&id);
+                    }
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id_a][u64 LE id_b][u64 LE threshold_bits]  → (int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeCompareAATolerant(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id_a = input.read_u64() as u32;
+        let id_b = input.read_u64() as u32;
+        let threshold = f64::from_bits(input.read_u64());
+        if !(0.0..=1.0).contains(&threshold) {
+            return write_u64_err(&format!("threshold must be within 0..=1: {}", threshold), out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match (map.get(&id_a), map.get(&id_b)) {
+                (None, _) => write_u64_err(&format!("invalid image id {}", id_a), out_len),
+                (_, None) => write_u64_err(&format!("invalid image id {}", id_b), out_len),
+                (Some(a), Some(b)) => {
+                    let (a, b) = (a.to_rgba8(), b.to_rgba8());
+                    if a.dimensions() != b.dimensions() {
+                        return write_u64_err("image dimensions differ", out_len);
+                    }
+                    write_u64_ok(ops::compare_aa_tolerant(&a, &b, threshold), out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u32 len][data bytes]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeOpenFirstFrame(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let data = input.read_bytes();
+        match ops::decode_first_frame(data) {
+            Ok(img) => match insert_image(img) {
+                Ok(id) => write_u64_ok(id as u64, out_len),
+                Err(e) => write_u64_err(&e, out_len),
+            },
+            Err(e) => write_u64_err(&e, out_len),
+        }
+    }
+
+    // Input: (none)  → uint64
+    #[no_mangle]
+    pub extern "C" fn nativeGetPixelsProcessed(_ptr: *const u8, _len: u32, out_len: *mut u32) -> *mut u8 {
+        write_u64(PIXELS_PROCESSED.load(Ordering::Relaxed), out_len)
+    }
+
+    // Input: (none)  → (none)
+    #[no_mangle]
+    pub extern "C" fn nativeResetPixelsProcessed(_ptr: *const u8, _len: u32, out_len: *mut u32) -> *mut u8 {
+        PIXELS_PROCESSED.store(0, Ordering::Relaxed);
+        write_empty(out_len)
+    }
+
+    // Lossless JPEG transforms need libjpeg-turbo, which isn't built for standalone WASM.
+    #[no_mangle]
+    pub extern "C" fn nativeJPEGLosslessRotate(_ptr: *const u8, _len: u32, out_len: *mut u32) -> *mut u8 {
+        write_bytes_err("nativeJPEGLosslessRotate: not supported in WASM standalone", out_len)
+    }
+
+    // Input: [u32 LE len][ext bytes][u64 LE quality]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSetDefaultQuality(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let ext = input.read_str().to_string();
+        let quality = input.read_u64() as i64;
+        let fmt = match format_from_ext(&ext) {
+            Ok(f)  => f,
+            Err(e) => return write_error(&e, out_len),
+        };
+        match ops::check_quality(fmt, quality) {
+            Err(e) => write_error(&e, out_len),
+            Ok(q) => match DEFAULT_QUALITY.lock() {
+                Err(_) => write_error("quality lock poisoned", out_len),
+                Ok(mut map) => {
+                    map.insert(fmt, q);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u32 LE len][ext bytes]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeClearDefaultQuality(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let ext = input.read_str().to_string();
+        let fmt = match format_from_ext(&ext) {
+            Ok(f)  => f,
+            Err(e) => return write_error(&e, out_len),
+        };
+        match DEFAULT_QUALITY.lock() {
+            Err(_) => write_error("quality lock poisoned", out_len),
+            Ok(mut map) => {
+                map.remove(&fmt);
+                write_nil_error(out_len)
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE w][u64 LE h][u64 LE r][u64 LE g][u64 LE b][u64 LE a]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeResizePad(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let w  = input.read_u64() as u32;
+        let h  = input.read_u64() as u32;
+        if w == 0 || h == 0 {
+            return write_error("dimension must be non-zero", out_len);
+        }
+        let color = match read_rgba(&mut input) {
+            Ok(c) => c,
+            Err(e) => return write_error(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::resize_pad(img, w, h, color) {
+                    Ok(padded) => {
+                        record_pixels(img);
+                        *img = padded;
+                        write_nil_error(out_len)
+                    }
+                    Err(e) => write_error(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u32 len][packed blobs][u64 LE max_w][u64 LE max_h]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeBatchThumbnailFromBytes(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let cancel = cancel_token();
+        let mut input = unsafe { Input::new(ptr, len) };
+        let items = input.read_bytes();
+        let max_w = input.read_u64() as u32;
+        let max_h = input.read_u64() as u32;
+        match ops::unpack_blobs(items) {
+            Ok(blobs) => match ops::batch_thumbnail(&blobs, max_w, max_h, &cancel) {
+                Ok(thumbs) => write_bytes_ok(&ops::pack_thumbnails(&thumbs), out_len),
+                Err(e) => write_bytes_err(&e, out_len),
+            },
+            Err(e) => write_bytes_err(&e, out_len),
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE scale_bits]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeToF32Bytes(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let scale = f64::from_bits(input.read_u64());
+        let scale = if scale == 0.0 { 255.0 } else { scale };
+        if !scale.is_finite() || scale <= 0.0 {
+            return write_bytes_err(&format!("scale must be positive: {}", scale), out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_bytes_ok(&ops::to_f32_bytes(img, scale as f32), out_len),
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u32 len][f64 means][u32 len][f64 stds]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeStandardize(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let means = ops::unpack_f64s(input.read_bytes());
+        let stds = ops::unpack_f64s(input.read_bytes());
+        let (means, stds) = match (means, stds) {
+            (Ok(m), Ok(s)) => (m, s),
+            (Err(e), _) | (_, Err(e)) => return write_bytes_err(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::standardize(img, &means, &stds) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeTrimAlpha(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::trim_alpha(img) {
+                    Ok(trimmed) => {
+                        *img = trimmed;
+                        write_nil_error(out_len)
+                    }
+                    Err(e) => write_error(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE big_endian]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeToRGB565Bytes(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let big_endian = input.read_u64() != 0;
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_bytes_ok(&ops::to_rgb565_bytes(img, big_endian), out_len),
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE threshold][u64 LE dither]  → ([]byte, int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeToMonoBitmap(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let threshold = input.read_u64();
+        let dither = input.read_u64() != 0;
+        let threshold = match u8::try_from(threshold) {
+            Ok(t)  => t,
+            Err(_) => return write_bytes_int_err(&format!("threshold out of range: {}", threshold), out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_bytes_int_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_int_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    let (bits, stride) = ops::to_mono_bitmap(img, threshold, dither);
+                    write_bytes_int_ok(&bits, stride as i64, out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE r][u64 LE g][u64 LE b][u64 LE a][u64 LE amount_bits]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeFadeToColor(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let color = match read_rgba(&mut input) {
+            Ok(c) => c,
+            Err(e) => return write_error(&e, out_len),
+        };
+        let amount = f64::from_bits(input.read_u64());
+        if !(0.0..=1.0).contains(&amount) {
+            return write_error(&format!("amount must be within 0..=1: {}", amount), out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    *img = ops::fade_to_color(img, color, amount);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE x_components][u64 LE y_components]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeBlurhash(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let x_components = input.read_u64().min(u32::MAX as u64) as u32;
+        let y_components = input.read_u64().min(u32::MAX as u64) as u32;
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::blurhash(img, x_components, y_components) {
+                    Ok(hash) => write_bytes_ok(hash.as_bytes(), out_len),
+                    Err(e)   => write_bytes_err(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u32 len][hash bytes][u64 LE w][u64 LE h][u64 LE punch_bits]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeOpenFromBlurhash(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let hash = input.read_str().to_string();
+        let w = input.read_u64() as u32;
+        let h = input.read_u64() as u32;
+        let punch = f64::from_bits(input.read_u64());
+        if !punch.is_finite() || punch <= 0.0 {
+            return write_u64_err(&format!("punch must be positive: {}", punch), out_len);
+        }
+        match ops::from_blurhash(&hash, w, h, punch) {
+            Ok(img) => match insert_image(img) {
+                Ok(id) => write_u64_ok(id as u64, out_len),
+                Err(e) => write_u64_err(&e, out_len),
+            },
+            Err(e) => write_u64_err(&e, out_len),
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE max_pixels]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeResizeToPixels(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let max_pixels = input.read_u64();
+        if max_pixels == 0 || max_pixels > i64::MAX as u64 {
+            return write_error(&format!("max_pixels must be positive: {}", max_pixels as i64), out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    if let Some((w, h)) = ops::pixel_budget_dimensions(img.width(), img.height(), max_pixels) {
+                        record_pixels(img);
+                        *img = img.resize_exact(w, h, image::imageops::FilterType::Lanczos3);
+                    }
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE r][u64 LE g][u64 LE b]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeTint(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let [r, g, b] = match read_rgb(&mut input) {
+            Ok(c) => c,
+            Err(e) => return write_error(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    *img = ops::tint(img, r, g, b);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE exposure_bits][u64 LE gamma_bits]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeToneMap(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let exposure = f64::from_bits(input.read_u64());
+        let gamma = f64::from_bits(input.read_u64());
+        if !exposure.is_finite() || exposure <= 0.0 {
+            return write_error(&format!("exposure must be positive: {}", exposure), out_len);
+        }
+        if !gamma.is_finite() || gamma <= 0.0 {
+            return write_error(&format!("gamma must be positive: {}", gamma), out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    *img = ops::tone_map(img, exposure, gamma);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // wasm32-unknown-unknown has no clock, so access timestamps aren't tracked.
+    #[no_mangle]
+    pub extern "C" fn nativeAccessInfo(_ptr: *const u8, _len: u32, out_len: *mut u32) -> *mut u8 {
+        write_two_ints_err("nativeAccessInfo: not supported in WASM standalone", out_len)
+    }
+
+    // Input: [u64 LE id][u64 LE upconvert]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeEncodePNG16(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let upconvert = input.read_u64() != 0;
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::encode_png16(img, upconvert) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE limit]  → (int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeUniqueColors(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let limit = input.read_u64();
+        if limit > i64::MAX as u64 {
+            return write_u64_err(&format!("limit out of range: {}", limit as i64), out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_u64_ok(ops::unique_colors(img, limit as usize) as u64, out_len),
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE x][u64 LE y][u64 LE w][u64 LE h]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSelectionSet(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let x = input.read_u64();
+        let y = input.read_u64();
+        let w = input.read_u64();
+        let h = input.read_u64();
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    if x.saturating_add(w) > img.width() as u64 || y.saturating_add(h) > img.height() as u64 {
+                        return write_error("selection out of bounds", out_len);
+                    }
+                    match SELECTIONS.lock() {
+                        Err(_) => write_error("selection lock poisoned", out_len),
+                        Ok(mut sel) => {
+                            let region = (x as u32, y as u32, w as u32, h as u32);
+                            sel.insert(id, ops::Selection { region, size: (img.width(), img.height()) });
+                            write_nil_error(out_len)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSelectionClear(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(map) if !map.contains_key(&id) => write_error(&format!("invalid image id {}", id), out_len),
+            Ok(_) => match SELECTIONS.lock() {
+                Err(_) => write_error("selection lock poisoned", out_len),
+                Ok(mut sel) => {
+                    sel.remove(&id);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSnapshot(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match HISTORY.lock() {
+                    Err(_) => write_error("history lock poisoned", out_len),
+                    Ok(mut history) => {
+                        let stack = history.entry(id).or_default();
+                        stack.push(img.clone());
+                        let depth = HISTORY_DEPTH.load(Ordering::Relaxed);
+                        if stack.len() > depth {
+                            stack.drain(..stack.len() - depth);
+                        }
+                        write_nil_error(out_len)
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeUndo(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match HISTORY.lock() {
+                    Err(_) => write_error("history lock poisoned", out_len),
+                    Ok(mut history) => match history.get_mut(&id).and_then(|stack| stack.pop()) {
+                        None => write_error("nothing to undo", out_len),
+                        Some(previous) => {
+                            *img = previous;
+                            write_nil_error(out_len)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE depth]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSetHistoryDepth(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let depth = input.read_u64();
+        if depth > i64::MAX as u64 {
+            return write_error(&format!("depth out of range: {}", depth as i64), out_len);
+        }
+        HISTORY_DEPTH.store(depth as usize, Ordering::Relaxed);
+        write_nil_error(out_len)
+    }
+
+    // Input: [u64 LE id]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeToPlanarRGBABytes(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_bytes_ok(&ops::to_planar_rgba(img), out_len),
+            }
+        }
+    }
+
+    // Input: [u64 LE w][u64 LE h][u32 len][planar bytes]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeNewFromPlanarRGBA(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let w = input.read_u64() as u32;
+        let h = input.read_u64() as u32;
+        let data = input.read_bytes();
+        match ops::from_planar_rgba(w, h, data) {
+            Ok(img) => match insert_image(img) {
+                Ok(id) => write_u64_ok(id as u64, out_len),
+                Err(e) => write_u64_err(&e, out_len),
+            },
+            Err(e) => write_u64_err(&e, out_len),
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE min_region_size]  → (int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeDespeckle(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let min_size = input.read_u64();
+        if min_size > i64::MAX as u64 {
+            return write_u64_err(&format!("min_region_size out of range: {}", min_size as i64), out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    let (cleaned, removed) = ops::despeckle(img, min_size as usize);
+                    *img = cleaned;
+                    write_u64_ok(removed as u64, out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][4×u64 LE from][4×u64 LE to][u64 LE tolerance_bits][u64 LE metric][u64 LE mask_id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeReplaceColorMasked(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let from = match read_rgba(&mut input) {
+            Ok(c) => c,
+            Err(e) => return write_error(&e, out_len),
+        };
+        let to = match read_rgba(&mut input) {
+            Ok(c) => c,
+            Err(e) => return write_error(&e, out_len),
+        };
+        let tolerance = f64::from_bits(input.read_u64());
+        let metric = match ops::ColorMetric::from_code(input.read_u64() as i64) {
+            Ok(m)  => m,
+            Err(e) => return write_error(&e, out_len),
+        };
+        let mask_id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => {
+                let mask = match map.get(&mask_id) {
+                    None => return write_error(&format!("invalid image id {}", mask_id), out_len),
+                    Some(m) => m.to_luma8(),
+                };
+                match map.get_mut(&id) {
+                    None => write_error(&format!("invalid image id {}", id), out_len),
+                    Some(img) => match ops::replace_color_masked(img, &mask, from, to, tolerance, metric) {
+                        Ok(replaced) => {
+                            *img = replaced;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE wr_bits][u64 LE wg_bits][u64 LE wb_bits]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeGrayscaleWeighted(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let mut weights = [0.0; 3];
+        for w in weights.iter_mut() {
+            *w = f64::from_bits(input.read_u64());
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::grayscale_weighted(img, weights) {
+                    Ok(gray) => {
+                        *img = gray;
+                        write_nil_error(out_len)
+                    }
+                    Err(e) => write_error(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u32 len][u32 LE ids][u64 LE cols][u64 LE thumb_w][u64 LE thumb_h]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeEncodeContactSheetPDF(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let cancel = cancel_token();
+        let mut input = unsafe { Input::new(ptr, len) };
+        let ids = match ops::unpack_ids(input.read_bytes()) {
+            Ok(ids) => ids,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        let cols = input.read_u64().min(u32::MAX as u64) as u32;
+        let thumb_w = input.read_u64().min(u32::MAX as u64) as u32;
+        let thumb_h = input.read_u64().min(u32::MAX as u64) as u32;
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => {
+                let mut images = Vec::with_capacity(ids.len());
+                for id in ids {
+                    match map.get(&id) {
+                        None => return write_bytes_err(&format!("invalid image id {}", id), out_len),
+                        Some(img) => images.push(img),
+                    }
+                }
+                for img in &images {
+                    record_pixels(img);
+                }
+                match ops::contact_sheet_pdf(&images, cols, thumb_w, thumb_h, &cancel) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → (uint64 coverage_bits, error)
+    #[no_mangle]
+    pub extern "C" fn nativeAlphaCoverage(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_u64_ok(ops::alpha_coverage(img).to_bits(), out_len),
+            }
+        }
+    }
+
+    // Input: [u32 len][gif bytes]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeGIFDecodeAll(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let data = input.read_bytes();
+        match ops::decode_gif_animation(data) {
+            Ok(anim) => match ANIMATIONS.lock() {
+                Err(_) => write_u64_err("animation lock poisoned", out_len),
+                Ok(mut anims) => {
+                    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+                    anims.insert(id, anim);
+                    write_u64_ok(id as u64, out_len)
+                }
+            },
+            Err(e) => write_u64_err(&e, out_len),
+        }
+    }
+
+    // Input: [u64 LE handle][u32 len][op bytes]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeGIFMap(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let handle = input.read_u64() as u32;
+        let op = input.read_str().to_string();
+        match ANIMATIONS.lock() {
+            Err(_) => write_error("animation lock poisoned", out_len),
+            Ok(mut anims) => match anims.get_mut(&handle) {
+                None => write_error(&format!("invalid animation id {}", handle), out_len),
+                Some(anim) => match ops::map_gif_frames(anim, &op) {
+                    Ok(()) => write_nil_error(out_len),
+                    Err(e) => write_error(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE handle]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeGIFEncode(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let handle = input.read_u64() as u32;
+        match ANIMATIONS.lock() {
+            Err(_) => write_bytes_err("animation lock poisoned", out_len),
+            Ok(anims) => match anims.get(&handle) {
+                None => write_bytes_err(&format!("invalid animation id {}", handle), out_len),
+                Some(anim) => match ops::encode_gif_animation(anim) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE handle]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeGIFClose(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let handle = input.read_u64() as u32;
+        match ANIMATIONS.lock() {
+            Err(_) => write_error("animation lock poisoned", out_len),
+            Ok(mut anims) => match anims.remove(&handle) {
+                None => write_error(&format!("invalid animation id {}", handle), out_len),
+                Some(_) => write_nil_error(out_len),
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeEncodePNGOptimized(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::encode_png_optimized(img) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id_a][u64 LE id_b]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSwap(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id_a = input.read_u64() as u32;
+        let id_b = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => {
+                for id in [id_a, id_b] {
+                    if !map.contains_key(&id) {
+                        return write_error(&format!("invalid image id {}", id), out_len);
+                    }
+                }
+                ops::swap_entries(&mut map, id_a, id_b);
+                if let Ok(mut sel) = SELECTIONS.lock() {
+                    ops::swap_entries(&mut sel, id_a, id_b);
+                }
+                if let Ok(mut history) = HISTORY.lock() {
+                    ops::swap_entries(&mut history, id_a, id_b);
+                }
+                if let Ok(mut spaces) = COLORSPACES.lock() {
+                    ops::swap_entries(&mut spaces, id_a, id_b);
+                }
+                if let Ok(mut orientations) = ORIENTATIONS.lock() {
+                    ops::swap_entries(&mut orientations, id_a, id_b);
+                }
+                write_nil_error(out_len)
+            }
+        }
+    }
+
+    // Input: [u64 LE bytes]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSetDecodeLimit(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let bytes = input.read_u64();
+        if bytes > i64::MAX as u64 {
+            return write_error(&format!("limit out of range: {}", bytes as i64), out_len);
+        }
+        DECODE_LIMIT.store(bytes, Ordering::Relaxed);
+        write_nil_error(out_len)
+    }
+
+    // Input: [u32 len][data bytes]  → (uint32, int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeOpenFromBytesAdaptive(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let data = input.read_bytes();
+        match ops::decode_adaptive(data, DECODE_LIMIT.load(Ordering::Relaxed)) {
+            Ok((img, divisor)) => match insert_image(img) {
+                Ok(id) => {
+                    if let (Some(orientation), Ok(mut orientations)) = (ops::read_orientation(data), ORIENTATIONS.lock()) {
+                        orientations.insert(id, orientation);
+                    }
+                    write_two_ints_ok(id as i64, divisor as i64, out_len)
+                }
+                Err(e) => write_two_ints_err(&e, out_len),
+            },
+            Err(e) => write_two_ints_err(&e, out_len),
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE channel][u64 LE value]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeClearChannel(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let channel = input.read_u64().min(usize::MAX as u64) as usize;
+        let value = match input.read_u8("value") {
+            Ok(v) => v,
+            Err(e) => return write_error(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::clear_channel(img, channel, value) {
+                    Ok(cleared) => {
+                        *img = cleared;
+                        write_nil_error(out_len)
+                    }
+                    Err(e) => write_error(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE x][u64 LE y][u64 LE w][u64 LE h]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeCrop(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let x = input.read_u64();
+        let y = input.read_u64();
+        let w = input.read_u64();
+        let h = input.read_u64();
+        if w == 0 || h == 0 {
+            return write_error("dimension must be non-zero", out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    if x.saturating_add(w) > img.width() as u64 || y.saturating_add(h) > img.height() as u64 {
+                        return write_error("crop region out of bounds", out_len);
+                    }
+                    *img = img.crop_imm(x as u32, y as u32, w as u32, h as u32);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE direction]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeEmboss(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let cancel = cancel_token();
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let direction = input.read_u64().min(u32::MAX as u64) as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    match ops::emboss(img, direction, &cancel) {
+                        Ok(embossed) => {
+                            *img = embossed;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE degrees]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeRotate(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let degrees = input.read_u64() as i64;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    *img = match degrees {
+                        90 => img.rotate90(),
+                        180 => img.rotate180(),
+                        270 => img.rotate270(),
+                        other => {
+                            return write_error(&format!("rotation must be 90, 180 or 270 degrees: {}", other), out_len)
+                        }
+                    };
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE cols]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeToANSIPreview(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let cols = input.read_u64().min(u32::MAX as u64) as u32;
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    match ops::ansi_preview(img, cols) {
+                        Ok(s)  => write_bytes_ok(s.as_bytes(), out_len),
+                        Err(e) => write_bytes_err(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeFlipHorizontal(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    *img = img.fliph();
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeFlipVertical(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    *img = img.flipv();
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE strength_bits]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSharpen(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let cancel = cancel_token();
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let strength = f64::from_bits(input.read_u64());
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    match ops::sharpen(img, strength, &cancel) {
+                        Ok(sharpened) => {
+                            *img = sharpened;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
 
-            let out_path = temp_file("save");
-            save_impl(id as u64, &out_path.to_string_lossy()).expect("save should succeed");
-            assert!(out_path.exists(), "saved output file should exist");
+    // Input: [u64 LE id][u64 LE angle_bits][u64 LE rgba][u64 LE interpolation]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeRotateAngle(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let degrees = f64::from_bits(input.read_u64());
+        let bg = input.read_u64();
+        let interpolation = input.read_u64() as i64;
+        if bg > u32::MAX as u64 {
+            return write_error(&format!("packed color out of range: {:#x}", bg), out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    match ops::rotate_angle(img, degrees, ops::unpack_rgba(bg), interpolation) {
+                        Ok(rotated) => {
+                            *img = rotated;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
 
-            let reopened = open_impl(&out_path.to_string_lossy()).expect("open should succeed");
-            let (rw, rh) = size_impl(reopened as u64).expect("reopened image size should succeed");
-            assert!(rw > 0 && rh > 0, "reopened image dimensions must be positive");
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeGrayscale(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    let gray = match selection_of(id, img) {
+                        Some(region) => ops::apply_in_region(img, region, |patch| Ok(patch.grayscale())),
+                        None => Ok(img.grayscale()),
+                    };
+                    match gray {
+                        Ok(gray) => {
+                            *img = gray;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
 
-            close_impl(reopened as u64).expect("close reopened image should succeed");
-            close_impl(id as u64).expect("close original image should succeed");
-            fs::remove_file(&out_path).expect("cleanup saved file should succeed");
+    // Input: [u64 LE id_a][u64 LE id_b][u64 LE cell_size]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeCheckerCompare(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id_a = input.read_u64() as u32;
+        let id_b = input.read_u64() as u32;
+        let cell = input.read_u64().min(u32::MAX as u64) as u32;
+        let checker = match IMAGES.lock() {
+            Err(_) => return write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match (map.get(&id_a), map.get(&id_b)) {
+                (Some(a), Some(b)) => ops::checker_compare(&a.to_rgba8(), &b.to_rgba8(), cell),
+                (None, _) => Err(format!("invalid image id {}", id_a)),
+                (_, None) => Err(format!("invalid image id {}", id_b)),
+            },
+        };
+        match checker.and_then(insert_image) {
+            Ok(id) => write_u64_ok(id as u64, out_len),
+            Err(e) => write_u64_err(&e, out_len),
         }
+    }
 
-        #[test]
-        fn invalid_image_id_paths_fail() {
-            let invalid = 9_999_999u64;
-            assert!(size_impl(invalid).is_err(), "size should fail for invalid id");
-            assert!(
-                encode_png_impl(invalid).is_err(),
-                "encode_png should fail for invalid id"
-            );
-            assert!(close_impl(invalid).is_err(), "close should fail for invalid id");
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeInvert(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    img.invert();
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE modules][u64 LE module_size][u64 LE r][u64 LE g][u64 LE b]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeAddQuietZone(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let modules = input.read_u64();
+        let module_size = input.read_u64();
+        let color = match read_rgb(&mut input) {
+            Ok([r, g, b]) => image::Rgba([r, g, b, 255]),
+            Err(e) => return write_error(&e, out_len),
+        };
+        let border = match modules.checked_mul(module_size).and_then(|b| u32::try_from(b).ok()) {
+            Some(b) => b,
+            None => return write_error(&format!("quiet zone too large: {}x{}", modules, module_size), out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::pad_border(img, border, color) {
+                    Ok(padded) => {
+                        *img = padded;
+                        write_nil_error(out_len)
+                    }
+                    Err(e) => write_error(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE space]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSetColorspace(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let space = match ops::ColorSpace::from_code(input.read_u64() as i64) {
+            Ok(s)  => s,
+            Err(e) => return write_error(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(map) if !map.contains_key(&id) => write_error(&format!("invalid image id {}", id), out_len),
+            Ok(_) => match COLORSPACES.lock() {
+                Err(_) => write_error("colorspace lock poisoned", out_len),
+                Ok(mut spaces) => {
+                    spaces.insert(id, space);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → (int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeGetColorspace(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) if !map.contains_key(&id) => write_u64_err(&format!("invalid image id {}", id), out_len),
+            Ok(_) => write_u64_ok(colorspace_of(id).code() as u64, out_len),
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE w][u64 LE h]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeResizeLinear(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let w  = input.read_u64() as u32;
+        let h  = input.read_u64() as u32;
+        if w == 0 || h == 0 {
+            return write_error("dimension must be non-zero", out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    *img = ops::resize_linear(img, w, h, colorspace_of(id).needs_linearize());
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE sigma_bits]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeBlur(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let cancel = cancel_token();
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let sigma = match ops::blur_sigma(f64::from_bits(input.read_u64())) {
+            Ok(s)  => s,
+            Err(e) => return write_error(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    let blurred = match selection_of(id, img) {
+                        Some(region) => ops::apply_in_region(img, region, |patch| ops::blur(patch, sigma, &cancel)),
+                        None => ops::blur(img, sigma, &cancel),
+                    };
+                    match blurred {
+                        Ok(blurred) => {
+                            *img = blurred;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE w][u64 LE h][u32 len][NV12 bytes]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeNewFromNV12(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let w = input.read_u64() as u32;
+        let h = input.read_u64() as u32;
+        let data = input.read_bytes();
+        match ops::from_nv12(w, h, data) {
+            Ok(img) => match insert_image(img) {
+                Ok(id) => write_u64_ok(id as u64, out_len),
+                Err(e) => write_u64_err(&e, out_len),
+            },
+            Err(e) => write_u64_err(&e, out_len),
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE sigma_bits][u64 LE threshold]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeUnsharpen(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let cancel = cancel_token();
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let sigma = f64::from_bits(input.read_u64());
+        let threshold = input.read_u64() as i64;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    match ops::unsharpen(img, sigma, threshold, &cancel) {
+                        Ok(sharpened) => {
+                            *img = sharpened;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE sigma1_bits][u64 LE sigma2_bits]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeDoG(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let cancel = cancel_token();
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let sigma1 = f64::from_bits(input.read_u64());
+        let sigma2 = f64::from_bits(input.read_u64());
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    match ops::difference_of_gaussians(img, sigma1, sigma2, &cancel) {
+                        Ok(band) => {
+                            *img = band;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE degrees, sign-extended]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeHueRotate(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let degrees = input.read_u64() as i64;
+        let degrees = match i32::try_from(degrees) {
+            Ok(d)  => d,
+            Err(_) => return write_error(&format!("degrees out of range: {}", degrees), out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    *img = img.huerotate(degrees);
+                    write_nil_error(out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE r][u64 LE g][u64 LE b][u64 LE tolerance]
+    //   → (x, y, w, h, found int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeFindColorRect(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let color = match read_rgb(&mut input) {
+            Ok(c) => c,
+            Err(e) => return write_ints_err(5, &e, out_len),
+        };
+        let tolerance = match input.read_u8("tolerance") {
+            Ok(v) => v,
+            Err(e) => return write_ints_err(5, &e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_ints_err(5, "image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_ints_err(5, &format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::find_color_rect(img, color, tolerance) {
+                    Some((x, y, w, h)) => write_ints_ok(&[x as i64, y as i64, w as i64, h as i64, 1], out_len),
+                    None => write_ints_ok(&[0, 0, 0, 0, 0], out_len),
+                },
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE max_w][u64 LE max_h]  → (int, int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeResizePreserve(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let w  = input.read_u64() as u32;
+        let h  = input.read_u64() as u32;
+        if w == 0 || h == 0 {
+            return write_two_ints_err("dimension must be non-zero", out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_two_ints_err("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_two_ints_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    *img = img.resize(w, h, image::imageops::FilterType::Lanczos3);
+                    write_two_ints_ok(img.width() as i64, img.height() as i64, out_len)
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE base_id][u64 LE shape_id][u64 LE x][u64 LE y][u64 LE r][u64 LE g][u64 LE b]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeStencil(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let base_id = input.read_u64() as u32;
+        let shape_id = input.read_u64() as u32;
+        let x = input.read_u64() as i64;
+        let y = input.read_u64() as i64;
+        let color = match read_rgb(&mut input) {
+            Ok(c) => c,
+            Err(e) => return write_error(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => {
+                let shape = match map.get(&shape_id) {
+                    Some(img) => img.to_rgba8(),
+                    None => return write_error(&format!("invalid image id {}", shape_id), out_len),
+                };
+                match map.get_mut(&base_id) {
+                    None => write_error(&format!("invalid image id {}", base_id), out_len),
+                    Some(img) => {
+                        *img = ops::stencil(img, &shape, x, y, color);
+                        write_nil_error(out_len)
+                    }
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE w][u64 LE h][u64 LE filter]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeResizeWithFilter(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let w  = input.read_u64() as u32;
+        let h  = input.read_u64() as u32;
+        let filter = match ops::filter_from_code(input.read_u64() as i64) {
+            Ok(f)  => f,
+            Err(e) => return write_error(&e, out_len),
+        };
+        if w == 0 || h == 0 {
+            return write_error("dimension must be non-zero", out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    *img = img.resize_exact(w, h, filter);
+                    write_nil_error(out_len)
+                }
+            }
         }
     }
-}
 
-#[cfg(feature = "native")]
-vo_ext::export_extensions!();
+    // Input: [u64 LE id]  → (channels, bits, has_alpha int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeColorInfo(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_ints_err(3, "image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_ints_err(3, &format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    let (channels, bits, alpha) = ops::color_info(img.color());
+                    write_ints_ok(&[channels, bits, alpha], out_len)
+                }
+            }
+        }
+    }
 
-// ── Standalone C-ABI WASM exports ────────────────────────────────────────────
-//
-// Uses ext_bridge v2 tagged binary protocol:
-//   Input:  one entry per param slot — Value=[u64 LE 8B], Bytes=[u32 len][bytes]
-//   Output: self-describing tagged stream — see tag constants below.
+    // Input: [u64 LE id][u64 LE strength_bits]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeHeightToNormal(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let strength = f64::from_bits(input.read_u64());
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    match ops::height_to_normal(img, strength) {
+                        Ok(normals) => {
+                            *img = normals;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
+    }
 
-#[cfg(feature = "wasm-standalone")]
-mod standalone {
-    use std::collections::HashMap;
-    use std::io::Cursor;
-    use std::sync::atomic::{AtomicU32, Ordering};
-    use std::sync::Mutex;
-    use image::{DynamicImage, ImageFormat};
-    use lazy_static::lazy_static;
+    // Input: [u64 LE id][u64 LE threshold][u64 LE connectivity]  → (int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeCountBlobs(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let threshold = match input.read_u8("threshold") {
+            Ok(v) => v,
+            Err(e) => return write_u64_err(&e, out_len),
+        };
+        let connectivity = input.read_u64().min(u32::MAX as u64) as u32;
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::count_blobs(img, threshold, connectivity) {
+                    Ok(count) => write_u64_ok(count as u64, out_len),
+                    Err(e) => write_u64_err(&e, out_len),
+                },
+            }
+        }
+    }
 
-    // v2 tagged protocol output tags (mirrors ext_bridge.rs constants)
-    const TAG_NIL_ERROR: u8 = 0xE0;
-    const TAG_ERROR_STR: u8 = 0xE1;
-    const TAG_VALUE:     u8 = 0xE2;
-    const TAG_BYTES:     u8 = 0xE3;
-    const TAG_NIL_REF:   u8 = 0xE4;
+    // Input: [u64 LE id][u64 LE quality]  → (bytes, error)
+    #[no_mangle]
+    pub extern "C" fn nativeSaveToBytesJpeg(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let quality = match ops::check_quality(ImageFormat::Jpeg, input.read_u64() as i64) {
+            Ok(q)  => q,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::encode_jpeg(img, quality) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                },
+            }
+        }
+    }
 
-    lazy_static! {
-        static ref IMAGES: Mutex<HashMap<u32, DynamicImage>> = Mutex::new(HashMap::new());
+    // Input: [u64 LE id][u64 LE level]  → (bytes, error)
+    #[no_mangle]
+    pub extern "C" fn nativeEncodePNGWithLevel(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let level = match ops::check_quality(ImageFormat::Png, input.read_u64() as i64) {
+            Ok(l)  => l,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::encode_png_with_level(img, level) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                },
+            }
+        }
     }
-    static NEXT_ID: AtomicU32 = AtomicU32::new(1);
 
-    // ── Memory management ─────────────────────────────────────────────────────
+    // Input: [u64 LE id][u64 LE x][u64 LE y][u64 LE w][u64 LE h]  → (bytes, error)
+    #[no_mangle]
+    pub extern "C" fn nativeHistogramRegion(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let mut coord = || input.read_u64().min(u32::MAX as u64) as u32;
+        let region = (coord(), coord(), coord(), coord());
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_bytes_ok(&ops::histogram_region(img, region), out_len),
+            }
+        }
+    }
 
+    // Input: [u64 LE id]  → (packed rgba, confidence_bits, error)
     #[no_mangle]
-    pub extern "C" fn vo_alloc(size: u32) -> *mut u8 {
-        let mut buf = Vec::<u8>::with_capacity(size as usize);
-        let ptr = buf.as_mut_ptr();
-        std::mem::forget(buf);
-        ptr
+    pub extern "C" fn nativeDetectBackground(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_two_ints_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_two_ints_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    let (color, confidence) = ops::detect_background(img);
+                    write_two_ints_ok(ops::pack_rgba(color) as i64, confidence.to_bits() as i64, out_len)
+                }
+            }
+        }
     }
 
+    // Input: [u64 LE base_id][u64 LE top_id][u64 LE x][u64 LE y]  → error
     #[no_mangle]
-    pub extern "C" fn vo_dealloc(ptr: *mut u8, size: u32) {
-        unsafe { drop(Vec::from_raw_parts(ptr, 0, size as usize)) };
+    pub extern "C" fn nativeOverlay(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let base_id = input.read_u64() as u32;
+        let top_id = input.read_u64() as u32;
+        let x = input.read_u64() as i64;
+        let y = input.read_u64() as i64;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => {
+                let top = match map.get(&top_id) {
+                    Some(img) => img.clone(),
+                    None => return write_error(&format!("invalid image id {}", top_id), out_len),
+                };
+                match map.get_mut(&base_id) {
+                    None => write_error(&format!("invalid image id {}", base_id), out_len),
+                    Some(img) => {
+                        image::imageops::overlay(img, &top, x, y);
+                        write_nil_error(out_len)
+                    }
+                }
+            }
+        }
     }
 
-    // ── Input / output helpers ────────────────────────────────────────────────
+    // Input: [u64 LE id]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeClone(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let copy = match IMAGES.lock() {
+            Err(_) => return write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => return write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => img.clone(),
+            }
+        };
+        match insert_image(copy) {
+            Ok(new_id) => write_u64_ok(new_id as u64, out_len),
+            Err(e) => write_u64_err(&e, out_len),
+        }
+    }
 
-    fn alloc_output(data: &[u8], out_len: *mut u32) -> *mut u8 {
-        unsafe { *out_len = data.len() as u32; }
-        let ptr = vo_alloc(data.len() as u32);
-        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len()); }
-        ptr
+    // Input: [u64 LE base_id][u64 LE other_id][u64 LE op]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeImageArithmetic(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let base_id = input.read_u64() as u32;
+        let other_id = input.read_u64() as u32;
+        let op = input.read_u64() as i64;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => {
+                let other = match map.get(&other_id) {
+                    Some(img) => img.clone(),
+                    None => return write_error(&format!("invalid image id {}", other_id), out_len),
+                };
+                match map.get_mut(&base_id) {
+                    None => write_error(&format!("invalid image id {}", base_id), out_len),
+                    Some(img) => {
+                        record_pixels(img);
+                        match ops::image_arithmetic(img, &other, op) {
+                            Ok(combined) => {
+                                *img = combined;
+                                write_nil_error(out_len)
+                            }
+                            Err(e) => write_error(&e, out_len),
+                        }
+                    }
+                }
+            }
+        }
     }
 
-    struct Input<'a> { buf: &'a [u8], pos: usize }
-    impl<'a> Input<'a> {
-        unsafe fn new(ptr: *const u8, len: u32) -> Self {
-            Self { buf: std::slice::from_raw_parts(ptr, len as usize), pos: 0 }
+    // Input: [u64 LE id][u64 LE x][u64 LE y][u64 LE w][u64 LE h][u64 LE rgba]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeFillRect(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let x = input.read_u64();
+        let y = input.read_u64();
+        let w = input.read_u64();
+        let h = input.read_u64();
+        let rgba = input.read_u64();
+        if w == 0 || h == 0 {
+            return write_error("dimension must be non-zero", out_len);
         }
-        fn read_u64(&mut self) -> u64 {
-            if self.pos + 8 > self.buf.len() { return 0; }
-            let v = u64::from_le_bytes(self.buf[self.pos..self.pos + 8].try_into().unwrap());
-            self.pos += 8;
-            v
+        if rgba > u32::MAX as u64 {
+            return write_error(&format!("packed color out of range: {:#x}", rgba), out_len);
         }
-        fn read_bytes(&mut self) -> &[u8] {
-            if self.pos + 4 > self.buf.len() { return &[]; }
-            let len = u32::from_le_bytes(self.buf[self.pos..self.pos + 4].try_into().unwrap()) as usize;
-            self.pos += 4;
-            if self.pos + len > self.buf.len() { return &[]; }
-            let data = &self.buf[self.pos..self.pos + len];
-            self.pos += len;
-            data
+        if [x, y, w, h].iter().any(|&v| v > u32::MAX as u64) {
+            return write_error("fill region out of bounds", out_len);
         }
-        fn read_str(&mut self) -> &str {
-            std::str::from_utf8(self.read_bytes()).unwrap_or("")
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    let region = (x as u32, y as u32, w as u32, h as u32);
+                    match ops::fill_rect(img, region, ops::unpack_rgba(rgba), selection_of(id, img)) {
+                        Ok(filled) => {
+                            *img = filled;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
         }
     }
 
-    fn write_u64_ok(v: u64, out_len: *mut u32) -> *mut u8 {
-        // [TAG_VALUE][u64 LE][TAG_NIL_ERROR]
-        let mut buf = Vec::with_capacity(11);
-        buf.push(TAG_VALUE);
-        buf.extend_from_slice(&v.to_le_bytes());
-        buf.push(TAG_NIL_ERROR);
-        alloc_output(&buf, out_len)
+    // Input: [u64 LE id][u64 LE target_hue][u64 LE range][u64 LE rotate_by], sign-extended  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSelectiveHueRotate(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let target_hue = input.read_u64() as i64 as f64;
+        let range = input.read_u64() as i64 as f64;
+        let rotate_by = input.read_u64() as i64 as f64;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    match ops::selective_hue_rotate(img, target_hue, range, rotate_by) {
+                        Ok(rotated) => {
+                            *img = rotated;
+                            write_nil_error(out_len)
+                        }
+                        Err(e) => write_error(&e, out_len),
+                    }
+                }
+            }
+        }
     }
 
-    fn write_u64_err(msg: &str, out_len: *mut u32) -> *mut u8 {
-        // [TAG_VALUE][u64 LE 0][TAG_ERROR_STR][u16 len][msg]
-        let mb = msg.as_bytes();
-        let mlen = mb.len().min(0xFFFF) as u16;
-        let mut buf = Vec::with_capacity(11 + mlen as usize);
-        buf.push(TAG_VALUE);
-        buf.extend_from_slice(&0u64.to_le_bytes());
-        buf.push(TAG_ERROR_STR);
-        buf.extend_from_slice(&mlen.to_le_bytes());
-        buf.extend_from_slice(&mb[..mlen as usize]);
-        alloc_output(&buf, out_len)
+    // Input: [u64 LE id][u64 LE x][u64 LE y]  → (u64 rgba, error)
+    #[no_mangle]
+    pub extern "C" fn nativeGetPixel(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let x = input.read_u64();
+        let y = input.read_u64();
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) if x >= img.width() as u64 || y >= img.height() as u64 => {
+                    write_u64_err("pixel out of bounds", out_len)
+                }
+                Some(img) => {
+                    let px = image::GenericImageView::get_pixel(img, x as u32, y as u32);
+                    write_u64_ok(ops::pack_rgba(px), out_len)
+                }
+            }
+        }
     }
 
-    fn write_nil_error(out_len: *mut u32) -> *mut u8 {
-        alloc_output(&[TAG_NIL_ERROR], out_len)
+    // Input: [u64 LE id][u64 LE x][u64 LE y][u64 LE rgba]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeSetPixel(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let x = input.read_u64();
+        let y = input.read_u64();
+        let rgba = input.read_u64();
+        if rgba > u32::MAX as u64 {
+            return write_error(&format!("packed color out of range: {:#x}", rgba), out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) if x >= img.width() as u64 || y >= img.height() as u64 => {
+                    write_error("pixel out of bounds", out_len)
+                }
+                Some(img) => {
+                    if !matches!(img, DynamicImage::ImageRgba8(_)) {
+                        *img = DynamicImage::ImageRgba8(img.to_rgba8());
+                    }
+                    if let DynamicImage::ImageRgba8(rgba_img) = img {
+                        rgba_img.put_pixel(x as u32, y as u32, ops::unpack_rgba(rgba));
+                    }
+                    write_nil_error(out_len)
+                }
+            }
+        }
     }
 
-    fn write_error(msg: &str, out_len: *mut u32) -> *mut u8 {
-        let mb = msg.as_bytes();
-        let mlen = mb.len().min(0xFFFF) as u16;
-        let mut buf = Vec::with_capacity(3 + mlen as usize);
-        buf.push(TAG_ERROR_STR);
-        buf.extend_from_slice(&mlen.to_le_bytes());
-        buf.extend_from_slice(&mb[..mlen as usize]);
-        alloc_output(&buf, out_len)
+    // Input: [u64 LE id][u32 len][u32 LE sizes]  → (bytes, error)
+    #[no_mangle]
+    pub extern "C" fn nativeFaviconSet(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let sizes = match ops::unpack_u32s(input.read_bytes(), "size") {
+            Ok(s)  => s,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::favicon_set(img, &sizes) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                },
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE x_bits][u64 LE y_bits][u64 LE interpolation]  → (u64 rgba, error)
+    #[no_mangle]
+    pub extern "C" fn nativeSample(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let x = f64::from_bits(input.read_u64());
+        let y = f64::from_bits(input.read_u64());
+        let interpolation = input.read_u64() as i64;
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::sample(img, x, y, interpolation) {
+                    Ok(px) => write_u64_ok(ops::pack_rgba(px), out_len),
+                    Err(e) => write_u64_err(&e, out_len),
+                },
+            }
+        }
     }
 
-    fn write_bytes_ok(data: &[u8], out_len: *mut u32) -> *mut u8 {
-        // [TAG_BYTES][u32 len][bytes][TAG_NIL_ERROR]
-        let mut buf = Vec::with_capacity(5 + data.len() + 1);
-        buf.push(TAG_BYTES);
-        buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
-        buf.extend_from_slice(data);
-        buf.push(TAG_NIL_ERROR);
-        alloc_output(&buf, out_len)
+    // Input: [u64 LE id][u64 LE r][u64 LE g][u64 LE b][u64 LE a]  → (uint32, error)
+    #[no_mangle]
+    pub extern "C" fn nativeNewLike(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let color = match read_rgba(&mut input) {
+            Ok(c) => c,
+            Err(e) => return write_u64_err(&e, out_len),
+        };
+        let (w, h) = match IMAGES.lock() {
+            Err(_) => return write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => return write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => (img.width(), img.height()),
+            }
+        };
+        match insert_image(DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(w, h, color))) {
+            Ok(new_id) => write_u64_ok(new_id as u64, out_len),
+            Err(e) => write_u64_err(&e, out_len),
+        }
     }
 
-    fn write_bytes_err(msg: &str, out_len: *mut u32) -> *mut u8 {
-        // [TAG_NIL_REF][TAG_ERROR_STR][u16 len][msg]
-        let mb = msg.as_bytes();
-        let mlen = mb.len().min(0xFFFF) as u16;
-        let mut buf = Vec::with_capacity(4 + mlen as usize);
-        buf.push(TAG_NIL_REF);
-        buf.push(TAG_ERROR_STR);
-        buf.extend_from_slice(&mlen.to_le_bytes());
-        buf.extend_from_slice(&mb[..mlen as usize]);
-        alloc_output(&buf, out_len)
+    // Input: [u64 LE id][u64 LE w][u64 LE h][u64 LE mode]  → (int, int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeThumbnailFit(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let w  = input.read_u64() as u32;
+        let h  = input.read_u64() as u32;
+        let mode = input.read_u64() as i64;
+        if w == 0 || h == 0 {
+            return write_two_ints_err("dimension must be non-zero", out_len);
+        }
+        match IMAGES.lock() {
+            Err(_) => write_two_ints_err("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_two_ints_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    record_pixels(img);
+                    match ops::thumbnail_fit(img, w, h, mode) {
+                        Ok(thumb) => {
+                            *img = thumb;
+                            write_two_ints_ok(img.width() as i64, img.height() as i64, out_len)
+                        }
+                        Err(e) => write_two_ints_err(&e, out_len),
+                    }
+                }
+            }
+        }
     }
 
-    fn write_two_ints_ok(a: i64, b: i64, out_len: *mut u32) -> *mut u8 {
-        // [TAG_VALUE][u64 LE a][TAG_VALUE][u64 LE b][TAG_NIL_ERROR]
-        let mut buf = Vec::with_capacity(19);
-        buf.push(TAG_VALUE); buf.extend_from_slice(&(a as u64).to_le_bytes());
-        buf.push(TAG_VALUE); buf.extend_from_slice(&(b as u64).to_le_bytes());
-        buf.push(TAG_NIL_ERROR);
-        alloc_output(&buf, out_len)
+    #[no_mangle]
+    pub extern "C" fn nativeCancelCurrent(_ptr: *const u8, _len: u32, out_len: *mut u32) -> *mut u8 {
+        CANCEL_EPOCH.fetch_add(1, Ordering::SeqCst);
+        write_empty(out_len)
     }
 
-    fn write_two_ints_err(msg: &str, out_len: *mut u32) -> *mut u8 {
-        // [TAG_VALUE][0][TAG_VALUE][0][TAG_ERROR_STR][u16 len][msg]
-        let mb = msg.as_bytes();
-        let mlen = mb.len().min(0xFFFF) as u16;
-        let mut buf = Vec::with_capacity(19 + mlen as usize);
-        buf.push(TAG_VALUE); buf.extend_from_slice(&0u64.to_le_bytes());
-        buf.push(TAG_VALUE); buf.extend_from_slice(&0u64.to_le_bytes());
-        buf.push(TAG_ERROR_STR);
-        buf.extend_from_slice(&mlen.to_le_bytes());
-        buf.extend_from_slice(&mb[..mlen as usize]);
-        alloc_output(&buf, out_len)
+    // Input: [u64 LE id]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeAutoOrient(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ORIENTATIONS.lock() {
+                    Err(_) => write_error("orientation lock poisoned", out_len),
+                    Ok(mut orientations) => {
+                        if let Some(orientation) = orientations.remove(&id) {
+                            img.apply_orientation(orientation);
+                        }
+                        write_nil_error(out_len)
+                    }
+                },
+            }
+        }
     }
 
-    // ── Image operations ──────────────────────────────────────────────────────
+    // Input: [u32 len][bytes]  → (int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeGuessFormat(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        match ops::guess_format_code(input.read_bytes()) {
+            Ok(code) => write_u64_ok(code as u64, out_len),
+            Err(e) => write_u64_err(&e, out_len),
+        }
+    }
 
-    fn insert_image(img: DynamicImage) -> Result<u32, String> {
-        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
-        IMAGES.lock().map_err(|_| "image lock poisoned".to_string())?.insert(id, img);
-        Ok(id)
+    // Input: [u64 LE id][u64 LE quality][u64 LE thumb_w][u64 LE thumb_h]  → (bytes, error)
+    #[no_mangle]
+    pub extern "C" fn nativeEncodeJPEGWithThumbnail(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let quality = match ops::check_quality(ImageFormat::Jpeg, input.read_u64() as i64) {
+            Ok(q)  => q,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        let thumb_w = match input.read_dim("thumb_w") {
+            Ok(v) => v,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        let thumb_h = match input.read_dim("thumb_h") {
+            Ok(v) => v,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::encode_jpeg_with_thumbnail(img, quality, thumb_w, thumb_h) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                },
+            }
+        }
     }
 
-    fn format_from_ext(ext: &str) -> Result<ImageFormat, String> {
-        match ext.to_lowercase().trim_start_matches('.') {
-            "png"  => Ok(ImageFormat::Png),
-            "jpg" | "jpeg" => Ok(ImageFormat::Jpeg),
-            "gif"  => Ok(ImageFormat::Gif),
-            "bmp"  => Ok(ImageFormat::Bmp),
-            "webp" => Ok(ImageFormat::WebP),
-            other  => Err(format!("unsupported image format: {}", other)),
+    // Input: [u64 LE id]  → (uint64 angle_bits, error)
+    #[no_mangle]
+    pub extern "C" fn nativeEstimateSkew(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_u64_ok(ops::estimate_skew(img).to_bits(), out_len),
+            }
         }
     }
 
-    // ── WASM exports ──────────────────────────────────────────────────────────
+    // Input: [u32 len][bytes]  → (int, int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeDimensionsFromBytes(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        match ops::dimensions_from_bytes(input.read_bytes()) {
+            Ok((w, h)) => write_two_ints_ok(w as i64, h as i64, out_len),
+            Err(e) => write_two_ints_err(&e, out_len),
+        }
+    }
 
-    // Input: [u32 len][data bytes]  → (uint32, error)
+    // Input: [u32 len][bytes]  → (int, int, error)
     #[no_mangle]
-    pub extern "C" fn nativeOpenFromBytes(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+    pub extern "C" fn nativeFrameInfo(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
         let mut input = unsafe { Input::new(ptr, len) };
-        let data = input.read_bytes();
-        match image::load_from_memory(data) {
-            Ok(img) => match insert_image(img) {
-                Ok(id) => write_u64_ok(id as u64, out_len),
-                Err(e) => write_u64_err(&e, out_len),
-            },
-            Err(e) => write_u64_err(&e.to_string(), out_len),
+        match ops::frame_info(input.read_bytes()) {
+            Ok((count, total_ms)) => write_two_ints_ok(count as i64, total_ms as i64, out_len),
+            Err(e) => write_two_ints_err(&e, out_len),
         }
     }
 
-    // Input: [u64 LE w][u64 LE h]  → (uint32, error)
+    // Input: [u64 LE id][u64 LE op]  → (uint32, error)
     #[no_mangle]
-    pub extern "C" fn nativeNewRGBA(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+    pub extern "C" fn nativeReduce(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
         let mut input = unsafe { Input::new(ptr, len) };
-        let w = input.read_u64() as u32;
-        let h = input.read_u64() as u32;
-        match insert_image(DynamicImage::new_rgba8(w, h)) {
-            Ok(id) => write_u64_ok(id as u64, out_len),
+        let id = input.read_u64() as u32;
+        let op = input.read_u64() as i64;
+        let reduced = match IMAGES.lock() {
+            Err(_) => return write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => return write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::reduce(img, op) {
+                    Ok(r)  => r,
+                    Err(e) => return write_u64_err(&e, out_len),
+                },
+            }
+        };
+        match insert_image(reduced) {
+            Ok(new_id) => write_u64_ok(new_id as u64, out_len),
             Err(e) => write_u64_err(&e, out_len),
         }
     }
 
-    // Input: [u64 LE id][u64 LE w][u64 LE h]  → error
+    // Input: [u64 LE id][u64 LE spacing][u64 LE r][u64 LE g][u64 LE b][u64 LE a]  → error
     #[no_mangle]
-    pub extern "C" fn nativeResize(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+    pub extern "C" fn nativeDrawGrid(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
         let mut input = unsafe { Input::new(ptr, len) };
         let id = input.read_u64() as u32;
-        let w  = input.read_u64() as u32;
-        let h  = input.read_u64() as u32;
+        let spacing = match input.read_dim("spacing") {
+            Ok(v) => v,
+            Err(e) => return write_error(&e, out_len),
+        };
+        let color = match read_rgba(&mut input) {
+            Ok(c) => c,
+            Err(e) => return write_error(&e, out_len),
+        };
         match IMAGES.lock() {
             Err(_) => write_error("image lock poisoned", out_len),
             Ok(mut map) => match map.get_mut(&id) {
                 None => write_error(&format!("invalid image id {}", id), out_len),
                 Some(img) => {
-                    *img = img.resize_exact(w, h, image::imageops::FilterType::Lanczos3);
+                    *img = ops::draw_grid(img, spacing, color);
                     write_nil_error(out_len)
                 }
             }
         }
     }
 
-    // Input: [u64 LE id][u64 LE w][u64 LE h]  → error
+    // Input: [u32 len][gif bytes][u64 LE index]  → (uint32, error)
     #[no_mangle]
-    pub extern "C" fn nativeThumbnail(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+    pub extern "C" fn nativeExtractFrame(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let data = input.read_bytes();
+        let index = input.read_u64();
+        match ops::extract_gif_frame(data, index).and_then(insert_image) {
+            Ok(id) => write_u64_ok(id as u64, out_len),
+            Err(e) => write_u64_err(&e, out_len),
+        }
+    }
+
+    // Input: [u32 len][u32 LE ids][u64 LE delay_ms]  → ([]byte, error)
+    #[no_mangle]
+    pub extern "C" fn nativeEncodeGIF(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let ids = match ops::unpack_ids(input.read_bytes()) {
+            Ok(ids) => ids,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        let delay_ms = match input.read_u32("delay_ms") {
+            Ok(v) => v,
+            Err(e) => return write_bytes_err(&e, out_len),
+        };
+        match IMAGES.lock() {
+            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Ok(map) => {
+                let mut images = Vec::with_capacity(ids.len());
+                for id in ids {
+                    match map.get(&id) {
+                        None => return write_bytes_err(&format!("invalid image id {}", id), out_len),
+                        Some(img) => images.push(img),
+                    }
+                }
+                match ops::encode_gif_frames(&images, delay_ms) {
+                    Ok(b)  => write_bytes_ok(&b, out_len),
+                    Err(e) => write_bytes_err(&e, out_len),
+                }
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → (int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeEffectiveBitDepth(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
         let mut input = unsafe { Input::new(ptr, len) };
         let id = input.read_u64() as u32;
-        let w  = input.read_u64() as u32;
-        let h  = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_u64_ok(ops::effective_bit_depth(img) as u64, out_len),
+            }
+        }
+    }
+
+    // Input: [u64 LE id]  → (u64 rgba, error)
+    #[no_mangle]
+    pub extern "C" fn nativeAverageColor(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        match IMAGES.lock() {
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match map.get(&id) {
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_u64_ok(ops::pack_rgba(ops::average_color(img)), out_len),
+            }
+        }
+    }
+
+    // Input: [u64 LE id][u64 LE radius]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeErode(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        morphology(ptr, len, out_len, false)
+    }
+
+    // Input: [u64 LE id][u64 LE radius]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeDilate(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        morphology(ptr, len, out_len, true)
+    }
+
+    fn morphology(ptr: *const u8, len: u32, out_len: *mut u32, dilate: bool) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id = input.read_u64() as u32;
+        let radius = match input.read_u32("radius") {
+            Ok(v) => v,
+            Err(e) => return write_error(&e, out_len),
+        };
         match IMAGES.lock() {
             Err(_) => write_error("image lock poisoned", out_len),
             Ok(mut map) => match map.get_mut(&id) {
                 None => write_error(&format!("invalid image id {}", id), out_len),
                 Some(img) => {
-                    *img = img.thumbnail(w, h);
+                    record_pixels(img);
+                    *img = ops::morphology(img, radius, dilate);
                     write_nil_error(out_len)
                 }
             }
         }
     }
 
-    // Input: [u64 LE id][u32 LE len][ext bytes]  → ([]byte, error)
+    // Input: [u64 LE id]  → (u64 hash, error)
     #[no_mangle]
-    pub extern "C" fn nativeSaveToBytes(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+    pub extern "C" fn nativePerceptualHash(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
         let mut input = unsafe { Input::new(ptr, len) };
-        let id  = input.read_u64() as u32;
-        let ext = input.read_str().to_string();
-        let fmt = match format_from_ext(&ext) {
-            Ok(f)  => f,
-            Err(e) => return write_bytes_err(&e, out_len),
-        };
+        let id = input.read_u64() as u32;
         match IMAGES.lock() {
-            Err(_) => write_bytes_err("image lock poisoned", out_len),
+            Err(_) => write_u64_err("image lock poisoned", out_len),
             Ok(map) => match map.get(&id) {
-                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
-                Some(img) => {
-                    let mut out = Cursor::new(Vec::new());
-                    match img.write_to(&mut out, fmt) {
-                        Ok(())  => write_bytes_ok(&out.into_inner(), out_len),
-                        Err(e) => write_bytes_err(&e.to_string(), out_len),
-                    }
-                }
+                None => write_u64_err(&format!("invalid image id {}", id), out_len),
+                Some(img) => write_u64_ok(ops::average_hash(img), out_len),
             }
         }
     }
 
-    // Input: [u64 LE id]  → ([]byte, error)
+    // Input: (none)  → error
     #[no_mangle]
-    pub extern "C" fn nativeEncodePNG(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+    pub extern "C" fn nativeShrinkToFit(_ptr: *const u8, _len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut map = match IMAGES.lock() {
+            Err(_) => return write_error("image lock poisoned", out_len),
+            Ok(map) => map,
+        };
+        map.shrink_to_fit();
+        // Side tables are keyed by image id, so they shrink along with IMAGES.
+        if let Ok(mut sel) = SELECTIONS.lock() {
+            sel.shrink_to_fit();
+        }
+        if let Ok(mut history) = HISTORY.lock() {
+            history.shrink_to_fit();
+        }
+        if let Ok(mut spaces) = COLORSPACES.lock() {
+            spaces.shrink_to_fit();
+        }
+        if let Ok(mut orientations) = ORIENTATIONS.lock() {
+            orientations.shrink_to_fit();
+        }
+        write_nil_error(out_len)
+    }
+
+    // Input: [u64 LE id][u32 len][colormap name]  → error
+    #[no_mangle]
+    pub extern "C" fn nativeApplyColormap(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
         let mut input = unsafe { Input::new(ptr, len) };
         let id = input.read_u64() as u32;
+        let colormap = input.read_str().to_string();
         match IMAGES.lock() {
-            Err(_) => write_bytes_err("image lock poisoned", out_len),
-            Ok(map) => match map.get(&id) {
-                None => write_bytes_err(&format!("invalid image id {}", id), out_len),
-                Some(img) => {
-                    let mut out = Cursor::new(Vec::new());
-                    match img.write_to(&mut out, ImageFormat::Png) {
-                        Ok(())  => write_bytes_ok(&out.into_inner(), out_len),
-                        Err(e) => write_bytes_err(&e.to_string(), out_len),
+            Err(_) => write_error("image lock poisoned", out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => match ops::apply_colormap(img, &colormap) {
+                    Ok(mapped) => {
+                        *img = mapped;
+                        write_nil_error(out_len)
                     }
+                    Err(e) => write_error(&e, out_len),
                 }
             }
         }
     }
 
-    // Input: [u64 LE id]  → (int, int, error)
+    // Input: [u64 LE hash_a][u64 LE hash_b]  → (int, error)
     #[no_mangle]
-    pub extern "C" fn nativeSize(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+    pub extern "C" fn nativeHashDistance(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
         let mut input = unsafe { Input::new(ptr, len) };
-        let id = input.read_u64() as u32;
+        let hash_a = input.read_u64();
+        let hash_b = input.read_u64();
+        write_u64_ok((hash_a ^ hash_b).count_ones() as u64, out_len)
+    }
+
+    // Input: [u64 LE id_a][u64 LE id_b][u64 LE tolerance]  → (int, error)
+    #[no_mangle]
+    pub extern "C" fn nativeCompare(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+        let mut input = unsafe { Input::new(ptr, len) };
+        let id_a = input.read_u64() as u32;
+        let id_b = input.read_u64() as u32;
+        let tolerance = match input.read_u8("tolerance") {
+            Ok(v) => v,
+            Err(e) => return write_u64_err(&e, out_len),
+        };
         match IMAGES.lock() {
-            Err(_) => write_two_ints_err("image lock poisoned", out_len),
-            Ok(map) => match map.get(&id) {
-                None => write_two_ints_err(&format!("invalid image id {}", id), out_len),
-                Some(img) => write_two_ints_ok(img.width() as i64, img.height() as i64, out_len),
+            Err(_) => write_u64_err("image lock poisoned", out_len),
+            Ok(map) => match (map.get(&id_a), map.get(&id_b)) {
+                (None, _) => write_u64_err(&format!("invalid image id {}", id_a), out_len),
+                (_, None) => write_u64_err(&format!("invalid image id {}", id_b), out_len),
+                (Some(a), Some(b)) => {
+                    let (a, b) = (a.to_rgba8(), b.to_rgba8());
+                    if a.dimensions() != b.dimensions() {
+                        return write_u64_err("image dimensions differ", out_len);
+                    }
+                    write_u64_ok(ops::count_differing(&a, &b, tolerance), out_len)
+                }
             }
         }
     }
 
-    // Input: [u64 LE id]  → error
+    // Input: [u64 LE id][u64 LE level]  → error
     #[no_mangle]
-    pub extern "C" fn nativeClose(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
+    pub extern "C" fn nativeThresholdAlpha(ptr: *const u8, len: u32, out_len: *mut u32) -> *mut u8 {
         let mut input = unsafe { Input::new(ptr, len) };
         let id = input.read_u64() as u32;
+        let level = match input.read_u8("level") {
+            Ok(v) => v,
+            Err(e) => return write_error(&e, out_len),
+        };
         match IMAGES.lock() {
             Err(_) => write_error("image lock poisoned", out_len),
-            Ok(mut map) => match map.remove(&id) {
-                None    => write_error(&format!("invalid image id {}", id), out_len),
-                Some(_) => write_nil_error(out_len),
+            Ok(mut map) => match map.get_mut(&id) {
+                None => write_error(&format!("invalid image id {}", id), out_len),
+                Some(img) => {
+                    *img = ops::threshold_alpha(img, level);
+                    write_nil_error(out_len)
+                }
             }
         }
     }
@@ -0,0 +1,2486 @@
+// Handle-free image operations shared by the native and standalone WASM
+// entry points. Everything here works on a `DynamicImage` directly; id
+// lookup and locking stay with the callers.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType as PngFilterType, PngDecoder, PngEncoder};
+use image::codecs::webp::WebPDecoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
+use image::{AnimationDecoder, ColorType, ImageDecoder, DynamicImage, Frame, GrayAlphaImage, GrayImage, ImageFormat, LumaA, Rgba, RgbaImage};
+use rayon::prelude::*;
+
+// Thumbnails to cover the box, then center-crops to exactly width×height.
+pub(crate) fn thumbnail_exact(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+) -> Result<DynamicImage, String> {
+    let (src_w, src_h) = (img.width(), img.height());
+    if src_w == 0 || src_h == 0 {
+        return Err("image has zero dimensions".to_string());
+    }
+    let scale = f64::max(
+        width as f64 / src_w as f64,
+        height as f64 / src_h as f64,
+    );
+    let cover_w = ((src_w as f64 * scale).ceil() as u32).max(width);
+    let cover_h = ((src_h as f64 * scale).ceil() as u32).max(height);
+    let cover = img.thumbnail_exact(cover_w, cover_h);
+    let x = (cover_w - width) / 2;
+    let y = (cover_h - height) / 2;
+    Ok(cover.crop_imm(x, y, width, height))
+}
+
+// Pixelmatch-style comparison: counts pixels whose YIQ distance exceeds the
+// threshold, skipping those that look like anti-aliasing in either image.
+pub(crate) fn compare_aa_tolerant(a: &RgbaImage, b: &RgbaImage, threshold: f64) -> u64 {
+    let max_delta = 35215.0 * threshold * threshold;
+    let (w, h) = a.dimensions();
+    let mut diff = 0;
+    for y in 0..h {
+        for x in 0..w {
+            let delta = color_delta(a.get_pixel(x, y).0, b.get_pixel(x, y).0, false);
+            if delta.abs() > max_delta
+                && !is_antialiased(a, b, x, y)
+                && !is_antialiased(b, a, x, y)
+            {
+                diff += 1;
+            }
+        }
+    }
+    diff
+}
+
+fn blend_white(c: f64, a: f64) -> f64 {
+    255.0 + (c - 255.0) * a
+}
+
+fn color_delta(p1: [u8; 4], p2: [u8; 4], y_only: bool) -> f64 {
+    if p1 == p2 {
+        return 0.0;
+    }
+    let flatten = |p: [u8; 4]| {
+        let (r, g, b) = (p[0] as f64, p[1] as f64, p[2] as f64);
+        if p[3] < 255 {
+            let a = p[3] as f64 / 255.0;
+            (blend_white(r, a), blend_white(g, a), blend_white(b, a))
+        } else {
+            (r, g, b)
+        }
+    };
+    let (r1, g1, b1) = flatten(p1);
+    let (r2, g2, b2) = flatten(p2);
+    let y1 = r1 * 0.29889531 + g1 * 0.58662247 + b1 * 0.11448223;
+    let y2 = r2 * 0.29889531 + g2 * 0.58662247 + b2 * 0.11448223;
+    let y = y1 - y2;
+    if y_only {
+        return y;
+    }
+    let i = (r1 * 0.59597799 - g1 * 0.27417610 - b1 * 0.32180189)
+        - (r2 * 0.59597799 - g2 * 0.27417610 - b2 * 0.32180189);
+    let q = (r1 * 0.21147017 - g1 * 0.52261711 + b1 * 0.31114694)
+        - (r2 * 0.21147017 - g2 * 0.52261711 + b2 * 0.31114694);
+    let delta = 0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q;
+    if y1 > y2 {
+        -delta
+    } else {
+        delta
+    }
+}
+
+fn neighborhood(w: u32, h: u32, x: u32, y: u32) -> (u32, u32, u32, u32) {
+    (
+        x.saturating_sub(1),
+        y.saturating_sub(1),
+        (x + 1).min(w - 1),
+        (y + 1).min(h - 1),
+    )
+}
+
+fn is_antialiased(img: &RgbaImage, other: &RgbaImage, x1: u32, y1: u32) -> bool {
+    let (w, h) = img.dimensions();
+    let (x0, y0, x2, y2) = neighborhood(w, h, x1, y1);
+    let center = img.get_pixel(x1, y1).0;
+    let mut zeroes = u32::from(x1 == x0 || x1 == x2 || y1 == y0 || y1 == y2);
+    let (mut min, mut max) = (0.0, 0.0);
+    let (mut min_at, mut max_at) = ((0, 0), (0, 0));
+    for x in x0..=x2 {
+        for y in y0..=y2 {
+            if x == x1 && y == y1 {
+                continue;
+            }
+            let delta = color_delta(center, img.get_pixel(x, y).0, true);
+            if delta == 0.0 {
+                zeroes += 1;
+                if zeroes > 2 {
+                    return false;
+                }
+            } else if delta < min {
+                min = delta;
+                min_at = (x, y);
+            } else if delta > max {
+                max = delta;
+                max_at = (x, y);
+            }
+        }
+    }
+    if min == 0.0 || max == 0.0 {
+        return false;
+    }
+    (has_many_siblings(img, min_at.0, min_at.1) && has_many_siblings(other, min_at.0, min_at.1))
+        || (has_many_siblings(img, max_at.0, max_at.1)
+            && has_many_siblings(other, max_at.0, max_at.1))
+}
+
+fn has_many_siblings(img: &RgbaImage, x1: u32, y1: u32) -> bool {
+    let (w, h) = img.dimensions();
+    let (x0, y0, x2, y2) = neighborhood(w, h, x1, y1);
+    let center = img.get_pixel(x1, y1);
+    let mut zeroes = u32::from(x1 == x0 || x1 == x2 || y1 == y0 || y1 == y2);
+    for x in x0..=x2 {
+        for y in y0..=y2 {
+            if x == x1 && y == y1 {
+                continue;
+            }
+            if img.get_pixel(x, y) == center {
+                zeroes += 1;
+            }
+            if zeroes > 2 {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+// Decodes only frame 0 of an animated GIF/WebP; anything else is decoded
+// whole, which for stills is the same thing.
+pub(crate) fn decode_first_frame(data: &[u8]) -> Result<DynamicImage, String> {
+    let frame = match image::guess_format(data).map_err(|e| e.to_string())? {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+            decoder.into_frames().next()
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+            if !decoder.has_animation() {
+                return image::load_from_memory(data).map_err(|e| e.to_string());
+            }
+            decoder.into_frames().next()
+        }
+        _ => return image::load_from_memory(data).map_err(|e| e.to_string()),
+    };
+    let frame = frame
+        .ok_or_else(|| "animation has no frames".to_string())?
+        .map_err(|e| e.to_string())?;
+    Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+}
+
+// Rotates JPEG bytes in the DCT domain via libjpeg-turbo, so no generation
+// loss. Edge blocks that don't fill a whole MCU are left as libjpeg leaves them.
+pub(crate) fn jpeg_lossless_rotate(data: &[u8], degrees: i64) -> Result<Vec<u8>, String> {
+    if degrees % 90 != 0 {
+        return Err(format!("rotation must be a multiple of 90 degrees: {degrees}"));
+    }
+    jpeg_transform(data, degrees.rem_euclid(360))
+}
+
+#[cfg(feature = "jpeg-transform")]
+fn jpeg_transform(data: &[u8], degrees: i64) -> Result<Vec<u8>, String> {
+    use turbojpeg::{Transform, TransformOp};
+
+    let op = match degrees {
+        0 => return Ok(data.to_vec()),
+        90 => TransformOp::Rot90,
+        180 => TransformOp::Rot180,
+        _ => TransformOp::Rot270,
+    };
+    let out = turbojpeg::transform(&Transform::op(op), data).map_err(|e| e.to_string())?;
+    Ok(out.to_vec())
+}
+
+#[cfg(not(feature = "jpeg-transform"))]
+fn jpeg_transform(_data: &[u8], _degrees: i64) -> Result<Vec<u8>, String> {
+    Err("lossless JPEG rotation requires the jpeg-transform feature".to_string())
+}
+
+// Validates a per-format quality knob: JPEG takes 1..=100, PNG takes a
+// compression level 0=fast, 1=default, 2=best.
+pub(crate) fn check_quality(fmt: ImageFormat, quality: i64) -> Result<u8, String> {
+    let range = match fmt {
+        ImageFormat::Jpeg => 1..=100,
+        ImageFormat::Png => 0..=2,
+        other => return Err(format!("quality is not configurable for {:?}", other)),
+    };
+    if !range.contains(&quality) {
+        return Err(format!(
+            "{:?} quality must be within {}..={}: {}",
+            fmt,
+            range.start(),
+            range.end(),
+            quality
+        ));
+    }
+    Ok(quality as u8)
+}
+
+// Initial output buffer size for encoding `img` as `fmt`, so large encodes
+// don't grow the Vec repeatedly: the raw pixel size for lossless formats and
+// a fraction of it for lossy ones.
+pub(crate) fn encode_capacity_hint(img: &DynamicImage, fmt: ImageFormat) -> usize {
+    let raw = img.width() as usize * img.height() as usize * img.color().bytes_per_pixel() as usize;
+    match fmt {
+        ImageFormat::Jpeg => raw / 8,
+        _ => raw,
+    }
+}
+
+// `quality` must already have passed `check_quality` for `fmt`.
+pub(crate) fn encode_with_quality(
+    img: &DynamicImage,
+    fmt: ImageFormat,
+    quality: u8,
+) -> Result<Vec<u8>, String> {
+    match fmt {
+        ImageFormat::Jpeg => encode_jpeg(img, quality),
+        ImageFormat::Png => encode_png_with_level(img, quality),
+        other => Err(format!("quality is not configurable for {:?}", other)),
+    }
+}
+
+// JpegEncoder's own default.
+pub(crate) const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+// Encoding used when no quality is configured. Every JPEG goes through
+// `encode_jpeg`, the same path as a configured quality, so gray sources stay
+// single-component and alpha is dropped instead of failing.
+pub(crate) fn encode_default(img: &DynamicImage, fmt: ImageFormat) -> Result<Vec<u8>, String> {
+    if fmt == ImageFormat::Jpeg {
+        return encode_jpeg(img, DEFAULT_JPEG_QUALITY);
+    }
+    let mut out = Cursor::new(Vec::with_capacity(encode_capacity_hint(img, fmt)));
+    img.write_to(&mut out, fmt).map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+fn is_gray(color: ColorType) -> bool {
+    matches!(color, ColorType::L8 | ColorType::La8 | ColorType::L16 | ColorType::La16)
+}
+
+// JPEG has no alpha, so color sources are flattened to RGB and gray ones
+// kept single-channel.
+pub(crate) fn encode_jpeg(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, String> {
+    let mut out = Vec::with_capacity(encode_capacity_hint(img, ImageFormat::Jpeg));
+    let mut encoder = JpegEncoder::new_with_quality(&mut out, quality);
+    let result = if is_gray(img.color()) {
+        encoder.encode_image(&img.to_luma8())
+    } else {
+        encoder.encode_image(&img.to_rgb8())
+    };
+    result.map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+pub(crate) fn encode_png_with_level(img: &DynamicImage, level: u8) -> Result<Vec<u8>, String> {
+    let compression = match level {
+        0 => CompressionType::Fast,
+        1 => CompressionType::Default,
+        _ => CompressionType::Best,
+    };
+    let mut out = Vec::with_capacity(encode_capacity_hint(img, ImageFormat::Png));
+    let encoder = PngEncoder::new_with_quality(&mut out, compression, PngFilterType::Adaptive);
+    img.write_with_encoder(encoder).map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+// Aspect-fits into width×height and pads the uncovered bands with `color`.
+pub(crate) fn resize_pad(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    color: Rgba<u8>,
+) -> Result<DynamicImage, String> {
+    if img.width() == 0 || img.height() == 0 {
+        return Err("image has zero dimensions".to_string());
+    }
+    let fitted = img.resize(width, height, FilterType::Lanczos3).to_rgba8();
+    let mut canvas = RgbaImage::from_pixel(width, height, color);
+    let x = (width - fitted.width()) / 2;
+    let y = (height - fitted.height()) / 2;
+    image::imageops::replace(&mut canvas, &fitted, x as i64, y as i64);
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+fn read_u32_le(data: &[u8], pos: usize) -> Option<u32> {
+    let bytes = data.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+// Packed blob list: [u32 count] then per item [u32 len][bytes].
+pub(crate) fn unpack_blobs(data: &[u8]) -> Result<Vec<&[u8]>, String> {
+    let count = read_u32_le(data, 0).ok_or("blob list is truncated")? as usize;
+    let mut pos = 4;
+    let mut blobs = Vec::with_capacity(count.min(data.len() / 4));
+    for i in 0..count {
+        let len = read_u32_le(data, pos).ok_or_else(|| format!("blob {i} is truncated"))? as usize;
+        pos += 4;
+        let blob = data
+            .get(pos..pos + len)
+            .ok_or_else(|| format!("blob {i} is truncated"))?;
+        blobs.push(blob);
+        pos += len;
+    }
+    Ok(blobs)
+}
+
+pub(crate) const CANCELLED: &str = "operation cancelled";
+
+// Snapshot of the cancel epoch taken when an op starts. CancelCurrent bumps
+// the epoch, so every op running at that moment sees a change and stops,
+// while a request made with nothing running is absorbed by the next op's
+// snapshot. Checks only read, so all parallel workers observe the request.
+pub(crate) struct CancelToken<'a> {
+    epoch: &'a AtomicU64,
+    start: u64,
+}
+
+impl<'a> CancelToken<'a> {
+    pub(crate) fn new(epoch: &'a AtomicU64) -> Self {
+        CancelToken { epoch, start: epoch.load(Ordering::SeqCst) }
+    }
+
+    // Long loops call this between units of work and bail out with `CANCELLED`.
+    pub(crate) fn check(&self) -> Result<(), String> {
+        if self.epoch.load(Ordering::Relaxed) != self.start {
+            return Err(CANCELLED.to_string());
+        }
+        Ok(())
+    }
+}
+
+// Copies `src` into `dst` at (x, y) without leaving `dst`'s color type.
+pub(crate) fn paste_same_type(dst: &mut DynamicImage, src: &DynamicImage, x: u32, y: u32) -> Result<(), String> {
+    use image::GenericImage;
+    macro_rules! paste {
+        ($($variant:ident),*) => {
+            match (dst, src) {
+                $((DynamicImage::$variant(d), DynamicImage::$variant(s)) => d.copy_from(s, x, y).map_err(|e| e.to_string()),)*
+                _ => Err("pasted image has a different color type".to_string()),
+            }
+        };
+    }
+    paste!(
+        ImageLuma8, ImageLumaA8, ImageRgb8, ImageRgba8, ImageLuma16, ImageLumaA16, ImageRgb16, ImageRgba16,
+        ImageRgb32F, ImageRgba32F
+    )
+}
+
+// Runs a neighborhood filter over bands of rows so long convolutions can be
+// cancelled between bands. Each band is cropped with `margin` rows of context
+// on both sides, so seams match a whole-image pass as long as the margin
+// covers the kernel. `filter` must keep the color type.
+pub(crate) fn filter_in_bands(
+    img: &DynamicImage,
+    margin: u32,
+    cancel: &CancelToken,
+    filter: impl Fn(&DynamicImage) -> DynamicImage,
+) -> Result<DynamicImage, String> {
+    let (w, h) = (img.width(), img.height());
+    // Context beyond the image height is never read, so a huge margin just
+    // means one band.
+    let margin = margin.min(h);
+    // Bands at least twice the margin keep the overlap under 2x the work.
+    let band_rows = margin.saturating_mul(2).max(64);
+    let mut out = img.clone();
+    let mut y = 0;
+    while y < h {
+        cancel.check()?;
+        let rows = band_rows.min(h - y);
+        let top = y.saturating_sub(margin);
+        let bottom = y.saturating_add(rows).saturating_add(margin).min(h);
+        let filtered = filter(&img.crop_imm(0, top, w, bottom - top));
+        paste_same_type(&mut out, &filtered.crop_imm(0, y - top, w, rows), 0, y)?;
+        y += rows;
+    }
+    Ok(out)
+}
+
+// Rows of context a Gaussian of `sigma` needs; its kernel stays within 3 sigma.
+pub(crate) fn blur_margin(sigma: f32) -> u32 {
+    ((sigma as f64 * 4.0).ceil() as u32).saturating_add(2)
+}
+
+pub(crate) fn blur(img: &DynamicImage, sigma: f32, cancel: &CancelToken) -> Result<DynamicImage, String> {
+    filter_in_bands(img, blur_margin(sigma), cancel, |band| band.blur(sigma))
+}
+
+// Decodes and thumbnails every blob in parallel. Items that fail to decode
+// come back as `None`.
+pub(crate) fn batch_thumbnail(
+    items: &[&[u8]],
+    max_w: u32,
+    max_h: u32,
+    cancel: &CancelToken,
+) -> Result<Vec<Option<RgbaImage>>, String> {
+    items
+        .par_iter()
+        .map(|data| {
+            cancel.check()?;
+            Ok(image::load_from_memory(data)
+                .ok()
+                .map(|img| img.thumbnail(max_w, max_h).to_rgba8()))
+        })
+        .collect()
+}
+
+// Packed result list: [u32 count] then per item
+// [u8 ok][u32 width][u32 height][u32 len][rgba bytes]; failed items are
+// ok=0 with an empty buffer.
+pub(crate) fn pack_thumbnails(results: &[Option<RgbaImage>]) -> Vec<u8> {
+    let payload: usize = results.iter().flatten().map(|t| t.as_raw().len()).sum();
+    let mut out = Vec::with_capacity(4 + results.len() * 13 + payload);
+    out.extend_from_slice(&(results.len() as u32).to_le_bytes());
+    for result in results {
+        match result {
+            Some(thumb) => {
+                out.push(1);
+                out.extend_from_slice(&thumb.width().to_le_bytes());
+                out.extend_from_slice(&thumb.height().to_le_bytes());
+                out.extend_from_slice(&(thumb.as_raw().len() as u32).to_le_bytes());
+                out.extend_from_slice(thumb.as_raw());
+            }
+            None => {
+                out.push(0);
+                out.extend_from_slice(&[0u8; 12]);
+            }
+        }
+    }
+    out
+}
+
+// RGBA8 samples as little-endian f32, each divided by `scale`.
+pub(crate) fn to_f32_bytes(img: &DynamicImage, scale: f32) -> Vec<u8> {
+    let rgba = img.to_rgba8();
+    let mut out = Vec::with_capacity(rgba.as_raw().len() * 4);
+    for &v in rgba.as_raw() {
+        out.extend_from_slice(&(v as f32 / scale).to_le_bytes());
+    }
+    out
+}
+
+// Packed little-endian f64 list, as produced by the Vo side.
+pub(crate) fn unpack_f64s(data: &[u8]) -> Result<Vec<f64>, String> {
+    if data.len() % 8 != 0 {
+        return Err("float list length must be a multiple of 8".to_string());
+    }
+    Ok(data
+        .chunks_exact(8)
+        .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+        .collect())
+}
+
+// Per-channel (v/255 - mean)/std as little-endian f32. Three parameters
+// produce RGB output, four produce RGBA.
+pub(crate) fn standardize(img: &DynamicImage, means: &[f64], stds: &[f64]) -> Result<Vec<u8>, String> {
+    if means.len() != stds.len() || !(3..=4).contains(&means.len()) {
+        return Err("means and stds must both have 3 or 4 entries".to_string());
+    }
+    if stds.iter().any(|&s| s == 0.0 || !s.is_finite()) {
+        return Err("stds must be non-zero".to_string());
+    }
+    let channels = means.len();
+    let rgba = img.to_rgba8();
+    let mut out = Vec::with_capacity(rgba.as_raw().len() / 4 * channels * 4);
+    for px in rgba.pixels() {
+        for c in 0..channels {
+            let v = (px.0[c] as f64 / 255.0 - means[c]) / stds[c];
+            out.extend_from_slice(&(v as f32).to_le_bytes());
+        }
+    }
+    Ok(out)
+}
+
+// Crops away fully transparent margins.
+pub(crate) fn trim_alpha(img: &DynamicImage) -> Result<DynamicImage, String> {
+    if !img.color().has_alpha() {
+        return Err("image has no alpha channel".to_string());
+    }
+    let rgba = img.to_rgba8();
+    let (mut x0, mut y0, mut x1, mut y1) = (u32::MAX, u32::MAX, 0, 0);
+    for (x, y, px) in rgba.enumerate_pixels() {
+        if px.0[3] != 0 {
+            x0 = x0.min(x);
+            y0 = y0.min(y);
+            x1 = x1.max(x);
+            y1 = y1.max(y);
+        }
+    }
+    if x0 == u32::MAX {
+        return Err("image is fully transparent".to_string());
+    }
+    Ok(img.crop_imm(x0, y0, x1 - x0 + 1, y1 - y0 + 1))
+}
+
+// Packs each pixel into 16-bit RGB565 in the requested byte order.
+pub(crate) fn to_rgb565_bytes(img: &DynamicImage, big_endian: bool) -> Vec<u8> {
+    let rgb = img.to_rgb8();
+    let mut out = Vec::with_capacity(rgb.as_raw().len() / 3 * 2);
+    for px in rgb.pixels() {
+        let [r, g, b] = px.0;
+        let v = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+        if big_endian {
+            out.extend_from_slice(&v.to_be_bytes());
+        } else {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+    out
+}
+
+// 1-bit bitmap, 8 pixels per byte MSB-first, rows padded to whole bytes. A
+// set bit is a dark pixel (luma below `threshold`), which is what thermal
+// printers expect. Returns the packed rows and the row stride in bytes.
+pub(crate) fn to_mono_bitmap(img: &DynamicImage, threshold: u8, dither: bool) -> (Vec<u8>, usize) {
+    let luma = img.to_luma8();
+    let (w, h) = (luma.width() as usize, luma.height() as usize);
+    let stride = w.div_ceil(8);
+    let mut out = vec![0u8; stride * h];
+    let mut levels: Vec<f32> = luma.as_raw().iter().map(|&v| v as f32).collect();
+    for y in 0..h {
+        for x in 0..w {
+            let old = levels[y * w + x];
+            let dark = old < threshold as f32;
+            if dark {
+                out[y * stride + x / 8] |= 0x80 >> (x % 8);
+            }
+            if dither {
+                let err = old - if dark { 0.0 } else { 255.0 };
+                let mut spread = |dx: isize, dy: usize, weight: f32| {
+                    let nx = x as isize + dx;
+                    if nx >= 0 && (nx as usize) < w && y + dy < h {
+                        levels[(y + dy) * w + nx as usize] += err * weight;
+                    }
+                };
+                spread(1, 0, 7.0 / 16.0);
+                spread(-1, 1, 3.0 / 16.0);
+                spread(0, 1, 5.0 / 16.0);
+                spread(1, 1, 1.0 / 16.0);
+            }
+        }
+    }
+    (out, stride)
+}
+
+// Linearly interpolates every channel toward `target`; amount is in 0..=1.
+pub(crate) fn fade_to_color(img: &DynamicImage, target: Rgba<u8>, amount: f64) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for px in rgba.pixels_mut() {
+        for c in 0..4 {
+            let from = px.0[c] as f64;
+            px.0[c] = (from + (target.0[c] as f64 - from) * amount).round() as u8;
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+// BlurHash only keeps a handful of low frequencies, so encoding from a small
+// thumbnail gives the same hash at a fraction of the cost.
+pub(crate) fn blurhash(img: &DynamicImage, x_components: u32, y_components: u32) -> Result<String, String> {
+    if !(1..=9).contains(&x_components) || !(1..=9).contains(&y_components) {
+        return Err(format!(
+            "blurhash components must be within 1..=9: {x_components}x{y_components}"
+        ));
+    }
+    if img.width() == 0 || img.height() == 0 {
+        return Err("image has zero dimensions".to_string());
+    }
+    let small = img.thumbnail(64, 64).to_rgba8();
+    blurhash::encode(x_components, y_components, small.width(), small.height(), small.as_raw())
+        .map_err(|e| e.to_string())
+}
+
+pub(crate) fn from_blurhash(hash: &str, width: u32, height: u32, punch: f64) -> Result<DynamicImage, String> {
+    if width == 0 || height == 0 {
+        return Err("blurhash output size must be non-zero".to_string());
+    }
+    let pixels = blurhash::decode(hash, width, height, punch as f32)
+        .map_err(|e| format!("invalid blurhash: {e}"))?;
+    RgbaImage::from_raw(width, height, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| "blurhash decoder returned a short buffer".to_string())
+}
+
+// Largest aspect-preserving size with width*height <= max_pixels; None when
+// the image already fits.
+pub(crate) fn pixel_budget_dimensions(width: u32, height: u32, max_pixels: u64) -> Option<(u32, u32)> {
+    let area = width as u64 * height as u64;
+    if area <= max_pixels {
+        return None;
+    }
+    let scale = (max_pixels as f64 / area as f64).sqrt();
+    let mut w = ((width as f64 * scale).floor() as u32).max(1);
+    let mut h = ((height as f64 * scale).floor() as u32).max(1);
+    // Rounding can land a pixel over budget; shave the longer side.
+    while w as u64 * h as u64 > max_pixels && (w > 1 || h > 1) {
+        if w >= h {
+            w -= 1;
+        } else {
+            h -= 1;
+        }
+    }
+    Some((w, h))
+}
+
+// Replaces RGB with a single color, keeping each pixel's alpha.
+pub(crate) fn tint(img: &DynamicImage, r: u8, g: u8, b: u8) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for px in rgba.pixels_mut() {
+        px.0 = [r, g, b, px.0[3]];
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+// OpenEXR and Radiance HDR decode to float buffers; keep them as Rgb32F so
+// later tone mapping sees one layout.
+pub(crate) fn normalize_hdr(img: DynamicImage, fmt: Option<ImageFormat>) -> DynamicImage {
+    match fmt {
+        Some(ImageFormat::OpenExr) | Some(ImageFormat::Hdr) => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        _ => img,
+    }
+}
+
+// Exposure tone curve 1 - e^(-v*exposure) followed by gamma encoding,
+// producing an 8-bit RGB image.
+pub(crate) fn tone_map(img: &DynamicImage, exposure: f64, gamma: f64) -> DynamicImage {
+    let src = img.to_rgb32f();
+    let inv_gamma = 1.0 / gamma as f32;
+    let exposure = exposure as f32;
+    let out = image::RgbImage::from_fn(src.width(), src.height(), |x, y| {
+        let px = src.get_pixel(x, y).0;
+        image::Rgb(px.map(|v| {
+            let mapped = 1.0 - (-v.max(0.0) * exposure).exp();
+            (mapped.powf(inv_gamma) * 255.0).round().clamp(0.0, 255.0) as u8
+        }))
+    });
+    DynamicImage::ImageRgb8(out)
+}
+
+// Encodes a PNG with 16 bits per channel. 8-bit and float sources are
+// widened only when `upconvert` is set.
+pub(crate) fn encode_png16(img: &DynamicImage, upconvert: bool) -> Result<Vec<u8>, String> {
+    let wide;
+    let src = match img.color() {
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => img,
+        _ if !upconvert => return Err("image is not 16-bit".to_string()),
+        ColorType::L8 => {
+            wide = DynamicImage::ImageLuma16(img.to_luma16());
+            &wide
+        }
+        ColorType::La8 => {
+            wide = DynamicImage::ImageLumaA16(img.to_luma_alpha16());
+            &wide
+        }
+        color if color.has_alpha() => {
+            wide = DynamicImage::ImageRgba16(img.to_rgba16());
+            &wide
+        }
+        _ => {
+            wide = DynamicImage::ImageRgb16(img.to_rgb16());
+            &wide
+        }
+    };
+    let mut out = Cursor::new(Vec::new());
+    src.write_to(&mut out, ImageFormat::Png).map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+// Distinct RGBA values, capped: returns limit+1 as soon as the count exceeds
+// `limit` so huge photos don't build a huge set.
+pub(crate) fn unique_colors(img: &DynamicImage, limit: usize) -> usize {
+    let rgba = img.to_rgba8();
+    let mut seen = HashSet::new();
+    for px in rgba.pixels() {
+        if seen.insert(px.0) && seen.len() > limit {
+            return limit + 1;
+        }
+    }
+    seen.len()
+}
+
+// Planar RRRR…GGGG…BBBB…AAAA layout: the four RGBA channel planes back to back.
+pub(crate) fn to_planar_rgba(img: &DynamicImage) -> Vec<u8> {
+    let rgba = img.to_rgba8();
+    let plane = rgba.width() as usize * rgba.height() as usize;
+    let mut out = vec![0u8; plane * 4];
+    for (i, px) in rgba.pixels().enumerate() {
+        for c in 0..4 {
+            out[c * plane + i] = px.0[c];
+        }
+    }
+    out
+}
+
+pub(crate) fn from_planar_rgba(width: u32, height: u32, data: &[u8]) -> Result<DynamicImage, String> {
+    let plane = width as usize * height as usize;
+    if data.len() != plane * 4 {
+        return Err(format!(
+            "planar data must be {} bytes for {}x{}, got {}",
+            plane * 4,
+            width,
+            height,
+            data.len()
+        ));
+    }
+    let mut rgba = Vec::with_capacity(plane * 4);
+    for i in 0..plane {
+        rgba.extend_from_slice(&[data[i], data[plane + i], data[2 * plane + i], data[3 * plane + i]]);
+    }
+    RgbaImage::from_raw(width, height, rgba)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| "planar buffer size mismatch".to_string())
+}
+
+// Fills every 4-connected region of identical color smaller than `min_size`
+// with the most common color bordering it. Returns the cleaned image and the
+// number of regions removed.
+pub(crate) fn despeckle(img: &DynamicImage, min_size: usize) -> (DynamicImage, usize) {
+    let mut rgba = img.to_rgba8();
+    let (w, h) = (rgba.width() as usize, rgba.height() as usize);
+    let src = rgba.clone();
+    let mut seen = vec![false; w * h];
+    let mut removed = 0;
+    for start in 0..w * h {
+        if seen[start] {
+            continue;
+        }
+        let pixel = |i: usize| src.get_pixel((i % w) as u32, (i / w) as u32).0;
+        let color = pixel(start);
+        let mut region = vec![start];
+        let mut border: HashMap<[u8; 4], usize> = HashMap::new();
+        seen[start] = true;
+        let mut next = 0;
+        while next < region.len() {
+            let i = region[next];
+            next += 1;
+            let (x, y) = (i % w, i / w);
+            let mut neighbors = Vec::with_capacity(4);
+            if x > 0 {
+                neighbors.push(i - 1);
+            }
+            if x + 1 < w {
+                neighbors.push(i + 1);
+            }
+            if y > 0 {
+                neighbors.push(i - w);
+            }
+            if y + 1 < h {
+                neighbors.push(i + w);
+            }
+            for n in neighbors {
+                let px = pixel(n);
+                if px != color {
+                    *border.entry(px).or_default() += 1;
+                } else if !seen[n] {
+                    seen[n] = true;
+                    region.push(n);
+                }
+            }
+        }
+        if region.len() >= min_size {
+            continue;
+        }
+        // A region with no border covers the whole image; leave it alone.
+        let Some((fill, _)) = border.into_iter().max_by_key(|&(px, count)| (count, px)) else {
+            continue;
+        };
+        for i in region {
+            rgba.put_pixel((i % w) as u32, (i / w) as u32, Rgba(fill));
+        }
+        removed += 1;
+    }
+    (DynamicImage::ImageRgba8(rgba), removed)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ColorMetric {
+    // Euclidean distance over RGBA, in 0..=255 units.
+    Rgb,
+    // Delta E over RGB in CIELAB; alpha is not compared.
+    Cie76,
+    Ciede2000,
+}
+
+impl ColorMetric {
+    pub(crate) fn from_code(code: i64) -> Result<ColorMetric, String> {
+        match code {
+            0 => Ok(ColorMetric::Rgb),
+            1 => Ok(ColorMetric::Cie76),
+            2 => Ok(ColorMetric::Ciede2000),
+            other => Err(format!("unknown color metric: {other}")),
+        }
+    }
+}
+
+pub(crate) fn color_distance(a: Rgba<u8>, b: Rgba<u8>, metric: ColorMetric) -> f64 {
+    match metric {
+        ColorMetric::Rgb => a
+            .0
+            .iter()
+            .zip(b.0.iter())
+            .map(|(x, y)| (*x as f64 - *y as f64).powi(2))
+            .sum::<f64>()
+            .sqrt(),
+        ColorMetric::Cie76 => {
+            let (l1, a1, b1) = srgb_to_lab(a);
+            let (l2, a2, b2) = srgb_to_lab(b);
+            ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt()
+        }
+        ColorMetric::Ciede2000 => ciede2000(srgb_to_lab(a), srgb_to_lab(b)),
+    }
+}
+
+// sRGB (D65) to CIELAB.
+fn srgb_to_lab(px: Rgba<u8>) -> (f64, f64, f64) {
+    let lin = |c: u8| {
+        let c = c as f64 / 255.0;
+        if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    };
+    let (r, g, b) = (lin(px.0[0]), lin(px.0[1]), lin(px.0[2]));
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+    let f = |t: f64| {
+        if t > 216.0 / 24389.0 { t.cbrt() } else { (24389.0 / 27.0 * t + 16.0) / 116.0 }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+// CIEDE2000 per Sharma, Wu & Dalal (2005), with kL = kC = kH = 1.
+pub(crate) fn ciede2000((l1, a1, b1): (f64, f64, f64), (l2, a2, b2): (f64, f64, f64)) -> f64 {
+    let pow7 = |v: f64| v.powi(7);
+    let c_bar = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+    let g = 0.5 * (1.0 - (pow7(c_bar) / (pow7(c_bar) + pow7(25.0))).sqrt());
+    let (a1p, a2p) = ((1.0 + g) * a1, (1.0 + g) * a2);
+    let (c1p, c2p) = (a1p.hypot(b1), a2p.hypot(b2));
+    let hue = |b: f64, a: f64| {
+        if a == 0.0 && b == 0.0 { 0.0 } else { b.atan2(a).to_degrees().rem_euclid(360.0) }
+    };
+    let (h1p, h2p) = (hue(b1, a1p), hue(b2, a2p));
+
+    let dl = l2 - l1;
+    let dc = c2p - c1p;
+    let dh = if c1p * c2p == 0.0 {
+        0.0
+    } else if h2p - h1p > 180.0 {
+        h2p - h1p - 360.0
+    } else if h2p - h1p < -180.0 {
+        h2p - h1p + 360.0
+    } else {
+        h2p - h1p
+    };
+    let dh_big = 2.0 * (c1p * c2p).sqrt() * (dh / 2.0).to_radians().sin();
+
+    let l_bar = (l1 + l2) / 2.0;
+    let c_bar_p = (c1p + c2p) / 2.0;
+    let h_bar = if c1p * c2p == 0.0 {
+        h1p + h2p
+    } else if (h1p - h2p).abs() <= 180.0 {
+        (h1p + h2p) / 2.0
+    } else if h1p + h2p < 360.0 {
+        (h1p + h2p + 360.0) / 2.0
+    } else {
+        (h1p + h2p - 360.0) / 2.0
+    };
+    let t = 1.0 - 0.17 * (h_bar - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * h_bar).to_radians().cos()
+        + 0.32 * (3.0 * h_bar + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * h_bar - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (pow7(c_bar_p) / (pow7(c_bar_p) + pow7(25.0))).sqrt();
+    let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_bar_p;
+    let s_h = 1.0 + 0.015 * c_bar_p * t;
+    let r_t = -(2.0 * d_theta).to_radians().sin() * r_c;
+    let (l, c, h) = (dl / s_l, dc / s_c, dh_big / s_h);
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
+// Replaces pixels within `tolerance` of `from` under `metric` with `to`, but
+// only where the mask's luma is at least 128.
+pub(crate) fn replace_color_masked(
+    img: &DynamicImage,
+    mask: &GrayImage,
+    from: Rgba<u8>,
+    to: Rgba<u8>,
+    tolerance: f64,
+    metric: ColorMetric,
+) -> Result<DynamicImage, String> {
+    if !tolerance.is_finite() || tolerance < 0.0 {
+        return Err(format!("tolerance must be non-negative: {tolerance}"));
+    }
+    if (img.width(), img.height()) != mask.dimensions() {
+        return Err("mask dimensions differ".to_string());
+    }
+    let mut rgba = img.to_rgba8();
+    for (px, m) in rgba.pixels_mut().zip(mask.pixels()) {
+        if m.0[0] >= 128 && color_distance(*px, from, metric) <= tolerance {
+            *px = to;
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+// Luma from the given channel weights, normalized to sum to one. Alpha is
+// kept when the source has it.
+pub(crate) fn grayscale_weighted(img: &DynamicImage, weights: [f64; 3]) -> Result<DynamicImage, String> {
+    if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+        return Err(format!("weights must be non-negative: {:?}", weights));
+    }
+    let sum: f64 = weights.iter().sum();
+    if sum == 0.0 {
+        return Err("weights must not all be zero".to_string());
+    }
+    let rgba = img.to_rgba8();
+    let mut out = GrayAlphaImage::new(rgba.width(), rgba.height());
+    for (dst, src) in out.pixels_mut().zip(rgba.pixels()) {
+        let [r, g, b, a] = src.0;
+        let luma = (r as f64 * weights[0] + g as f64 * weights[1] + b as f64 * weights[2]) / sum;
+        *dst = LumaA([luma.round().min(255.0) as u8, a]);
+    }
+    let out = DynamicImage::ImageLumaA8(out);
+    Ok(if img.color().has_alpha() { out } else { DynamicImage::ImageLuma8(out.to_luma8()) })
+}
+
+pub(crate) fn unpack_ids(data: &[u8]) -> Result<Vec<u32>, String> {
+    unpack_u32s(data, "id")
+}
+
+pub(crate) fn unpack_u32s(data: &[u8], what: &str) -> Result<Vec<u32>, String> {
+    if data.len() % 4 != 0 {
+        return Err(format!("{what} list length must be a multiple of 4"));
+    }
+    Ok(data
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+        .collect())
+}
+
+// Lays the images out as a grid of thumbnails on a single PDF page sized to
+// fit them. Each thumbnail is embedded as a JPEG (DCTDecode) XObject.
+pub(crate) fn contact_sheet_pdf(
+    images: &[&DynamicImage],
+    cols: u32,
+    thumb_w: u32,
+    thumb_h: u32,
+    cancel: &CancelToken,
+) -> Result<Vec<u8>, String> {
+    const PAD: u32 = 8;
+    if images.is_empty() {
+        return Err("contact sheet needs at least one image".to_string());
+    }
+    if cols == 0 || thumb_w == 0 || thumb_h == 0 {
+        return Err("columns and thumbnail size must be non-zero".to_string());
+    }
+    let too_large = || "contact sheet too large".to_string();
+    let count = u32::try_from(images.len()).map_err(|_| too_large())?;
+    let rows = count.div_ceil(cols);
+    // Every tile offset below stays within the page size, so checking the
+    // page is enough.
+    let page_extent = |n: u32, thumb: u32| {
+        thumb.checked_add(PAD)?.checked_mul(n)?.checked_add(PAD)
+    };
+    let page_w = page_extent(cols.min(count), thumb_w).ok_or_else(too_large)?;
+    let page_h = page_extent(rows, thumb_h).ok_or_else(too_large)?;
+
+    // Objects 1-4 are the catalog, page tree, page and content stream; the
+    // images follow from object 5.
+    let mut content = String::new();
+    let mut resources = String::new();
+    let mut image_objects = Vec::with_capacity(images.len());
+    for (i, img) in images.iter().enumerate() {
+        cancel.check()?;
+        let thumb = DynamicImage::ImageRgb8(img.thumbnail(thumb_w, thumb_h).to_rgb8());
+        let (w, h) = (thumb.width(), thumb.height());
+        let (col, row) = (i as u32 % cols, i as u32 / cols);
+        // PDF space starts at the bottom-left corner.
+        let x = PAD + col * (thumb_w + PAD) + (thumb_w - w) / 2;
+        let y = page_h - (row + 1) * (thumb_h + PAD) + (thumb_h - h) / 2;
+        content.push_str(&format!("q {w} 0 0 {h} {x} {y} cm /Im{i} Do Q\n"));
+        resources.push_str(&format!("/Im{i} {} 0 R ", 5 + i));
+        let jpeg = encode_jpeg(&thumb, 85)?;
+        let mut obj = format!(
+            "<< /Type /XObject /Subtype /Image /Width {w} /Height {h} /ColorSpace /DeviceRGB \
+             /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>\nstream\n",
+            jpeg.len()
+        )
+        .into_bytes();
+        obj.extend_from_slice(&jpeg);
+        obj.extend_from_slice(b"\nendstream");
+        image_objects.push(obj);
+    }
+
+    let mut objects: Vec<Vec<u8>> = vec![
+        b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+        b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_vec(),
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {page_w} {page_h}] \
+             /Resources << /XObject << {resources}>> >> /Contents 4 0 R >>"
+        )
+        .into_bytes(),
+        format!("<< /Length {} >>\nstream\n{content}endstream", content.len()).into_bytes(),
+    ];
+    objects.extend(image_objects);
+
+    let mut out = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, obj) in objects.iter().enumerate() {
+        offsets.push(out.len());
+        out.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+        out.extend_from_slice(obj);
+        out.extend_from_slice(b"\nendobj\n");
+    }
+    let xref = out.len();
+    out.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        out.extend_from_slice(format!("{offset:010} 00000 n \n").as_bytes());
+    }
+    out.extend_from_slice(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            objects.len() + 1
+        )
+        .as_bytes(),
+    );
+    Ok(out)
+}
+
+// Fraction of pixels with non-zero alpha; images without an alpha channel
+// are fully covered.
+pub(crate) fn alpha_coverage(img: &DynamicImage) -> f64 {
+    let total = img.width() as u64 * img.height() as u64;
+    if !img.color().has_alpha() || total == 0 {
+        return 1.0;
+    }
+    let covered = match img {
+        DynamicImage::ImageRgba8(buf) => buf.pixels().filter(|p| p.0[3] > 0).count(),
+        other => other.to_rgba16().pixels().filter(|p| p.0[3] > 0).count(),
+    };
+    covered as f64 / total as f64
+}
+
+// Decoded GIF frames with the timing needed to re-encode them faithfully.
+pub(crate) struct GifAnimation {
+    frames: Vec<Frame>,
+    // None when the source had no NETSCAPE2.0 block, i.e. plays once.
+    repeat: Option<Repeat>,
+}
+
+// The loop count lives in the NETSCAPE2.0 application extension:
+// [0x03][0x01][u16 LE count], where 0 means loop forever. Only the extensions
+// between the screen descriptor and the first image count; the same bytes
+// inside a later comment or pixel data are not a loop block.
+pub(crate) fn gif_repeat(data: &[u8]) -> Option<Repeat> {
+    let packed = *data.get(10)?;
+    // 6-byte header, 7-byte logical screen descriptor, optional global table.
+    let mut pos = 13;
+    if packed & 0x80 != 0 {
+        pos += 3 << ((packed & 0x07) + 1);
+    }
+    while *data.get(pos)? == 0x21 {
+        let label = *data.get(pos + 1)?;
+        pos += 2;
+        let body = pos;
+        loop {
+            let size = *data.get(pos)? as usize;
+            pos += 1 + size;
+            if size == 0 {
+                break;
+            }
+        }
+        if label == 0xFF && data.get(body..body + 12) == Some(&b"\x0bNETSCAPE2.0"[..]) {
+            return match data.get(body + 12..body + 16)? {
+                [3, 1, lo, hi] => match u16::from_le_bytes([*lo, *hi]) {
+                    0 => Some(Repeat::Infinite),
+                    n => Some(Repeat::Finite(n)),
+                },
+                _ => None,
+            };
+        }
+    }
+    None
+}
+
+pub(crate) fn decode_gif_animation(data: &[u8]) -> Result<GifAnimation, String> {
+    let decoder = GifDecoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+    let frames = decoder.into_frames().collect_frames().map_err(|e| e.to_string())?;
+    if frames.is_empty() {
+        return Err("animation has no frames".to_string());
+    }
+    Ok(GifAnimation { frames, repeat: gif_repeat(data) })
+}
+
+// Applies a named per-frame op ("grayscale" or "invert"), keeping each
+// frame's offset and delay.
+pub(crate) fn map_gif_frames(anim: &mut GifAnimation, op: &str) -> Result<(), String> {
+    let apply: fn(DynamicImage) -> DynamicImage = match op {
+        "grayscale" => |img| img.grayscale(),
+        "invert" => |mut img| {
+            img.invert();
+            img
+        },
+        other => return Err(format!("unsupported frame op: {other}")),
+    };
+    for frame in anim.frames.iter_mut() {
+        let (left, top, delay) = (frame.left(), frame.top(), frame.delay());
+        let buffer = std::mem::replace(frame, Frame::new(RgbaImage::new(0, 0))).into_buffer();
+        let mapped = apply(DynamicImage::ImageRgba8(buffer)).to_rgba8();
+        *frame = Frame::from_parts(mapped, left, top, delay);
+    }
+    Ok(())
+}
+
+pub(crate) fn encode_gif_animation(anim: &GifAnimation) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut out);
+        if let Some(repeat) = anim.repeat {
+            encoder.set_repeat(repeat).map_err(|e| e.to_string())?;
+        }
+        encoder
+            .encode_frames(anim.frames.iter().cloned())
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(out)
+}
+
+// Lossless size-optimized PNG: images with at most 256 distinct colors are
+// written palette-indexed at the smallest bit depth that fits, with adaptive
+// per-scanline filtering. Anything else falls back to best compression.
+pub(crate) fn encode_png_optimized(img: &DynamicImage) -> Result<Vec<u8>, String> {
+    let wide = matches!(
+        img.color(),
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 | ColorType::Rgb32F | ColorType::Rgba32F
+    );
+    let rgba = img.to_rgba8();
+    let palette = if wide { None } else { build_palette(&rgba) };
+    let Some(mut palette) = palette else {
+        return encode_png_with_level(img, 2);
+    };
+    // Translucent entries first so tRNS can stop at the last one.
+    palette.sort_by_key(|c| c[3] == 255);
+    let index: HashMap<[u8; 4], u8> = palette.iter().enumerate().map(|(i, c)| (*c, i as u8)).collect();
+    let (depth, bits) = match palette.len() {
+        0..=2 => (png::BitDepth::One, 1),
+        3..=4 => (png::BitDepth::Two, 2),
+        5..=16 => (png::BitDepth::Four, 4),
+        _ => (png::BitDepth::Eight, 8),
+    };
+
+    let (width, height) = rgba.dimensions();
+    let per_byte = 8 / bits;
+    let stride = (width as usize).div_ceil(per_byte);
+    let mut data = vec![0u8; stride * height as usize];
+    for (y, row) in rgba.rows().enumerate() {
+        for (x, px) in row.enumerate() {
+            let shift = 8 - bits * (x % per_byte + 1);
+            data[y * stride + x / per_byte] |= index[&px.0] << shift;
+        }
+    }
+
+    let mut out = Vec::with_capacity(encode_capacity_hint(img, ImageFormat::Png));
+    {
+        let mut encoder = png::Encoder::new(&mut out, width, height);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_depth(depth);
+        encoder.set_palette(palette.iter().flat_map(|c| [c[0], c[1], c[2]]).collect::<Vec<u8>>());
+        let translucent = palette.iter().take_while(|c| c[3] != 255).count();
+        if translucent > 0 {
+            encoder.set_trns(palette[..translucent].iter().map(|c| c[3]).collect::<Vec<u8>>());
+        }
+        encoder.set_compression(png::Compression::Best);
+        encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive);
+        let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+        writer.write_image_data(&data).map_err(|e| e.to_string())?;
+        writer.finish().map_err(|e| e.to_string())?;
+    }
+    Ok(out)
+}
+
+// Distinct colors in first-seen order, or None past 256.
+fn build_palette(rgba: &RgbaImage) -> Option<Vec<[u8; 4]>> {
+    let mut seen = HashSet::new();
+    let mut palette = Vec::new();
+    for px in rgba.pixels() {
+        if seen.insert(px.0) {
+            if palette.len() == 256 {
+                return None;
+            }
+            palette.push(px.0);
+        }
+    }
+    Some(palette)
+}
+
+// Decodes under an allocation limit in bytes (0 keeps the library default).
+// A JPEG that would exceed it is retried with DCT-domain downscaling at 1/2,
+// 1/4 and 1/8; the returned divisor is the scale applied (1 = full size).
+pub(crate) fn decode_adaptive(data: &[u8], max_alloc: u64) -> Result<(DynamicImage, u32), String> {
+    let mut limits = image::Limits::default();
+    if max_alloc > 0 {
+        limits.max_alloc = Some(max_alloc);
+    }
+    let mut reader = image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?;
+    let fmt = reader.format();
+    reader.limits(limits.clone());
+    match reader.decode() {
+        Ok(img) => Ok((normalize_hdr(img, fmt), 1)),
+        Err(image::ImageError::Limits(_)) if fmt == Some(ImageFormat::Jpeg) => {
+            let budget = limits.max_alloc.unwrap_or(u64::MAX);
+            decode_jpeg_scaled(data, budget)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn decode_jpeg_scaled(data: &[u8], budget: u64) -> Result<(DynamicImage, u32), String> {
+    for divisor in [2u32, 4, 8] {
+        let mut decoder = jpeg_decoder::Decoder::new(Cursor::new(data));
+        decoder.read_info().map_err(|e| e.to_string())?;
+        let info = decoder.info().ok_or("jpeg has no frame header")?;
+        let requested_w = (info.width as u32).div_ceil(divisor) as u16;
+        let requested_h = (info.height as u32).div_ceil(divisor) as u16;
+        let (w, h) = decoder.scale(requested_w, requested_h).map_err(|e| e.to_string())?;
+        let channels = match info.pixel_format {
+            jpeg_decoder::PixelFormat::L8 => 1,
+            jpeg_decoder::PixelFormat::RGB24 => 3,
+            other => return Err(format!("unsupported jpeg pixel format for scaled decode: {other:?}")),
+        };
+        if w as u64 * h as u64 * channels > budget {
+            continue;
+        }
+        let pixels = decoder.decode().map_err(|e| e.to_string())?;
+        let (w, h) = (w as u32, h as u32);
+        let img = if channels == 1 {
+            GrayImage::from_raw(w, h, pixels).map(DynamicImage::ImageLuma8)
+        } else {
+            image::RgbImage::from_raw(w, h, pixels).map(DynamicImage::ImageRgb8)
+        };
+        let img = img.ok_or("jpeg decoder returned a short buffer")?;
+        return Ok((img, divisor));
+    }
+    Err("image exceeds the decode limit even at 1/8 scale".to_string())
+}
+
+// Sets channel 0..=3 (R, G, B, A) of every pixel to `value`.
+pub(crate) fn clear_channel(img: &DynamicImage, channel: usize, value: u8) -> Result<DynamicImage, String> {
+    if channel > 3 {
+        return Err(format!("channel must be within 0..=3: {channel}"));
+    }
+    let mut rgba = img.to_rgba8();
+    for px in rgba.pixels_mut() {
+        px.0[channel] = value;
+    }
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+// Directional emboss on luma, offset to mid-gray so flat areas land at 128.
+// `direction` names where the light comes from, clockwise from north:
+// 0 N, 1 NE, 2 E, 3 SE, 4 S, 5 SW, 6 W, 7 NW. Edges facing it turn light.
+pub(crate) fn emboss(img: &DynamicImage, direction: u32, cancel: &CancelToken) -> Result<DynamicImage, String> {
+    const LIGHT: [(i32, i32); 8] = [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)];
+    let &(lx, ly) = LIGHT
+        .get(direction as usize)
+        .ok_or_else(|| format!("direction must be within 0..=7: {direction}"))?;
+    let luma = img.to_luma8();
+    let alpha = img.to_rgba8();
+    let (w, h) = luma.dimensions();
+    let mut out = RgbaImage::new(w, h);
+    for y in 0..h {
+        cancel.check()?;
+        for x in 0..w {
+            let mut sum = 128i32;
+            for j in -1i32..=1 {
+                for i in -1i32..=1 {
+                    // Taps on the lit side subtract, those away from it add.
+                    let weight = -(i * lx + j * ly).signum();
+                    if weight == 0 {
+                        continue;
+                    }
+                    let sx = (x as i32 + i).clamp(0, w as i32 - 1) as u32;
+                    let sy = (y as i32 + j).clamp(0, h as i32 - 1) as u32;
+                    sum += weight * luma.get_pixel(sx, sy).0[0] as i32;
+                }
+            }
+            let v = sum.clamp(0, 255) as u8;
+            out.put_pixel(x, y, Rgba([v, v, v, alpha.get_pixel(x, y).0[3]]));
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(out))
+}
+
+// Truecolor ANSI preview `cols` characters wide. Each cell is an upper half
+// block with the top pixel as foreground and the bottom one as background,
+// so a cell covers two square-ish pixels and rows follow the aspect ratio.
+pub(crate) fn ansi_preview(img: &DynamicImage, cols: u32) -> Result<String, String> {
+    if cols == 0 {
+        return Err("cols must be non-zero".to_string());
+    }
+    if img.width() == 0 || img.height() == 0 {
+        return Err("image has zero dimensions".to_string());
+    }
+    let rows = ((cols as f64 * img.height() as f64 / img.width() as f64).round() as u32).max(1);
+    let small = img.resize_exact(cols, rows, FilterType::Triangle).to_rgb8();
+    let mut out = String::new();
+    for y in (0..rows).step_by(2) {
+        for x in 0..cols {
+            let [r, g, b] = small.get_pixel(x, y).0;
+            out.push_str(&format!("\x1b[38;2;{r};{g};{b}m"));
+            if y + 1 < rows {
+                let [r, g, b] = small.get_pixel(x, y + 1).0;
+                out.push_str(&format!("\x1b[48;2;{r};{g};{b}m"));
+            } else {
+                out.push_str("\x1b[49m");
+            }
+            out.push('\u{2580}');
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    Ok(out)
+}
+
+// Strength 0..=1 blends from the original to a sigma-1 unsharp mask, so it
+// acts as the mask amount. 0 leaves the image unchanged.
+pub(crate) fn sharpen(img: &DynamicImage, strength: f64, cancel: &CancelToken) -> Result<DynamicImage, String> {
+    if !(0.0..=1.0).contains(&strength) {
+        return Err(format!("strength must be within 0..=1: {strength}"));
+    }
+    if strength == 0.0 {
+        return Ok(img.clone());
+    }
+    let sharp = filter_in_bands(img, blur_margin(1.0), cancel, |band| band.unsharpen(1.0, 0))?.to_rgba8();
+    let mut out = img.to_rgba8();
+    for (px, s) in out.pixels_mut().zip(sharp.pixels()) {
+        for c in 0..4 {
+            let from = px.0[c] as f64;
+            px.0[c] = (from + (s.0[c] as f64 - from) * strength).round() as u8;
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(out))
+}
+
+// Packed color as 0xRRGGBBAA in the low 32 bits.
+pub(crate) fn unpack_rgba(packed: u64) -> Rgba<u8> {
+    let [_, _, _, _, r, g, b, a] = packed.to_be_bytes();
+    Rgba([r, g, b, a])
+}
+
+// Rotates clockwise by `degrees` onto a canvas grown to hold the whole
+// result; uncovered corners are filled with `bg`. `interpolation` picks the
+// `sample` kernel: 0 nearest, 1 bilinear, 2 bicubic.
+pub(crate) fn rotate_angle(
+    img: &DynamicImage,
+    degrees: f64,
+    bg: Rgba<u8>,
+    interpolation: i64,
+) -> Result<DynamicImage, String> {
+    if !degrees.is_finite() {
+        return Err(format!("angle must be finite: {degrees}"));
+    }
+    if !(0..=2).contains(&interpolation) {
+        return Err(format!("unknown interpolation: {interpolation}"));
+    }
+    let src = img.to_rgba8();
+    let (w, h) = (src.width() as f64, src.height() as f64);
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    // Rounded first so 90-degree multiples don't gain a pixel from float noise.
+    let fit = |v: f64| ((v * 1e6).round() / 1e6).ceil() as u32;
+    let out_w = fit(w * cos.abs() + h * sin.abs());
+    let out_h = fit(w * sin.abs() + h * cos.abs());
+    let (cx, cy) = (w / 2.0, h / 2.0);
+    let (ox, oy) = (out_w as f64 / 2.0, out_h as f64 / 2.0);
+
+    let texel = |x: i64, y: i64| {
+        if x >= 0 && y >= 0 && (x as u32) < src.width() && (y as u32) < src.height() {
+            src.get_pixel(x as u32, y as u32).0
+        } else {
+            bg.0
+        }
+    };
+    let mut out = RgbaImage::new(out_w, out_h);
+    for (x, y, px) in out.enumerate_pixels_mut() {
+        let dx = x as f64 + 0.5 - ox;
+        let dy = y as f64 + 0.5 - oy;
+        // Inverse rotation back into source pixel space.
+        let sx = cos * dx + sin * dy + cx - 0.5;
+        let sy = -sin * dx + cos * dy + cy - 0.5;
+        *px = interpolate(&texel, sx, sy, interpolation)?;
+    }
+    Ok(DynamicImage::ImageRgba8(out))
+}
+
+// A stored selection and the image size it was drawn on. Once an op changes
+// the size the region no longer describes the image, so lookups drop it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Selection {
+    pub(crate) region: (u32, u32, u32, u32),
+    pub(crate) size: (u32, u32),
+}
+
+// Clips a region to the image's bounds.
+pub(crate) fn clip_region(img: &DynamicImage, (x, y, w, h): (u32, u32, u32, u32)) -> (u32, u32, u32, u32) {
+    let x = x.min(img.width());
+    let y = y.min(img.height());
+    (x, y, w.min(img.width() - x), h.min(img.height() - y))
+}
+
+// Runs `op` on just the region and pastes the result back, converted to the
+// image's own color type so a selection never changes the format.
+pub(crate) fn apply_in_region(
+    img: &DynamicImage,
+    region: (u32, u32, u32, u32),
+    op: impl FnOnce(&DynamicImage) -> Result<DynamicImage, String>,
+) -> Result<DynamicImage, String> {
+    let (x, y, w, h) = clip_region(img, region);
+    let patch = op(&img.crop_imm(x, y, w, h))?;
+    let mut out = img.clone();
+    paste_same_type(&mut out, &convert_like(&patch, img.color()), x, y)?;
+    Ok(out)
+}
+
+// Converts to the DynamicImage variant matching `color`.
+fn convert_like(img: &DynamicImage, color: ColorType) -> DynamicImage {
+    match color {
+        ColorType::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(img.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        ColorType::Rgba32F => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+        _ => DynamicImage::ImageRgba8(img.to_rgba8()),
+    }
+}
+
+// Alternates `cell`-sized squares of a and b, starting with a at the origin.
+pub(crate) fn checker_compare(a: &RgbaImage, b: &RgbaImage, cell: u32) -> Result<DynamicImage, String> {
+    if cell == 0 {
+        return Err("cell_size must be non-zero".to_string());
+    }
+    if a.dimensions() != b.dimensions() {
+        return Err("image dimensions differ".to_string());
+    }
+    let out = RgbaImage::from_fn(a.width(), a.height(), |x, y| {
+        if (x / cell + y / cell) % 2 == 0 { *a.get_pixel(x, y) } else { *b.get_pixel(x, y) }
+    });
+    Ok(DynamicImage::ImageRgba8(out))
+}
+
+// Pads every side by `border` pixels of `color`.
+pub(crate) fn pad_border(img: &DynamicImage, border: u32, color: Rgba<u8>) -> Result<DynamicImage, String> {
+    let grow = |v: u32| v.checked_add(border.checked_mul(2)?);
+    let (Some(w), Some(h)) = (grow(img.width()), grow(img.height())) else {
+        return Err(format!("border too large: {border}"));
+    };
+    let mut canvas = RgbaImage::from_pixel(w, h, color);
+    image::imageops::replace(&mut canvas, &img.to_rgba8(), border as i64, border as i64);
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ColorSpace {
+    Srgb,
+    Linear,
+    DisplayP3,
+}
+
+impl ColorSpace {
+    pub(crate) fn from_code(code: i64) -> Result<ColorSpace, String> {
+        match code {
+            0 => Ok(ColorSpace::Srgb),
+            1 => Ok(ColorSpace::Linear),
+            2 => Ok(ColorSpace::DisplayP3),
+            other => Err(format!("unknown color space: {other}")),
+        }
+    }
+
+    pub(crate) fn code(self) -> i64 {
+        match self {
+            ColorSpace::Srgb => 0,
+            ColorSpace::Linear => 1,
+            ColorSpace::DisplayP3 => 2,
+        }
+    }
+
+    // Display P3 shares the sRGB transfer curve, so only linear data can be
+    // resampled as stored.
+    pub(crate) fn needs_linearize(self) -> bool {
+        self != ColorSpace::Linear
+    }
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+// Resamples in linear light so averages of bright and dark pixels don't come
+// out too dark. `linearize` is false when the data is already linear.
+pub(crate) fn resize_linear(img: &DynamicImage, width: u32, height: u32, linearize: bool) -> DynamicImage {
+    let mut float = img.to_rgba32f();
+    if linearize {
+        for px in float.pixels_mut() {
+            for c in &mut px.0[..3] {
+                *c = srgb_to_linear(*c);
+            }
+        }
+    }
+    let mut resized = image::imageops::resize(&float, width, height, FilterType::Lanczos3);
+    for px in resized.pixels_mut() {
+        for c in &mut px.0 {
+            *c = c.clamp(0.0, 1.0);
+        }
+        if linearize {
+            for c in &mut px.0[..3] {
+                *c = linear_to_srgb(*c);
+            }
+        }
+    }
+    DynamicImage::ImageRgba8(DynamicImage::ImageRgba32F(resized).to_rgba8())
+}
+
+// Blur cost grows with sigma and image size; very large sigmas on big images
+// are slow but deliberately uncapped. The sigma must still survive the cast
+// to f32 as a finite value.
+pub(crate) fn blur_sigma(sigma: f64) -> Result<f32, String> {
+    let narrowed = sigma as f32;
+    if !(sigma.is_finite() && sigma > 0.0 && narrowed.is_finite() && narrowed > 0.0) {
+        return Err(format!(
+            "blur sigma must be positive and finite: {sigma} (large sigmas are slow on big images)"
+        ));
+    }
+    Ok(narrowed)
+}
+
+// NV12: a full-resolution Y plane followed by interleaved U/V at half
+// resolution. Converted with limited-range BT.601 coefficients.
+pub(crate) fn from_nv12(width: u32, height: u32, data: &[u8]) -> Result<DynamicImage, String> {
+    if width == 0 || height == 0 || width % 2 != 0 || height % 2 != 0 {
+        return Err(format!("NV12 dimensions must be even and non-zero: {width}x{height}"));
+    }
+    let (w, h) = (width as usize, height as usize);
+    let expected = w * h * 3 / 2;
+    if data.len() != expected {
+        return Err(format!(
+            "NV12 data must be {expected} bytes for {width}x{height}, got {}",
+            data.len()
+        ));
+    }
+    let (luma, chroma) = data.split_at(w * h);
+    let mut rgba = Vec::with_capacity(w * h * 4);
+    for y in 0..h {
+        for x in 0..w {
+            let uv = (y / 2) * w + (x / 2) * 2;
+            let c = 1.164 * (luma[y * w + x] as f32 - 16.0);
+            let u = chroma[uv] as f32 - 128.0;
+            let v = chroma[uv + 1] as f32 - 128.0;
+            let r = c + 1.596 * v;
+            let g = c - 0.392 * u - 0.813 * v;
+            let b = c + 2.017 * u;
+            rgba.extend_from_slice(&[
+                r.round().clamp(0.0, 255.0) as u8,
+                g.round().clamp(0.0, 255.0) as u8,
+                b.round().clamp(0.0, 255.0) as u8,
+                255,
+            ]);
+        }
+    }
+    RgbaImage::from_raw(width, height, rgba)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| "NV12 buffer size mismatch".to_string())
+}
+
+pub(crate) fn unsharpen(
+    img: &DynamicImage,
+    sigma: f64,
+    threshold: i64,
+    cancel: &CancelToken,
+) -> Result<DynamicImage, String> {
+    let narrowed = sigma as f32;
+    if !(narrowed.is_finite() && narrowed > 0.0) {
+        return Err(format!("unsharpen sigma must be positive and finite: {sigma}"));
+    }
+    let threshold = i32::try_from(threshold)
+        .ok()
+        .filter(|t| *t >= 0)
+        .ok_or_else(|| format!("unsharpen threshold must be a non-negative i32: {threshold}"))?;
+    filter_in_bands(img, blur_margin(narrowed), cancel, |band| band.unsharpen(narrowed, threshold))
+}
+
+// Difference of Gaussians per color channel, offset so that no response is
+// mid-gray. Alpha is kept from the source.
+pub(crate) fn difference_of_gaussians(
+    img: &DynamicImage,
+    sigma1: f64,
+    sigma2: f64,
+    cancel: &CancelToken,
+) -> Result<DynamicImage, String> {
+    if !(sigma1 > 0.0 && sigma2 > sigma1) {
+        return Err(format!("DoG requires sigma2 > sigma1 > 0: {sigma1}, {sigma2}"));
+    }
+    let (sigma1, sigma2) = (blur_sigma(sigma1)?, blur_sigma(sigma2)?);
+    let src = DynamicImage::ImageRgba8(img.to_rgba8());
+    let narrow = blur(&src, sigma1, cancel)?.into_rgba8();
+    let wide = blur(&src, sigma2, cancel)?.into_rgba8();
+    let mut out = src.into_rgba8();
+    for ((px, n), w) in out.pixels_mut().zip(narrow.pixels()).zip(wide.pixels()) {
+        for c in 0..3 {
+            px.0[c] = (128 + n.0[c] as i32 - w.0[c] as i32).clamp(0, 255) as u8;
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(out))
+}
+
+// Bounding box and size of one connected region of a mask.
+pub(crate) struct Region {
+    pub(crate) pixels: usize,
+    pub(crate) min: (usize, usize),
+    pub(crate) max: (usize, usize),
+}
+
+// Labels connected `true` runs of a row-major mask, 4-connected unless
+// `diagonal` is set.
+pub(crate) fn label_regions(mask: &[bool], w: usize, h: usize, diagonal: bool) -> Vec<Region> {
+    let mut seen = vec![false; w * h];
+    let mut regions = Vec::new();
+    for start in 0..w * h {
+        if !mask[start] || seen[start] {
+            continue;
+        }
+        seen[start] = true;
+        let mut stack = vec![start];
+        let mut region = Region { pixels: 0, min: (start % w, start / w), max: (start % w, start / w) };
+        while let Some(i) = stack.pop() {
+            let (x, y) = (i % w, i / w);
+            region.pixels += 1;
+            region.min = (region.min.0.min(x), region.min.1.min(y));
+            region.max = (region.max.0.max(x), region.max.1.max(y));
+            for dy in -1i64..=1 {
+                for dx in -1i64..=1 {
+                    if (dx == 0 && dy == 0) || (!diagonal && dx != 0 && dy != 0) {
+                        continue;
+                    }
+                    let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                    if nx < 0 || ny < 0 || nx >= w as i64 || ny >= h as i64 {
+                        continue;
+                    }
+                    let n = ny as usize * w + nx as usize;
+                    if mask[n] && !seen[n] {
+                        seen[n] = true;
+                        stack.push(n);
+                    }
+                }
+            }
+        }
+        regions.push(region);
+    }
+    regions
+}
+
+// Bounding box (x, y, w, h) of the largest 4-connected region whose RGB is
+// within `tolerance` of `color` on every channel.
+pub(crate) fn find_color_rect(img: &DynamicImage, color: [u8; 3], tolerance: u8) -> Option<(u32, u32, u32, u32)> {
+    let rgb = img.to_rgb8();
+    let mask: Vec<bool> = rgb
+        .pixels()
+        .map(|px| (0..3).all(|c| px.0[c].abs_diff(color[c]) <= tolerance))
+        .collect();
+    let regions = label_regions(&mask, rgb.width() as usize, rgb.height() as usize, false);
+    let largest = regions.into_iter().max_by_key(|r| r.pixels)?;
+    Some((
+        largest.min.0 as u32,
+        largest.min.1 as u32,
+        (largest.max.0 - largest.min.0 + 1) as u32,
+        (largest.max.1 - largest.min.1 + 1) as u32,
+    ))
+}
+
+// Paints `color` onto `base` through the shape's alpha, with the shape's top
+// left at (x, y). The shape's own colors are ignored.
+pub(crate) fn stencil(base: &DynamicImage, shape: &RgbaImage, x: i64, y: i64, color: [u8; 3]) -> DynamicImage {
+    let mut out = base.to_rgba8();
+    for (sx, sy, px) in shape.enumerate_pixels() {
+        let (tx, ty) = (x + sx as i64, y + sy as i64);
+        if px.0[3] == 0 || tx < 0 || ty < 0 || tx >= out.width() as i64 || ty >= out.height() as i64 {
+            continue;
+        }
+        let a = px.0[3] as f32 / 255.0;
+        let dst = out.get_pixel_mut(tx as u32, ty as u32);
+        for c in 0..3 {
+            dst.0[c] = (color[c] as f32 * a + dst.0[c] as f32 * (1.0 - a)).round() as u8;
+        }
+        dst.0[3] = (255.0 * a + dst.0[3] as f32 * (1.0 - a)).round() as u8;
+    }
+    DynamicImage::ImageRgba8(out)
+}
+
+pub(crate) fn filter_from_code(code: i64) -> Result<FilterType, String> {
+    match code {
+        0 => Ok(FilterType::Nearest),
+        1 => Ok(FilterType::Triangle),
+        2 => Ok(FilterType::CatmullRom),
+        3 => Ok(FilterType::Gaussian),
+        4 => Ok(FilterType::Lanczos3),
+        other => Err(format!("unknown resize filter: {other}")),
+    }
+}
+
+// (channels, bits per channel, has alpha) for a stored color type.
+pub(crate) fn color_info(color: ColorType) -> (i64, i64, i64) {
+    let channels = color.channel_count() as i64;
+    let bits = color.bits_per_pixel() as i64 / channels;
+    (channels, bits, color.has_alpha() as i64)
+}
+
+// Treats luma as height and encodes the Sobel-derived surface normal as
+// tangent-space RGB, so flat areas come out (128, 128, 255).
+pub(crate) fn height_to_normal(img: &DynamicImage, strength: f64) -> Result<DynamicImage, String> {
+    if !strength.is_finite() || strength < 0.0 {
+        return Err(format!("normal strength must be finite and non-negative: {strength}"));
+    }
+    let luma = img.to_luma8();
+    let (w, h) = (luma.width() as i64, luma.height() as i64);
+    let at = |x: i64, y: i64| luma.get_pixel(x.clamp(0, w - 1) as u32, y.clamp(0, h - 1) as u32).0[0] as f64 / 255.0;
+    let out = RgbaImage::from_fn(luma.width(), luma.height(), |x, y| {
+        let (x, y) = (x as i64, y as i64);
+        let dx = (at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1))
+            - (at(x - 1, y - 1) + 2.0 * at(x - 1, y) + at(x - 1, y + 1));
+        let dy = (at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1))
+            - (at(x - 1, y - 1) + 2.0 * at(x, y - 1) + at(x + 1, y - 1));
+        let (nx, ny, nz) = (-dx * strength, -dy * strength, 1.0);
+        let len = (nx * nx + ny * ny + nz * nz).sqrt();
+        let encode = |v: f64| ((v / len * 0.5 + 0.5) * 255.0).round() as u8;
+        Rgba([encode(nx), encode(ny), encode(nz), 255])
+    });
+    Ok(DynamicImage::ImageRgba8(out))
+}
+
+// Counts connected regions of pixels whose luma exceeds `threshold`.
+pub(crate) fn count_blobs(img: &DynamicImage, threshold: u8, connectivity: u32) -> Result<usize, String> {
+    let diagonal = match connectivity {
+        4 => false,
+        8 => true,
+        other => return Err(format!("connectivity must be 4 or 8: {other}")),
+    };
+    let luma = img.to_luma8();
+    let mask: Vec<bool> = luma.pixels().map(|px| px.0[0] > threshold).collect();
+    Ok(label_regions(&mask, luma.width() as usize, luma.height() as usize, diagonal).len())
+}
+
+// Per-channel counts over the region clipped to bounds, as R, G, B then A
+// blocks of 256 little-endian u32 each.
+pub(crate) fn histogram_region(img: &DynamicImage, region: (u32, u32, u32, u32)) -> Vec<u8> {
+    let (x, y, w, h) = clip_region(img, region);
+    let rgba = img.crop_imm(x, y, w, h).to_rgba8();
+    let mut counts = [[0u32; 256]; 4];
+    for px in rgba.pixels() {
+        for (channel, &v) in counts.iter_mut().zip(px.0.iter()) {
+            channel[v as usize] += 1;
+        }
+    }
+    counts.iter().flatten().flat_map(|n| n.to_le_bytes()).collect()
+}
+
+// Most common color among the border pixels, with the fraction of border
+// pixels that have exactly that color.
+pub(crate) fn detect_background(img: &DynamicImage) -> (Rgba<u8>, f64) {
+    let rgba = img.to_rgba8();
+    let (w, h) = rgba.dimensions();
+    let mut border = Vec::new();
+    for x in 0..w {
+        border.push((x, 0));
+        if h > 1 {
+            border.push((x, h - 1));
+        }
+    }
+    for y in 1..h.saturating_sub(1) {
+        border.push((0, y));
+        if w > 1 {
+            border.push((w - 1, y));
+        }
+    }
+    let mut counts: HashMap<[u8; 4], usize> = HashMap::new();
+    for &(x, y) in &border {
+        *counts.entry(rgba.get_pixel(x, y).0).or_default() += 1;
+    }
+    match counts.into_iter().max_by_key(|&(px, count)| (count, px)) {
+        Some((px, count)) => (Rgba(px), count as f64 / border.len() as f64),
+        None => (Rgba([0, 0, 0, 0]), 0.0),
+    }
+}
+
+pub(crate) fn pack_rgba(px: Rgba<u8>) -> u64 {
+    u32::from_be_bytes(px.0) as u64
+}
+
+// Per-channel saturating arithmetic on RGB: 0 add, 1 subtract, 2 multiply
+// (normalized, so white is identity), 3 absolute difference. Alpha is kept
+// from `base`.
+pub(crate) fn image_arithmetic(base: &DynamicImage, other: &DynamicImage, op: i64) -> Result<DynamicImage, String> {
+    let apply: fn(u8, u8) -> u8 = match op {
+        0 => |a, b| a.saturating_add(b),
+        1 => |a, b| a.saturating_sub(b),
+        2 => |a, b| ((a as u16 * b as u16 + 127) / 255) as u8,
+        3 => |a, b| a.abs_diff(b),
+        other => return Err(format!("unknown arithmetic op: {other}")),
+    };
+    if (base.width(), base.height()) != (other.width(), other.height()) {
+        return Err(format!(
+            "image sizes differ: {}x{} vs {}x{}",
+            base.width(),
+            base.height(),
+            other.width(),
+            other.height()
+        ));
+    }
+    let mut out = base.to_rgba8();
+    let other = other.to_rgba8();
+    for (px, o) in out.pixels_mut().zip(other.pixels()) {
+        for c in 0..3 {
+            px.0[c] = apply(px.0[c], o.0[c]);
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(out))
+}
+
+// Sets every pixel of the rectangle to `color`, converting to RGBA first.
+// With a selection, only pixels inside both the rectangle and the selection
+// are filled. The rectangle must lie within the image, as for crop.
+pub(crate) fn fill_rect(
+    img: &DynamicImage,
+    (x, y, w, h): (u32, u32, u32, u32),
+    color: Rgba<u8>,
+    selection: Option<(u32, u32, u32, u32)>,
+) -> Result<DynamicImage, String> {
+    if x as u64 + w as u64 > img.width() as u64 || y as u64 + h as u64 > img.height() as u64 {
+        return Err("fill region out of bounds".to_string());
+    }
+    let (sx, sy, sw, sh) = selection.map_or((0, 0, img.width(), img.height()), |s| clip_region(img, s));
+    let (x0, y0) = (x.max(sx), y.max(sy));
+    let (x1, y1) = ((x + w).min(sx + sw), (y + h).min(sy + sh));
+    let mut rgba = img.to_rgba8();
+    for py in y0..y1.max(y0) {
+        for px in x0..x1.max(x0) {
+            rgba.put_pixel(px, py, color);
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+// (hue degrees in 0..360, saturation, value), all from 0..=1 channels.
+fn rgb_to_hsv(r: f64, g: f64, b: f64) -> (f64, f64, f64) {
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let sat = if max == 0.0 { 0.0 } else { delta / max };
+    (hue, sat, max)
+}
+
+fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (f64, f64, f64) {
+    let c = v * s;
+    let h = h.rem_euclid(360.0) / 60.0;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    (r + m, g + m, b + m)
+}
+
+// Rotates the hue of pixels whose hue is within `range` degrees of
+// `target_hue`; grays have no hue and are never touched.
+pub(crate) fn selective_hue_rotate(
+    img: &DynamicImage,
+    target_hue: f64,
+    range: f64,
+    rotate_by: f64,
+) -> Result<DynamicImage, String> {
+    if !(0.0..=180.0).contains(&range) {
+        return Err(format!("hue range must be within 0..=180: {range}"));
+    }
+    let mut rgba = img.to_rgba8();
+    for px in rgba.pixels_mut() {
+        let [r, g, b, _] = px.0.map(|c| c as f64 / 255.0);
+        let (h, s, v) = rgb_to_hsv(r, g, b);
+        let distance = (h - target_hue).rem_euclid(360.0);
+        if s == 0.0 || distance.min(360.0 - distance) > range {
+            continue;
+        }
+        let (r, g, b) = hsv_to_rgb(h + rotate_by, s, v);
+        for (c, v) in px.0.iter_mut().zip([r, g, b]) {
+            *c = (v * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    Ok(DynamicImage::ImageRgba8(rgba))
+}
+
+// Square PNGs of each requested size, packed as [u32 count] then
+// [u32 size][u32 len][png] per entry, in request order.
+pub(crate) fn favicon_set(img: &DynamicImage, sizes: &[u32]) -> Result<Vec<u8>, String> {
+    if sizes.is_empty() {
+        return Err("at least one favicon size is required".to_string());
+    }
+    if let Some(bad) = sizes.iter().find(|s| !(1..=1024).contains(*s)) {
+        return Err(format!("favicon size must be within 1..=1024: {bad}"));
+    }
+    let pngs: Vec<Vec<u8>> = sizes
+        .par_iter()
+        .map(|&size| encode_png_with_level(&img.resize_exact(size, size, FilterType::Lanczos3), 1))
+        .collect::<Result<_, _>>()?;
+    let mut out = Vec::with_capacity(4 + pngs.iter().map(|p| p.len() + 8).sum::<usize>());
+    out.extend_from_slice(&(sizes.len() as u32).to_le_bytes());
+    for (size, png) in sizes.iter().zip(&pngs) {
+        out.extend_from_slice(&size.to_le_bytes());
+        out.extend_from_slice(&(png.len() as u32).to_le_bytes());
+        out.extend_from_slice(png);
+    }
+    Ok(out)
+}
+
+// Samples at (x, y), where integer coordinates are pixel centers. Coordinates
+// outside the image clamp to the edge. 0 nearest, 1 bilinear, 2 bicubic
+// (Catmull-Rom).
+pub(crate) fn sample(img: &DynamicImage, x: f64, y: f64, interpolation: i64) -> Result<Rgba<u8>, String> {
+    if !x.is_finite() || !y.is_finite() {
+        return Err(format!("sample coordinates must be finite: ({x}, {y})"));
+    }
+    let (w, h) = (img.width() as i64, img.height() as i64);
+    let at = |px: i64, py: i64| {
+        image::GenericImageView::get_pixel(img, px.clamp(0, w - 1) as u32, py.clamp(0, h - 1) as u32).0
+    };
+    // Every tap beyond two pixels outside the image reads the edge anyway;
+    // clamping first keeps the tap offsets from overflowing.
+    let (x, y) = (x.clamp(-2.0, (w + 1) as f64), y.clamp(-2.0, (h + 1) as f64));
+    interpolate(at, x, y, interpolation)
+}
+
+// The kernels behind `sample`, reading texels through `at` so callers choose
+// what lies outside the image.
+fn interpolate(at: impl Fn(i64, i64) -> [u8; 4], x: f64, y: f64, interpolation: i64) -> Result<Rgba<u8>, String> {
+    let (fx, fy) = (x.floor(), y.floor());
+    let (tx, ty) = (x - fx, y - fy);
+    let (ix, iy) = (fx as i64, fy as i64);
+    let mut out = [0u8; 4];
+    match interpolation {
+        0 => return Ok(Rgba(at(x.round() as i64, y.round() as i64))),
+        1 => {
+            let corners = [at(ix, iy), at(ix + 1, iy), at(ix, iy + 1), at(ix + 1, iy + 1)];
+            for (c, v) in out.iter_mut().enumerate() {
+                let top = corners[0][c] as f64 * (1.0 - tx) + corners[1][c] as f64 * tx;
+                let bottom = corners[2][c] as f64 * (1.0 - tx) + corners[3][c] as f64 * tx;
+                *v = (top * (1.0 - ty) + bottom * ty).round() as u8;
+            }
+        }
+        2 => {
+            let weights = |t: f64| {
+                [
+                    ((-t + 2.0) * t - 1.0) * t / 2.0,
+                    ((3.0 * t - 5.0) * t * t + 2.0) / 2.0,
+                    ((-3.0 * t + 4.0) * t + 1.0) * t / 2.0,
+                    (t - 1.0) * t * t / 2.0,
+                ]
+            };
+            let (wx, wy) = (weights(tx), weights(ty));
+            for (c, v) in out.iter_mut().enumerate() {
+                let mut sum = 0.0;
+                for (j, wyj) in wy.iter().enumerate() {
+                    for (i, wxi) in wx.iter().enumerate() {
+                        sum += at(ix + i as i64 - 1, iy + j as i64 - 1)[c] as f64 * wxi * wyj;
+                    }
+                }
+                *v = sum.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        other => return Err(format!("unknown interpolation: {other}")),
+    }
+    Ok(Rgba(out))
+}
+
+// Mode 0 (contain) fits inside the box keeping aspect, so one side may come
+// out smaller; mode 1 (cover) fills the box and center-crops to exactly
+// width x height.
+pub(crate) fn thumbnail_fit(img: &DynamicImage, width: u32, height: u32, mode: i64) -> Result<DynamicImage, String> {
+    match mode {
+        0 => Ok(img.thumbnail(width, height)),
+        1 => Ok(img.resize_to_fill(width, height, FilterType::Lanczos3)),
+        other => Err(format!("unknown fit mode: {other}")),
+    }
+}
+
+// EXIF orientation from the encoded bytes, read from the headers only.
+// Upright images (or ones without EXIF) give None.
+pub(crate) fn read_orientation(data: &[u8]) -> Option<Orientation> {
+    let mut decoder = image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    match decoder.orientation().ok()? {
+        Orientation::NoTransforms => None,
+        orientation => Some(orientation),
+    }
+}
+
+// Stable codes for sniffed formats; new formats are only ever appended.
+pub(crate) fn format_code(fmt: ImageFormat) -> Result<i64, String> {
+    Ok(match fmt {
+        ImageFormat::Png => 0,
+        ImageFormat::Jpeg => 1,
+        ImageFormat::Gif => 2,
+        ImageFormat::Bmp => 3,
+        ImageFormat::WebP => 4,
+        ImageFormat::Tiff => 5,
+        ImageFormat::Ico => 6,
+        ImageFormat::Tga => 7,
+        ImageFormat::Pnm => 8,
+        ImageFormat::Hdr => 9,
+        ImageFormat::OpenExr => 10,
+        ImageFormat::Farbfeld => 11,
+        ImageFormat::Avif => 12,
+        ImageFormat::Qoi => 13,
+        ImageFormat::Dds => 14,
+        ImageFormat::Pcx => 15,
+        other => return Err(format!("unsupported image format: {other:?}")),
+    })
+}
+
+pub(crate) fn guess_format_code(data: &[u8]) -> Result<i64, String> {
+    let fmt = image::guess_format(data).map_err(|_| "could not determine image format".to_string())?;
+    format_code(fmt)
+}
+
+// JPEG with an APP1 EXIF block right after SOI whose IFD1 carries a JPEG
+// thumbnail fitted into thumb_w x thumb_h. The whole APP1 segment must fit
+// in 64 KiB, so oversized thumbnails are an error.
+pub(crate) fn encode_jpeg_with_thumbnail(
+    img: &DynamicImage,
+    quality: u8,
+    thumb_w: u32,
+    thumb_h: u32,
+) -> Result<Vec<u8>, String> {
+    let main = encode_jpeg(img, quality)?;
+    let thumb = encode_jpeg(&img.thumbnail(thumb_w, thumb_h), quality)?;
+
+    // Little-endian TIFF: IFD0 at 8 with one Orientation entry, IFD1 at 26
+    // with Compression, JPEGInterchangeFormat and its length, thumb at 68.
+    const THUMB_OFFSET: u32 = 68;
+    let entry = |tiff: &mut Vec<u8>, tag: u16, kind: u16, value: u32| {
+        tiff.extend_from_slice(&tag.to_le_bytes());
+        tiff.extend_from_slice(&kind.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&value.to_le_bytes());
+    };
+    let mut tiff = b"II*\0".to_vec();
+    tiff.extend_from_slice(&8u32.to_le_bytes());
+    tiff.extend_from_slice(&1u16.to_le_bytes());
+    entry(&mut tiff, 0x0112, 3, 1);
+    tiff.extend_from_slice(&26u32.to_le_bytes());
+    tiff.extend_from_slice(&3u16.to_le_bytes());
+    entry(&mut tiff, 0x0103, 3, 6);
+    entry(&mut tiff, 0x0201, 4, THUMB_OFFSET);
+    entry(&mut tiff, 0x0202, 4, thumb.len() as u32);
+    tiff.extend_from_slice(&0u32.to_le_bytes());
+    debug_assert_eq!(tiff.len() as u32, THUMB_OFFSET);
+    tiff.extend_from_slice(&thumb);
+
+    let segment_len = 2 + 6 + tiff.len();
+    if segment_len > u16::MAX as usize {
+        return Err(format!("EXIF thumbnail too large: {} bytes", thumb.len()));
+    }
+    let mut out = Vec::with_capacity(main.len() + segment_len + 2);
+    out.extend_from_slice(&main[..2]);
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&(segment_len as u16).to_be_bytes());
+    out.extend_from_slice(b"Exif\0\0");
+    out.extend_from_slice(&tiff);
+    out.extend_from_slice(&main[2..]);
+    Ok(out)
+}
+
+// Projection-profile skew estimate: dark pixels (luma < 128) are projected
+// onto the normal of each candidate angle and the angle whose row profile is
+// sharpest wins. Positive means the content is rotated clockwise, so
+// rotate_angle by the negated result straightens it.
+pub(crate) fn estimate_skew(img: &DynamicImage) -> f64 {
+    let luma = img.to_luma8();
+    let points: Vec<(f64, f64)> = luma
+        .enumerate_pixels()
+        .filter(|(_, _, p)| p.0[0] < 128)
+        .map(|(x, y, _)| (x as f64, y as f64))
+        .collect();
+    if points.is_empty() {
+        return 0.0;
+    }
+    let span = (luma.width() + luma.height()) as usize;
+    let mut bins = vec![0u64; 2 * span + 1];
+    let mut score = |degrees: f64| -> u64 {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        bins.iter_mut().for_each(|b| *b = 0);
+        for &(x, y) in &points {
+            bins[((y * cos - x * sin).round() as i64 + span as i64) as usize] += 1;
+        }
+        bins.iter().map(|&b| b * b).sum()
+    };
+    let mut search = |center: f64, step: f64, steps: i32| -> f64 {
+        let mut best = (center, 0u64);
+        for i in -steps..=steps {
+            let angle = center + i as f64 * step;
+            let s = score(angle);
+            if s > best.1 {
+                best = (angle, s);
+            }
+        }
+        best.0
+    };
+    let coarse = search(0.0, 0.5, 90);
+    search(coarse, 0.05, 10)
+}
+
+// Width and height from the header alone; pixel data is never decoded.
+pub(crate) fn dimensions_from_bytes(data: &[u8]) -> Result<(u32, u32), String> {
+    image::ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?
+        .into_dimensions()
+        .map_err(|e| e.to_string())
+}
+
+// Frame count and summed frame delay in milliseconds for GIF, APNG and
+// animated WebP. Every frame is decoded, since delays live per frame. Any
+// other readable image is a still: (1, 0).
+pub(crate) fn frame_info(data: &[u8]) -> Result<(u32, u64), String> {
+    fn sum(frames: image::Frames<'_>) -> Result<(u32, u64), String> {
+        let (mut count, mut total_ms) = (0u32, 0f64);
+        for frame in frames {
+            let (numer, denom) = frame.map_err(|e| e.to_string())?.delay().numer_denom_ms();
+            count += 1;
+            total_ms += numer as f64 / denom.max(1) as f64;
+        }
+        if count == 0 {
+            return Err("animation has no frames".to_string());
+        }
+        Ok((count, total_ms.round() as u64))
+    }
+    match image::guess_format(data).map_err(|e| e.to_string())? {
+        ImageFormat::Gif => {
+            let decoder = GifDecoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+            sum(decoder.into_frames())
+        }
+        ImageFormat::Png => {
+            let decoder = PngDecoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+            if !decoder.is_apng().map_err(|e| e.to_string())? {
+                return Ok((1, 0));
+            }
+            sum(decoder.apng().map_err(|e| e.to_string())?.into_frames())
+        }
+        ImageFormat::WebP => {
+            let decoder = WebPDecoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+            if !decoder.has_animation() {
+                return Ok((1, 0));
+            }
+            sum(decoder.into_frames())
+        }
+        _ => dimensions_from_bytes(data).map(|_| (1, 0)),
+    }
+}
+
+// Rounded per-channel mean over the RGBA view, alpha included.
+pub(crate) fn average_color(img: &DynamicImage) -> Rgba<u8> {
+    let rgba = img.to_rgba8();
+    let mut sums = [0u64; 4];
+    for p in rgba.pixels() {
+        for (sum, &v) in sums.iter_mut().zip(&p.0) {
+            *sum += v as u64;
+        }
+    }
+    let n = (rgba.pixels().len() as u64).max(1);
+    Rgba(sums.map(|sum| ((sum + n / 2) / n) as u8))
+}
+
+// Collapses the image to one RGBA pixel holding the per-channel mean (0),
+// min (1) or max (2) over every pixel.
+pub(crate) fn reduce(img: &DynamicImage, op: i64) -> Result<DynamicImage, String> {
+    let px = match op {
+        0 => average_color(img),
+        1 | 2 => {
+            let rgba = img.to_rgba8();
+            let mut px = [0u8; 4];
+            for (c, out) in px.iter_mut().enumerate() {
+                let channel = rgba.pixels().map(|p| p.0[c]);
+                *out = if op == 1 { channel.min() } else { channel.max() }.unwrap_or(0);
+            }
+            Rgba(px)
+        }
+        other => return Err(format!("unknown reduce op: {other}")),
+    };
+    Ok(DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, px)))
+}
+
+// One-pixel lines at every multiple of `spacing` on both axes, starting at
+// 0 and covering the whole image regardless of any selection.
+pub(crate) fn draw_grid(img: &DynamicImage, spacing: u32, color: Rgba<u8>) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for (x, y, px) in rgba.enumerate_pixels_mut() {
+        if x % spacing == 0 || y % spacing == 0 {
+            *px = color;
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+// Frame `index` of a GIF, composited onto the full canvas. Frames before it
+// are decoded too, since each one builds on the last.
+pub(crate) fn extract_gif_frame(data: &[u8], index: u64) -> Result<DynamicImage, String> {
+    let decoder = GifDecoder::new(Cursor::new(data)).map_err(|e| e.to_string())?;
+    let index = usize::try_from(index).map_err(|_| "frame index out of range".to_string())?;
+    let frame = decoder
+        .into_frames()
+        .nth(index)
+        .ok_or_else(|| "frame index out of range".to_string())?
+        .map_err(|e| e.to_string())?;
+    Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+}
+
+// Looping GIF with one frame per image, each shown for `delay_ms`. Frames
+// must all be the same size.
+pub(crate) fn encode_gif_frames(images: &[&DynamicImage], delay_ms: u32) -> Result<Vec<u8>, String> {
+    let first = images.first().ok_or_else(|| "no frames to encode".to_string())?;
+    if let Some(other) = images.iter().find(|img| (img.width(), img.height()) != (first.width(), first.height())) {
+        return Err(format!(
+            "frame sizes differ: {}x{} vs {}x{}",
+            first.width(),
+            first.height(),
+            other.width(),
+            other.height()
+        ));
+    }
+    let delay = image::Delay::from_numer_denom_ms(delay_ms, 1);
+    let mut out = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut out);
+        encoder.set_repeat(Repeat::Infinite).map_err(|e| e.to_string())?;
+        encoder
+            .encode_frames(images.iter().map(|img| Frame::from_parts(img.to_rgba8(), 0, 0, delay)))
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(out)
+}
+
+// Fewest bits per channel that reproduce every sample when the storage
+// depth is rebuilt by bit replication, so 16-bit data holding only multiples
+// of 257 reports 8. Float images always report 32.
+pub(crate) fn effective_bit_depth(img: &DynamicImage) -> u32 {
+    let (depth, samples): (u32, Vec<u16>) = match img.color() {
+        ColorType::Rgb32F | ColorType::Rgba32F => return 32,
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => (16, img.to_rgba16().into_raw()),
+        _ => (8, img.to_rgba8().into_raw().into_iter().map(u16::from).collect()),
+    };
+    let mut seen = vec![false; 1 << depth];
+    for s in samples {
+        seen[s as usize] = true;
+    }
+    let max = (1u64 << depth) - 1;
+    (1..depth)
+        .find(|&bits| {
+            let levels = (1u64 << bits) - 1;
+            seen.iter().enumerate().filter(|(_, hit)| **hit).all(|(v, _)| {
+                let reduced = (v as u64 * levels + max / 2) / max;
+                (reduced * max + levels / 2) / levels == v as u64
+            })
+        })
+        .unwrap_or(depth)
+}
+
+// Per-channel min (erode) or max (dilate) over a (2r+1)-pixel square, done
+// as two separable passes. Applied to every channel, so it works on masks
+// whether they live in luma or alpha. Windows are clamped at the edges.
+pub(crate) fn morphology(img: &DynamicImage, radius: u32, dilate: bool) -> DynamicImage {
+    let src = img.to_rgba8();
+    let (w, h) = src.dimensions();
+    let mut rows = RgbaImage::new(w, h);
+    for y in 0..h {
+        let line: Vec<[u8; 4]> = (0..w).map(|x| src.get_pixel(x, y).0).collect();
+        for (x, v) in running_extreme(&line, radius as usize, dilate).into_iter().enumerate() {
+            rows.put_pixel(x as u32, y, Rgba(v));
+        }
+    }
+    let mut out = RgbaImage::new(w, h);
+    for x in 0..w {
+        let line: Vec<[u8; 4]> = (0..h).map(|y| rows.get_pixel(x, y).0).collect();
+        for (y, v) in running_extreme(&line, radius as usize, dilate).into_iter().enumerate() {
+            out.put_pixel(x, y as u32, Rgba(v));
+        }
+    }
+    DynamicImage::ImageRgba8(out)
+}
+
+// van Herk/Gil-Werman running min or max over [i - r, i + r], clamped to the
+// line: the line is padded with the neutral value so every window has length
+// k = 2r + 1, then each window is the suffix extreme of one k-block joined
+// with the prefix extreme of the next. Cost per pixel is constant whatever
+// the radius.
+fn running_extreme(line: &[[u8; 4]], radius: usize, dilate: bool) -> Vec<[u8; 4]> {
+    let n = line.len();
+    // A window wider than the line behaves like one covering all of it.
+    let r = radius.min(n.saturating_sub(1));
+    let k = 2 * r + 1;
+    let pick = |a: [u8; 4], b: [u8; 4]| {
+        let mut out = a;
+        for (o, v) in out.iter_mut().zip(b) {
+            *o = if dilate { (*o).max(v) } else { (*o).min(v) };
+        }
+        out
+    };
+    let neutral = if dilate { [0; 4] } else { [255; 4] };
+    let mut padded = vec![neutral; r];
+    padded.extend_from_slice(line);
+    padded.resize(n + 2 * r, neutral);
+    let mut prefix = padded.clone();
+    for block in prefix.chunks_mut(k) {
+        let mut acc = neutral;
+        for v in block.iter_mut() {
+            acc = pick(acc, *v);
+            *v = acc;
+        }
+    }
+    let mut suffix = padded;
+    for block in suffix.chunks_mut(k) {
+        let mut acc = neutral;
+        for v in block.iter_mut().rev() {
+            acc = pick(acc, *v);
+            *v = acc;
+        }
+    }
+    (0..n).map(|i| pick(suffix[i], prefix[i + k - 1])).collect()
+}
+
+// Average hash: 8x8 nearest-neighbor grayscale, one bit per cell set when it
+// is brighter than the mean, row-major from the most significant bit.
+pub(crate) fn average_hash(img: &DynamicImage) -> u64 {
+    let small = img.grayscale().resize_exact(8, 8, FilterType::Nearest).to_luma8();
+    let sum: u32 = small.pixels().map(|p| p.0[0] as u32).sum();
+    small
+        .pixels()
+        .fold(0u64, |hash, p| (hash << 1) | u64::from(p.0[0] as u32 * 64 > sum))
+}
+
+// (position, color) stops for the named colormaps, interpolated linearly.
+fn colormap_stops(name: &str) -> Result<&'static [(f64, [u8; 3])], String> {
+    const VIRIDIS: &[(f64, [u8; 3])] = &[
+        (0.0, [68, 1, 84]),
+        (0.125, [71, 44, 122]),
+        (0.25, [59, 81, 139]),
+        (0.375, [44, 113, 142]),
+        (0.5, [33, 144, 141]),
+        (0.625, [39, 173, 129]),
+        (0.75, [92, 200, 99]),
+        (0.875, [170, 220, 50]),
+        (1.0, [253, 231, 37]),
+    ];
+    const JET: &[(f64, [u8; 3])] = &[
+        (0.0, [0, 0, 128]),
+        (0.125, [0, 0, 255]),
+        (0.375, [0, 255, 255]),
+        (0.625, [255, 255, 0]),
+        (0.875, [255, 0, 0]),
+        (1.0, [128, 0, 0]),
+    ];
+    const GRAYSCALE: &[(f64, [u8; 3])] = &[(0.0, [0, 0, 0]), (1.0, [255, 255, 255])];
+    match name {
+        "viridis" => Ok(VIRIDIS),
+        "jet" => Ok(JET),
+        "grayscale" => Ok(GRAYSCALE),
+        other => Err(format!("unknown colormap: {other}")),
+    }
+}
+
+// Uses luma as an index into the colormap. Alpha is kept when present.
+pub(crate) fn apply_colormap(img: &DynamicImage, name: &str) -> Result<DynamicImage, String> {
+    let stops = colormap_stops(name)?;
+    let lut: Vec<[u8; 3]> = (0..=255u32)
+        .map(|i| {
+            let t = i as f64 / 255.0;
+            let k = stops.windows(2).position(|w| t <= w[1].0).unwrap_or(stops.len() - 2);
+            let ((t0, c0), (t1, c1)) = (stops[k], stops[k + 1]);
+            let f = (t - t0) / (t1 - t0);
+            [0, 1, 2].map(|c| (c0[c] as f64 + (c1[c] as f64 - c0[c] as f64) * f).round() as u8)
+        })
+        .collect();
+    let luma = img.to_luma_alpha8();
+    let mapped = RgbaImage::from_fn(luma.width(), luma.height(), |x, y| {
+        let [l, a] = luma.get_pixel(x, y).0;
+        let [r, g, b] = lut[l as usize];
+        Rgba([r, g, b, a])
+    });
+    Ok(if img.color().has_alpha() {
+        DynamicImage::ImageRgba8(mapped)
+    } else {
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(mapped).to_rgb8())
+    })
+}
+
+// Counts pixels where any channel differs by more than `tolerance`.
+pub(crate) fn count_differing(a: &RgbaImage, b: &RgbaImage, tolerance: u8) -> u64 {
+    a.pixels()
+        .zip(b.pixels())
+        .filter(|(pa, pb)| pa.0.iter().zip(&pb.0).any(|(&x, &y)| x.abs_diff(y) > tolerance))
+        .count() as u64
+}
+
+// Hardens alpha to 0 below `level` and 255 at or above it; RGB is untouched.
+pub(crate) fn threshold_alpha(img: &DynamicImage, level: u8) -> DynamicImage {
+    let mut rgba = img.to_rgba8();
+    for px in rgba.pixels_mut() {
+        px.0[3] = if px.0[3] >= level { 255 } else { 0 };
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+// Exchanges the values stored under two keys, moving an entry that exists
+// under only one of them across.
+pub(crate) fn swap_entries<V>(map: &mut HashMap<u32, V>, a: u32, b: u32) {
+    let from_a = map.remove(&a);
+    let from_b = map.remove(&b);
+    if let Some(v) = from_a {
+        map.insert(b, v);
+    }
+    if let Some(v) = from_b {
+        map.insert(a, v);
+    }
+}